@@ -1,5 +1,7 @@
 use cold_clear_2::data::{Board, Piece};
-use cold_clear_2::movegen::find_moves;
+use cold_clear_2::movegen::{
+    find_moves, find_moves_with, find_moves_with_rules, MoveRules, Scratch,
+};
 use criterion::{criterion_group, criterion_main, Criterion};
 
 const PIECES: [Piece; 7] = [
@@ -16,62 +18,64 @@ fn bench_movegen(c: &mut Criterion, name: &str, board: Board) {
     let mut group = c.benchmark_group(name);
     for p in PIECES {
         group.bench_function(format!("{:?}", p), |b| b.iter(|| find_moves(&board, p)));
+        let mut scratch = Scratch::default();
+        let mut moves = vec![];
+        group.bench_function(format!("{:?} reused", p), |b| {
+            b.iter(|| find_moves_with(&board, p, &mut scratch, &mut moves))
+        });
+    }
+}
+
+fn bench_movegen_180(c: &mut Criterion, name: &str, board: Board) {
+    let mut group = c.benchmark_group(name);
+    let rules = MoveRules {
+        rotate_180: true,
+        ..Default::default()
+    };
+    for p in PIECES {
+        let mut scratch = Scratch::default();
+        let mut moves = vec![];
+        group.bench_function(format!("{:?}", p), |b| {
+            b.iter(|| find_moves_with_rules(&board, p, &rules, &mut scratch, &mut moves))
+        });
     }
 }
 
 fn bench(c: &mut Criterion) {
     bench_movegen(c, "empty", Board::default());
 
-    // v115@egA8IeC8FeE8DeF8CeH8BeH8CeH8AeD8JeAgH
-    #[rustfmt::skip]
-    bench_movegen(c, "tspin", Board {
-        cols: [
-            0b00111111,
-            0b00111111,
-            0b00011111,
-            0b00000111,
-            0b00000001,
-            0b00000000,
-            0b00001101,
-            0b00011111,
-            0b00111111,
-            0b11111111,
-        ]
-    });
+    bench_movegen(
+        c,
+        "tspin",
+        Board::from_fumen("v115@egA8IeC8FeE8DeF8CeH8BeH8CeH8AeD8JeAgH").unwrap(),
+    );
 
-    // v115@LgB8HeD8BeH8CeI8AeH8BeH8CeH8AeI8AeH8AeD8Je?AgH
-    #[rustfmt::skip]
-    bench_movegen(c, "dtd", Board {
-        cols: [
-            0b111111111,
-            0b111111111,
-            0b011111111,
-            0b011111111,
-            0b000111111,
-            0b000100110,
-            0b010000001,
-            0b011110111,
-            0b011111111,
-            0b011111111,
-        ]
-    });
+    bench_movegen(
+        c,
+        "dtd",
+        Board::from_fumen("v115@LgB8HeD8BeH8CeI8AeH8BeH8CeH8AeI8AeH8AeD8Je?AgH").unwrap(),
+    );
+
+    bench_movegen(
+        c,
+        "terrible",
+        Board::from_fumen("v115@vfH8BeH8IeA8IeH8BeH8BeB8HeB8HeB8BeH8BeH8Ie?A8SeAgH").unwrap(),
+    );
 
-    // v115@vfH8BeH8IeA8IeH8BeH8BeB8HeB8HeB8BeH8BeH8Ie?A8SeAgH
+    // the T-spin at x=1, y=4 can only be reached with a 180 rotation
     #[rustfmt::skip]
-    bench_movegen(c, "terrible", Board {
-        cols: [
-            0b000011111111,
-            0b000011000000,
-            0b110011000000,
-            0b110011001100,
-            0b110011001100,
-            0b110011001100,
-            0b110011001100,
-            0b110000001100,
-            0b110000001100,
-            0b111111111100,
-        ]
-    });
+    bench_movegen_180(c, "180", Board::new([
+        0b101011,
+        0b001101,
+        0b100000,
+        0b000111,
+        0b001000,
+        0b101110,
+        0b101110,
+        0b010001,
+        0b100000,
+        0b111011,
+    ]));
 }
 
 criterion_group!(benchmark, bench);
@@ -0,0 +1,154 @@
+#ifndef COLD_CLEAR_2_H
+#define COLD_CLEAR_2_H
+
+/* Generated by cbindgen from src/ffi.rs. Don't edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Used for `CC2Start::hold` when the hold slot is empty.
+ */
+#define CC2_NO_PIECE 255
+
+#define CC2_RANDOMIZER_UNKNOWN 0
+
+#define CC2_RANDOMIZER_SEVEN_BAG 1
+
+#define CC2_RANDOMIZER_MEMORYLESS 2
+
+#define CC2_RANDOMIZER_CLASSIC 3
+
+/**
+ * Board cell value marking garbage, which only matters with the `garbage-plane` feature. Any
+ * other nonzero value is an ordinary filled cell.
+ */
+#define CC2_GARBAGE_CELL 8
+
+typedef struct CC2Bot CC2Bot;
+
+/**
+ * The position to start a game from, as in a TBP `start` message.
+ */
+typedef struct CC2Start {
+  /**
+   * Cells row by row from the bottom, 10 per row. Zero is empty.
+   */
+  uint8_t board[400];
+  /**
+   * `CC2_NO_PIECE` if the hold slot is empty.
+   */
+  uint8_t hold;
+  /**
+   * The current piece followed by the next pieces. May be null if `queue_len` is 0.
+   */
+  const uint8_t *queue;
+  size_t queue_len;
+  uint32_t combo;
+  bool back_to_back;
+  /**
+   * One of the `CC2_RANDOMIZER_` constants.
+   */
+  uint8_t randomizer;
+  /**
+   * For the 7-bag randomizer, the pieces left in the current bag after the queue, with bit `n`
+   * set for piece `n`.
+   */
+  uint8_t bag_state;
+} CC2Start;
+
+typedef struct CC2Move {
+  uint8_t piece;
+  uint8_t rotation;
+  int8_t x;
+  int8_t y;
+  uint8_t spin;
+  /**
+   * Whether playing this move means swapping the current piece with hold.
+   */
+  bool hold;
+} CC2Move;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a bot and starts its search threads. `config_json` is a nul-terminated JSON bot
+ * configuration, or null for the default one. Returns null if the configuration is invalid.
+ *
+ * # Safety
+ *
+ * `config_json` must be null or point to a nul-terminated string.
+ */
+struct CC2Bot *cc2_create(const char *config_json);
+
+/**
+ * Stops the search threads and frees the bot.
+ *
+ * # Safety
+ *
+ * `bot` must have come from `cc2_create` and must not be used again afterwards.
+ */
+void cc2_destroy(struct CC2Bot *bot);
+
+/**
+ * Starts a new game. Returns false, leaving the previous game running, if `start` contains an
+ * invalid piece or randomizer.
+ *
+ * # Safety
+ *
+ * `bot` must be a live bot and `start` must point to a valid `CC2Start` whose `queue` holds
+ * `queue_len` pieces.
+ */
+bool cc2_start(struct CC2Bot *bot, const struct CC2Start *start);
+
+/**
+ * Ends the current game. Search stops until the next `cc2_start`.
+ *
+ * # Safety
+ *
+ * `bot` must be a live bot.
+ */
+void cc2_stop(struct CC2Bot *bot);
+
+/**
+ * Adds a piece to the end of the queue. Returns false if `piece_id` isn't a piece.
+ *
+ * # Safety
+ *
+ * `bot` must be a live bot.
+ */
+bool cc2_new_piece(struct CC2Bot *bot, uint8_t piece_id);
+
+/**
+ * Plays a move, which need not be one the bot suggested. Returns false, leaving the game as it
+ * was, if `mv` isn't a valid move, no game is in progress, or `mv.piece` and `mv.hold` don't
+ * match the current, hold and next pieces. Also returns false if the move overlaps the board,
+ * which ends the game.
+ *
+ * # Safety
+ *
+ * `bot` must be a live bot and `mv` must point to a valid `CC2Move`.
+ */
+bool cc2_play(struct CC2Bot *bot, const struct CC2Move *mv);
+
+/**
+ * Writes up to `capacity` suggested moves, best first, to `out` and returns how many were
+ * written. With `think_ms` above 0 this first waits until search has run that long since the
+ * last move or reached the node limit. Returns 0 if no game is running or there are no moves.
+ *
+ * # Safety
+ *
+ * `bot` must be a live bot and `out` must have room for `capacity` moves.
+ */
+size_t cc2_suggest(struct CC2Bot *bot, uint32_t think_ms, struct CC2Move *out, size_t capacity);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* COLD_CLEAR_2_H */
@@ -0,0 +1,84 @@
+/*
+ * Plays a short 7-bag game through the C interface, printing each move.
+ *
+ *     cargo build --release
+ *     cc examples/ffi.c -I. target/release/libcold_clear_2.a -lpthread -ldl -lm -o ffi
+ *     ./ffi
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "cold_clear_2.h"
+
+#define PREVIEWS 5
+#define PIECES 20
+
+static const char *PIECE_NAMES = "IOTLJSZ";
+static const char *ROTATION_NAMES[] = {"north", "west", "south", "east"};
+
+static uint8_t bag[7];
+static int bag_left = 0;
+
+static uint8_t draw(void) {
+    if (bag_left == 0) {
+        for (int i = 0; i < 7; i++) {
+            bag[i] = (uint8_t)i;
+        }
+        bag_left = 7;
+    }
+    int i = rand() % bag_left;
+    uint8_t piece = bag[i];
+    bag[i] = bag[--bag_left];
+    return piece;
+}
+
+int main(void) {
+    srand(1);
+    CC2Bot *bot = cc2_create(NULL);
+    if (bot == NULL) {
+        return 1;
+    }
+
+    uint8_t queue[1 + PREVIEWS];
+    for (int i = 0; i < 1 + PREVIEWS; i++) {
+        queue[i] = draw();
+    }
+    CC2Start start = {0};
+    start.hold = CC2_NO_PIECE;
+    start.queue = queue;
+    start.queue_len = 1 + PREVIEWS;
+    start.randomizer = CC2_RANDOMIZER_SEVEN_BAG;
+    for (int i = 0; i < bag_left; i++) {
+        start.bag_state |= 1 << bag[i];
+    }
+    if (!cc2_start(bot, &start)) {
+        cc2_destroy(bot);
+        return 1;
+    }
+
+    for (int piece = 0; piece < PIECES; piece++) {
+        CC2Move moves[1];
+        if (cc2_suggest(bot, 100, moves, 1) == 0) {
+            printf("no moves left\n");
+            break;
+        }
+        CC2Move mv = moves[0];
+        printf(
+            "%c %s at (%d, %d)%s\n",
+            PIECE_NAMES[mv.piece],
+            ROTATION_NAMES[mv.rotation],
+            mv.x,
+            mv.y,
+            mv.hold ? ", holding" : ""
+        );
+        if (!cc2_play(bot, &mv)) {
+            printf("move was rejected\n");
+            break;
+        }
+        cc2_new_piece(bot, draw());
+    }
+
+    cc2_destroy(bot);
+    return 0;
+}
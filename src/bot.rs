@@ -1,27 +1,362 @@
 use std::collections::VecDeque;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use enum_dispatch::enum_dispatch;
+use enumset::EnumSet;
 use once_cell::sync::Lazy;
+use rand::rngs::SmallRng;
+use rand::{thread_rng, Rng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 
-use crate::data::{GameState, Piece, Placement};
+use crate::data::{AttackTable, Board, GameState, GarbagePattern, Piece, Placement, PlacementInfo};
+use crate::movegen::{is_legal, KickTable, MoveRules};
 
+mod book;
+mod downstack;
 mod freestyle;
+mod perfect_clear;
+mod sprint;
+mod warm_start;
 
+use self::book::{Book, OpeningBook};
+use self::downstack::Downstack;
 use self::freestyle::Freestyle;
+use self::perfect_clear::PerfectClear;
+use self::sprint::Sprint;
+use self::warm_start::WarmStart;
+
+pub use self::freestyle::Weights;
+
+/// Why a played move couldn't be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AdvanceError {
+    /// No piece after the played one is known yet. Playing takes the next piece out of the
+    /// queue either way, so the move has to wait for `new_piece`. Nothing is changed.
+    NoNextPiece,
+    /// The move overlaps the board or leaves it, first at this cell.
+    Overlaps(i8, i8),
+}
+
+impl std::fmt::Display for AdvanceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AdvanceError::NoNextPiece => {
+                write!(f, "no piece after the played one is known yet")
+            }
+            AdvanceError::Overlaps(x, y) => {
+                write!(f, "played move overlaps the board at ({}, {})", x, y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AdvanceError {}
 
 pub struct Bot {
     options: BotOptions,
     current: GameState,
     queue: VecDeque<Piece>,
+    /// How many pieces at the front of the queue the mode has been told about.
+    known: usize,
+    /// The states before each move played, with the piece used, for rewinding.
+    history: Vec<(GameState, Piece, PlacementInfo)>,
+    /// How many nodes the tree may hold before evicting. Starts at `max_nodes`, but is raised
+    /// when eviction can't get below it so search doesn't evict after every node.
+    node_cap: Option<usize>,
     mode: ModeEnum,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BotConfig {
-    pub freestyle_weights: freestyle::Weights,
+    pub freestyle_weights: Weights,
     pub freestyle_exploitation: f64,
+    /// Weights used while playing sprint.
+    #[serde(default = "default_sprint_weights")]
+    pub sprint_weights: Weights,
+    /// When set, the bot plays sprint until this many lines have been cleared.
+    #[serde(default)]
+    pub sprint_lines: Option<u32>,
+    /// Path to an opening book to play from while the position is in it. The book is loaded
+    /// at the start of every game.
+    #[serde(default)]
+    pub book: Option<PathBuf>,
+    /// Weights used while downstacking.
+    #[serde(default = "default_downstack_weights")]
+    pub downstack_weights: Weights,
+    /// Search the known queue for a perfect clear whenever the board is at most 4 rows tall,
+    /// and play it out if there is one.
+    #[serde(default)]
+    pub perfect_clear_solver: bool,
+    /// When set, the bot switches from freestyle to downstacking whenever the stack is taller
+    /// than this many rows, and back once it is 4 rows lower, or empty for thresholds up to 4.
+    #[serde(default)]
+    pub downstack_height: Option<u32>,
+    /// Include the cells occupied by each suggested placement in suggestions.
+    #[serde(default)]
+    pub report_cells: bool,
+    /// Path to a table of known evaluations used to seed newly created nodes.
+    #[serde(default)]
+    pub warm_start: Option<WarmStart>,
+    /// Only keep this many of the best initially evaluated children of each node. Narrows the
+    /// search so it goes deeper, at the risk of discarding moves which only look good later.
+    #[serde(default)]
+    pub beam_width: Option<usize>,
+    /// Only search this many pieces of the known queue as known; later pieces are remembered
+    /// but searched as if they were unknown until enough pieces have been played. Bounds the
+    /// amount of work per move when the frontend provides a very long queue.
+    #[serde(default)]
+    pub max_lookahead: Option<usize>,
+    /// How the frontend fills in `hold` and `queue` when starting a game.
+    #[serde(default)]
+    pub hold_convention: HoldConvention,
+    /// Practice mode: search this many nodes per move, with very little exploration, before
+    /// considering the search finished. This is slow by design and meant for analysis tools which
+    /// want the best answer rather than a fast one; poll suggest until `progress` reaches 1 and
+    /// use analyze to get the line the bot expects.
+    #[serde(default)]
+    pub practice_nodes: Option<u64>,
+    /// What to do once search reaches the end of the known queue.
+    #[serde(default)]
+    pub queue_exhausted: QueueExhausted,
+    /// Analysis mode: keep the search tree above the current position after each move so the
+    /// frontend can rewind and explore a different move. Nothing is freed until the game ends,
+    /// so memory use grows without bound; only enable this for analysis tools.
+    #[serde(default)]
+    pub keep_history: bool,
+    /// Which of two equally evaluated moves to prefer.
+    #[serde(default)]
+    pub tiebreak: Tiebreak,
+    /// The most cells a piece may be soft dropped, for rules which limit or disable soft drop.
+    #[serde(default)]
+    pub max_soft_drop: Option<u32>,
+    /// Whether the game allows rotating pieces 180 degrees.
+    #[serde(default)]
+    pub rotate_180: bool,
+    /// The rotation system of the game. Frontends can also choose it in the `rules` message.
+    #[serde(default)]
+    pub kick_table: KickTable,
+    /// Whether the game counts any piece which can't move after rotating into place as a spin.
+    #[serde(default)]
+    pub all_spin: bool,
+    /// How much garbage each kind of line clear sends, for stats and the `attack` weight.
+    #[serde(default)]
+    pub attack_table: AttackTable,
+    /// Stop searching after this many nodes per move, for reproducible results or to save CPU.
+    /// Suggestions still give the best move found. Frontends can change it with `set_node_limit`.
+    #[serde(default)]
+    pub node_limit: Option<u64>,
+    /// The number of search threads, started once when the bot launches.
+    #[serde(default = "default_threads")]
+    pub threads: usize,
+    /// Seed for the randomness used by search, making runs reproducible: with a single thread and
+    /// a node limit, the same inputs give the same suggestions. Each thread derives its own seed
+    /// from it. Unseeded search draws from the operating system.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Evict the least valuable unexpanded positions once the search tree holds more than this
+    /// many, bounding memory use when a single move is searched for a long time. Positions are
+    /// kept if they are the best move from anywhere, so the tree can still outgrow this.
+    #[serde(default)]
+    pub max_nodes: Option<usize>,
+}
+
+fn default_sprint_weights() -> Weights {
+    BotConfig::default().sprint_weights
+}
+
+fn default_downstack_weights() -> Weights {
+    BotConfig::default().downstack_weights
+}
+
+fn default_threads() -> usize {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldConvention {
+    /// As specified by TBP: the first queue piece is the current piece and `hold` is the held
+    /// piece, which is absent when nothing has been held yet.
+    #[default]
+    Standard,
+    /// `hold` carries the current piece and the queue only contains the pieces after it.
+    CurrentInHold,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueExhausted {
+    /// Keep searching by considering every piece which could come next. Uses all available
+    /// compute and lets the bot plan around pieces it hasn't seen yet.
+    #[default]
+    Speculate,
+    /// Stop once every position reachable with the known queue has been searched. Saves compute
+    /// when the frontend provides a long queue, but with a short queue the bot plays without
+    /// looking ahead much. TBP has no way to ask the frontend for more pieces, so they have to
+    /// arrive on their own.
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tiebreak {
+    /// Keep the order moves were generated in.
+    #[default]
+    MoveOrder,
+    /// Prefer the move clearing more lines, taking safety now.
+    ClearNow,
+    /// Prefer the move clearing fewer lines, keeping lines around for a bigger clear later.
+    Setup,
+}
+
+impl BotConfig {
+    /// The movement rules search generates placements under.
+    pub fn move_rules(&self) -> MoveRules {
+        MoveRules {
+            max_soft_drop: self.max_soft_drop.unwrap_or(u32::MAX),
+            rotate_180: self.rotate_180,
+            kick_table: self.kick_table,
+            all_spin: self.all_spin,
+        }
+    }
+
+    /// How many nodes to search per move before stopping, from `node_limit` and practice mode.
+    pub fn nodes_per_move(&self) -> u64 {
+        let practice = self.practice_nodes.unwrap_or(u64::MAX);
+        practice.min(self.node_limit.unwrap_or(u64::MAX))
+    }
+
+    /// The exploitation parameter used for selection in every mode.
+    pub fn exploitation(&self) -> f64 {
+        const PRACTICE_EXPLOITATION: f64 = 4.0;
+        match self.practice_nodes {
+            Some(_) => PRACTICE_EXPLOITATION,
+            None => self.freestyle_exploitation,
+        }
+    }
+
+    /// Parses a configuration, also returning the paths of any fields which were ignored because
+    /// they aren't recognized (most likely typos). Numbers too large to be represented are an
+    /// error.
+    pub fn from_json(json: serde_json::Value) -> serde_json::Result<(Self, Vec<String>)> {
+        let config: BotConfig = serde_json::from_value(json.clone())?;
+        let known = serde_json::to_value(&config)?;
+        let mut non_finite = vec![];
+        non_finite_fields(&json, &known, "", &mut non_finite);
+        if !non_finite.is_empty() {
+            return Err(serde::de::Error::custom(format!(
+                "numbers out of range: {}",
+                non_finite.join(", ")
+            )));
+        }
+        let weights = [
+            ("freestyle_weights", &config.freestyle_weights),
+            ("sprint_weights", &config.sprint_weights),
+            ("downstack_weights", &config.downstack_weights),
+        ];
+        for (name, weights) in weights {
+            if let Err(e) = weights.validate() {
+                return Err(serde::de::Error::custom(format!("{}: {}", name, e)));
+            }
+        }
+        if config.beam_width == Some(0) {
+            return Err(serde::de::Error::custom(
+                "beam_width must be at least 1, or null for no limit",
+            ));
+        }
+        let mut unknown = vec![];
+        unknown_fields(&json, &known, "", &mut unknown);
+        Ok((config, unknown))
+    }
+
+    /// Replaces the freestyle, sprint and/or downstack weights with ones sent by the frontend.
+    /// Nothing is changed if any of them fail to parse or contain unknown fields.
+    pub fn with_weights(
+        &self,
+        freestyle: Option<serde_json::Value>,
+        sprint: Option<serde_json::Value>,
+        downstack: Option<serde_json::Value>,
+    ) -> Result<Self, String> {
+        let parse = |json: serde_json::Value| -> Result<Weights, String> {
+            let weights: Weights =
+                serde_json::from_value(json.clone()).map_err(|e| e.to_string())?;
+            let mut unknown = vec![];
+            let known = serde_json::to_value(&weights).map_err(|e| e.to_string())?;
+            let mut non_finite = vec![];
+            non_finite_fields(&json, &known, "", &mut non_finite);
+            if !non_finite.is_empty() {
+                return Err(format!("numbers out of range: {}", non_finite.join(", ")));
+            }
+            unknown_fields(&json, &known, "", &mut unknown);
+            if !unknown.is_empty() {
+                return Err(format!("unknown fields: {}", unknown.join(", ")));
+            }
+            weights.validate()?;
+            Ok(weights)
+        };
+        let mut config = self.clone();
+        if let Some(json) = freestyle {
+            config.freestyle_weights = parse(json)?;
+        }
+        if let Some(json) = sprint {
+            config.sprint_weights = parse(json)?;
+        }
+        if let Some(json) = downstack {
+            config.downstack_weights = parse(json)?;
+        }
+        Ok(config)
+    }
+}
+
+fn unknown_fields(
+    given: &serde_json::Value,
+    known: &serde_json::Value,
+    path: &str,
+    out: &mut Vec<String>,
+) {
+    if let (Some(given), Some(known)) = (given.as_object(), known.as_object()) {
+        for (key, value) in given {
+            let path = match path {
+                "" => key.clone(),
+                _ => format!("{}.{}", path, key),
+            };
+            match known.get(key) {
+                Some(known) => unknown_fields(value, known, &path, out),
+                None => out.push(path),
+            }
+        }
+    }
+}
+
+/// Finds numbers which parsed to infinity, such as `1e39` for an `f32`. These serialize as `null`,
+/// so they show up as a number in `given` where `parsed` has `null`.
+fn non_finite_fields(
+    given: &serde_json::Value,
+    parsed: &serde_json::Value,
+    path: &str,
+    out: &mut Vec<String>,
+) {
+    let child = |key: &dyn std::fmt::Display| match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    };
+    match (given, parsed) {
+        (serde_json::Value::Number(_), serde_json::Value::Null) => out.push(path.to_owned()),
+        (serde_json::Value::Object(given), serde_json::Value::Object(parsed)) => {
+            for (key, value) in given {
+                if let Some(parsed) = parsed.get(key) {
+                    non_finite_fields(value, parsed, &child(key), out);
+                }
+            }
+        }
+        (serde_json::Value::Array(given), serde_json::Value::Array(parsed)) => {
+            for (i, (value, parsed)) in given.iter().zip(parsed).enumerate() {
+                non_finite_fields(value, parsed, &child(&i), out);
+            }
+        }
+        _ => {}
+    }
 }
 
 impl Default for BotConfig {
@@ -41,80 +376,446 @@ pub struct BotOptions {
 #[enum_dispatch]
 enum ModeEnum {
     Freestyle,
+    Sprint,
+    Downstack,
+    Book,
+    PerfectClear,
 }
 
 #[enum_dispatch(ModeEnum)]
 trait Mode {
-    fn advance(&mut self, options: &BotOptions, mv: Placement) -> Option<ModeSwitch>;
+    fn advance(&mut self, options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch>;
     fn new_piece(&mut self, options: &BotOptions, piece: Piece);
     fn suggest(&self, options: &BotOptions) -> Vec<Placement>;
-    fn do_work(&self, options: &BotOptions) -> Statistics;
+    fn confidence(&self, options: &BotOptions) -> Option<f64>;
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)>;
+    fn root_moves(&self, options: &BotOptions) -> Vec<(Placement, f64, u32)>;
+    fn depth(&self, options: &BotOptions) -> u32;
+    fn node_count(&self, options: &BotOptions) -> usize;
+    fn tree_size(&self, options: &BotOptions) -> (usize, usize);
+    fn evict(&mut self, options: &BotOptions, target: usize) -> usize;
+    fn rewind(&mut self, options: &BotOptions, info: &PlacementInfo);
+    /// Throws away the search and starts over from `root`, which isn't reachable from the
+    /// current root by playing moves.
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]);
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics;
 }
 
 enum ModeSwitch {
     Freestyle,
+    Downstack,
+    /// Play out this sequence of moves, which ends in a perfect clear.
+    PerfectClear(Vec<Placement>),
 }
 
 impl Bot {
     pub fn new(options: BotOptions, root: GameState, queue: &[Piece]) -> Self {
-        Bot {
+        let known = &queue[..queue.len().min(lookahead(&options))];
+        let mut bot = Bot {
             current: root,
             queue: queue.iter().copied().collect(),
-            mode: Freestyle::new(&options, root, queue).into(),
+            known: known.len(),
+            history: vec![],
+            node_cap: options.config.max_nodes,
+            mode: match (options.config.sprint_lines, load_book(&options, &root)) {
+                (Some(lines), _) => Sprint::new(&options, root, known, lines).into(),
+                (None, Some(book)) => Book::new(&options, book, root, known).into(),
+                _ => Freestyle::new(&options, root, known).into(),
+            },
             options,
+        };
+        if let Some(to) = bot.automatic_switch() {
+            bot.switch(to);
         }
+        bot
     }
 
-    pub fn advance(&mut self, mv: Placement) {
-        puffin::profile_function!();
-        self.current.advance(self.queue.pop_front().unwrap(), mv);
-        if let Some(to) = self.mode.advance(&self.options, mv) {
+    /// Plays `mv`. Fails without changing anything if the queue is empty or `mv` doesn't fit on
+    /// the board.
+    pub fn advance(&mut self, mv: Placement) -> Result<PlacementInfo, AdvanceError> {
+        profile_function!();
+        let piece = match self.queue.front() {
+            Some(&piece) => piece,
+            None => return Err(AdvanceError::NoNextPiece),
+        };
+        let mut board = self.current.board;
+        board
+            .place_checked(mv.location)
+            .map_err(|(x, y)| AdvanceError::Overlaps(x, y))?;
+        // The frontend decides what was played, so a move our rules can't reach is still played,
+        // but it usually means the frontend's rules differ from the configured ones.
+        if !is_legal(&self.current.board, &mv, &self.options.config.move_rules()) {
+            eprintln!(
+                "warning: played move {:?} isn't reachable under the configured movement rules",
+                mv
+            );
+        }
+        let previous = self.current;
+        self.queue.pop_front();
+        let info = self
+            .current
+            .advance_with(piece, mv, &self.options.config.attack_table);
+        if self.options.config.keep_history {
+            self.history.push((previous, piece, info));
+        }
+        let switch = self.mode.advance(&self.options, &info);
+        self.node_cap = self.options.config.max_nodes;
+        self.known -= 1;
+        if self.known < lookahead(&self.options) {
+            if let Some(&piece) = self.queue.get(self.known) {
+                self.mode.new_piece(&self.options, piece);
+                self.known += 1;
+            }
+        }
+        if let Some(to) = switch.or_else(|| self.automatic_switch()) {
             self.switch(to);
         };
+        Ok(info)
     }
 
+    /// The mode the position calls for, if it isn't the current one: a perfect clear when the
+    /// solver finds one, or downstacking when the stack gets too tall and back.
+    fn automatic_switch(&self) -> Option<ModeSwitch> {
+        match self.mode {
+            ModeEnum::Freestyle(_) => {
+                if let Some(solution) = self.perfect_clear() {
+                    return Some(ModeSwitch::PerfectClear(solution));
+                }
+                Downstack::should_start(&self.options, &self.current)
+                    .then_some(ModeSwitch::Downstack)
+            }
+            ModeEnum::Downstack(_) => Downstack::should_stop(&self.options, &self.current)
+                .then_some(ModeSwitch::Freestyle),
+            _ => None,
+        }
+    }
+
+    /// A sequence of moves through the known queue ending in a perfect clear, if the solver is
+    /// enabled and finds one.
+    fn perfect_clear(&self) -> Option<Vec<Placement>> {
+        if !self.options.config.perfect_clear_solver {
+            return None;
+        }
+        let known: Vec<_> = self.queue.iter().take(self.known).copied().collect();
+        perfect_clear::solve(&self.options.config.move_rules(), &self.current, &known)
+    }
+
+    /// Adds a piece to the end of the queue. While speculating on a 7-bag, a piece the bag can't
+    /// produce is either a repeat of the last piece, which is taken to be the frontend sending it
+    /// twice and ignored, or a sign that the randomizer isn't a 7-bag after all. In that case the
+    /// piece is kept and search starts over without speculation, as it would have if the
+    /// starting queue had been impossible.
     pub fn new_piece(&mut self, piece: Piece) {
-        puffin::profile_function!();
+        profile_function!();
+        if self.options.speculate && !self.bag_after_queue().contains(piece) {
+            if self.queue.back() == Some(&piece) {
+                eprintln!(
+                    "warning: ignoring new piece {:?}, which looks like a duplicate",
+                    piece
+                );
+                return;
+            }
+            eprintln!(
+                "warning: new piece {:?} is impossible under 7-bag, disabling speculation",
+                piece
+            );
+            self.options.speculate = false;
+            self.current.bag = EnumSet::all();
+            self.queue.push_back(piece);
+            self.restart_search();
+            return;
+        }
         self.queue.push_back(piece);
-        self.mode.new_piece(&self.options, piece);
+        if self.known < lookahead(&self.options) {
+            self.mode.new_piece(&self.options, piece);
+            self.known += 1;
+        }
+    }
+
+    /// Undoes the most recent move, returning to the position before it with the search done
+    /// so far intact. Only possible with `keep_history`, and not past a switch between modes.
+    /// Returns whether there was a move to undo.
+    ///
+    /// If playing the move let the search see a piece past `max_lookahead`, the search before it
+    /// would now know too much, so it starts over and earlier moves can't be undone.
+    pub fn rewind(&mut self) -> bool {
+        profile_function!();
+        let (state, piece, info) = match self.history.pop() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.current = state;
+        self.queue.push_front(piece);
+        if self.known < lookahead(&self.options) {
+            self.mode.rewind(&self.options, &info);
+            self.known += 1;
+        } else {
+            self.restart_search();
+        }
+        true
+    }
+
+    /// Pushes garbage rows in from the bottom of the board. The search so far was for the board
+    /// without them, so it starts over. Returns `true` if the garbage pushed filled cells out of
+    /// the top of the board.
+    pub fn add_garbage(&mut self, column: u8, lines: u8) -> bool {
+        profile_function!();
+        let mut board = self.current.board;
+        let overflowed = board.add_garbage(column, lines);
+        self.reset_board(board);
+        overflowed
+    }
+
+    /// Like `add_garbage`, but with holes laid out according to `pattern`.
+    pub fn insert_garbage(
+        &mut self,
+        lines: u32,
+        pattern: GarbagePattern,
+        rng: &mut impl Rng,
+    ) -> bool {
+        profile_function!();
+        let mut board = self.current.board;
+        let overflowed = board.insert_garbage(lines, pattern, rng);
+        self.reset_board(board);
+        overflowed
+    }
+
+    fn reset_board(&mut self, board: Board) {
+        self.current.set_board(board);
+        self.restart_search();
+    }
+
+    /// Throws away the search, which no longer applies to the current position and queue.
+    fn restart_search(&mut self) {
+        let queue = self.queue.make_contiguous();
+        self.known = queue.len().min(lookahead(&self.options));
+        self.history.clear();
+        self.mode
+            .reset(&self.options, self.current, &queue[..self.known]);
+    }
+
+    pub fn config(&self) -> &BotConfig {
+        &self.options.config
+    }
+
+    pub fn state(&self) -> (GameState, Vec<Piece>) {
+        (self.current, self.queue.iter().copied().collect())
     }
 
     pub fn suggest(&self) -> Vec<Placement> {
-        puffin::profile_function!();
+        profile_function!();
         self.mode.suggest(&self.options)
     }
 
-    pub fn do_work(&self) -> Statistics {
-        puffin::profile_function!();
-        self.mode.do_work(&self.options)
+    /// How much better the suggested move evaluates than the average move available, if any
+    /// moves have been evaluated yet.
+    pub fn confidence(&self) -> Option<f64> {
+        profile_function!();
+        self.mode.confidence(&self.options)
+    }
+
+    /// The line of best moves through the known queue, with the evaluation after each move.
+    pub fn principal_variation(&self) -> Vec<(PlacementInfo, f64)> {
+        profile_function!();
+        self.mode.principal_variation(&self.options)
+    }
+
+    /// Every move available from the root with its evaluation and visit count.
+    pub fn root_moves(&self) -> Vec<(Placement, f64, u32)> {
+        profile_function!();
+        self.mode.root_moves(&self.options)
+    }
+
+    /// How many moves deep search has expanded positions from the current root.
+    pub fn depth(&self) -> u32 {
+        self.mode.depth(&self.options)
+    }
+
+    /// How many positions the search tree stores, and roughly how many bytes that takes.
+    pub fn tree_size(&self) -> (usize, usize) {
+        self.mode.tree_size(&self.options)
+    }
+
+    /// Whether the search tree holds more nodes than `max_nodes` allows.
+    pub fn over_node_cap(&self) -> bool {
+        match self.node_cap {
+            Some(cap) => self.mode.node_count(&self.options) > cap,
+            None => false,
+        }
+    }
+
+    /// Evicts the least valuable nodes from the search tree if it holds more than `max_nodes`,
+    /// bringing it down to 3/4 of the cap so search doesn't stop to evict after every node.
+    pub fn evict(&mut self) {
+        profile_function!();
+        let max = match self.options.config.max_nodes {
+            Some(max) if self.over_node_cap() => max,
+            _ => return,
+        };
+        self.mode.evict(&self.options, max / 4 * 3);
+        let remaining = self.mode.node_count(&self.options);
+        if remaining > max {
+            // Only expanded nodes and best moves are left, so evicting again right away won't
+            // help. Wait until the tree has grown by another quarter of the cap.
+            if self.node_cap == Some(max) {
+                eprintln!(
+                    "warning: search tree holds {} nodes after eviction, more than max_nodes",
+                    remaining
+                );
+            }
+            self.node_cap = Some(remaining + max / 4);
+        }
+    }
+
+    /// Searches on the current thread until `done` is true of the number of nodes searched so far,
+    /// evicting when over the node cap. Stops early once the tree can't grow any further, which
+    /// with nothing else searching is what a failed selection means. Returns the nodes searched.
+    pub fn search_until(
+        &mut self,
+        rng: &mut dyn RngCore,
+        mut done: impl FnMut(u64) -> bool,
+    ) -> u64 {
+        let mut nodes = 0;
+        while !done(nodes) {
+            let stats = self.do_work(rng);
+            if stats.expansions == 0 {
+                break;
+            }
+            nodes += stats.nodes;
+            if self.over_node_cap() {
+                self.evict();
+            }
+        }
+        nodes
+    }
+
+    pub fn do_work(&self, rng: &mut dyn RngCore) -> Statistics {
+        profile_function!();
+        self.mode.do_work(&self.options, rng)
+    }
+
+    fn bag_after_queue(&self) -> EnumSet<Piece> {
+        if self.current.memoryless {
+            return EnumSet::all();
+        }
+        let mut bag = self.current.bag;
+        for &p in &self.queue {
+            bag.remove(p);
+            if bag.is_empty() {
+                bag = EnumSet::all();
+            }
+        }
+        bag
     }
 
     fn switch(&mut self, to: ModeSwitch) {
-        puffin::profile_function!();
-        match to {
-            ModeSwitch::Freestyle => {
-                self.mode =
-                    Freestyle::new(&self.options, self.current, self.queue.make_contiguous()).into()
+        profile_function!();
+        // Book and perfect clear search alongside, so keep going from that search
+        match (&to, &mut self.mode) {
+            (ModeSwitch::Freestyle, ModeEnum::Book(book)) => {
+                self.mode = book.take_search().into();
+                return;
+            }
+            (ModeSwitch::Freestyle, ModeEnum::PerfectClear(pc)) => {
+                self.mode = pc.take_search().into();
+                return;
+            }
+            _ => {}
+        }
+        let queue = self.queue.make_contiguous();
+        let known = &queue[..queue.len().min(lookahead(&self.options))];
+        self.known = known.len();
+        self.history.clear();
+        self.mode = match to {
+            ModeSwitch::Freestyle => Freestyle::new(&self.options, self.current, known).into(),
+            ModeSwitch::Downstack => Downstack::new(&self.options, self.current, known).into(),
+            ModeSwitch::PerfectClear(solution) => {
+                PerfectClear::new(&self.options, self.current, known, solution).into()
             }
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct Statistics {
-    pub nodes: u64,
-    pub selections: u64,
-    pub expansions: u64,
+/// Loads the configured opening book if it has a move for `root`. Problems loading it are
+/// reported and the game goes on without it.
+fn load_book(options: &BotOptions, root: &GameState) -> Option<OpeningBook> {
+    let path = options.config.book.as_ref()?;
+    if options.config.sprint_lines.is_some() {
+        return None;
+    }
+    match OpeningBook::load(path) {
+        Ok(book) => book.get(root).is_some().then_some(book),
+        Err(e) => {
+            eprintln!("warning: not using opening book: {}", e);
+            None
+        }
+    }
 }
 
-impl Default for Statistics {
-    fn default() -> Self {
-        Statistics {
-            nodes: 0,
-            selections: 0,
-            expansions: 0,
+/// The best placement of `piece` in `state`, ignoring hold. With a `node_budget` of 0 each
+/// placement is judged by the freestyle evaluation alone. Otherwise about that many nodes are
+/// searched with `piece` in hand, looking ahead at the pieces which could follow it. This is
+/// seeded from `config.seed` when it is set.
+pub fn best_placement_for(
+    config: &BotConfig,
+    state: &GameState,
+    piece: Piece,
+    node_budget: u64,
+) -> Option<Placement> {
+    if node_budget > 0 {
+        let mut rng: Box<dyn RngCore> = match config.seed {
+            Some(seed) => Box::new(SmallRng::seed_from_u64(seed)),
+            None => Box::new(thread_rng()),
+        };
+        let options = BotOptions {
+            speculate: true,
+            config: Arc::new(config.clone()),
+        };
+        let mut root = *state;
+        root.reserve = piece;
+        let mut bot = Bot::new(options, root, &[]);
+        bot.search_until(&mut rng, |nodes| nodes >= node_budget);
+        // with no next piece known, only placements of the piece in hand are suggested
+        if let Some(&mv) = bot.suggest().first() {
+            return Some(mv);
         }
     }
+    score_placements(config, state, piece)
+        .into_iter()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(mv, _)| mv)
+}
+
+/// Every placement of `piece` in `state` reachable under the configured movement rules, with its
+/// freestyle evaluation, without searching ahead.
+pub fn score_placements(
+    config: &BotConfig,
+    state: &GameState,
+    piece: Piece,
+) -> Vec<(Placement, f64)> {
+    freestyle::score_placements(
+        &config.freestyle_weights,
+        &config.attack_table,
+        &config.move_rules(),
+        state,
+        piece,
+    )
+}
+
+/// How many queue pieces the mode is told about. At least the next piece is always needed to
+/// advance.
+fn lookahead(options: &BotOptions) -> usize {
+    options
+        .config
+        .max_lookahead
+        .map_or(usize::MAX, |n| n.max(1))
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Statistics {
+    pub nodes: u64,
+    pub selections: u64,
+    pub expansions: u64,
 }
 
 impl Statistics {
@@ -124,3 +825,356 @@ impl Statistics {
         self.expansions += other.expansions;
     }
 }
+
+#[cfg(test)]
+impl Bot {
+    /// Panics if the search tree is inconsistent, as `Dag::check_invariants` does. Only checks
+    /// freestyle, and only meaningful while nothing is searching.
+    pub(crate) fn check_invariants(&self) {
+        if let ModeEnum::Freestyle(freestyle) = &self.mode {
+            freestyle.check_invariants();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::data::{PieceLocation, Rotation, Spin};
+
+    /// A bot at the start of a 7-bag game with `queue` after the current piece.
+    fn bot(current: Piece, queue: &[Piece]) -> Bot {
+        bot_with(BotConfig::default(), current, queue)
+    }
+
+    fn bot_with(config: BotConfig, current: Piece, queue: &[Piece]) -> Bot {
+        let options = BotOptions {
+            speculate: true,
+            config: Arc::new(config),
+        };
+        let root = GameState::new(Board::default(), current, EnumSet::all() - current, 0, 0);
+        Bot::new(options, root, queue)
+    }
+
+    #[test]
+    fn duplicate_new_piece_is_ignored() {
+        use Piece::*;
+        let mut bot = bot(T, &[I, O]);
+        bot.new_piece(O);
+        assert_eq!(bot.state().1, [I, O]);
+        assert!(bot.options.speculate);
+        bot.new_piece(L);
+        assert_eq!(bot.state().1, [I, O, L]);
+    }
+
+    #[test]
+    fn rewind_stays_within_the_lookahead() {
+        use Piece::*;
+        let queue = [I, O, L, J, S, Z];
+        for max_lookahead in [None, Some(2)] {
+            let config = BotConfig {
+                keep_history: true,
+                max_lookahead,
+                ..BotConfig::default()
+            };
+            let mut bot = bot_with(config, T, &queue);
+            let mut rng = SmallRng::seed_from_u64(0);
+            let known = bot.known;
+            let before = bot.state();
+            bot.search_until(&mut rng, |nodes| nodes >= 500);
+            let mv = bot.suggest()[0];
+            bot.advance(mv).unwrap();
+            assert!(bot.rewind());
+            assert_eq!(bot.known, known);
+            assert_eq!(bot.state().0.board, before.0.board);
+            assert_eq!(bot.state().1, before.1);
+            bot.search_until(&mut rng, |nodes| nodes >= 500);
+            assert!(!bot.suggest().is_empty());
+        }
+    }
+
+    #[test]
+    fn max_lookahead_caps_the_known_queue() {
+        use Piece::*;
+        let bag = [I, O, L, J, S, Z, T];
+        let queue: Vec<_> = bag.iter().cycle().take(20).copied().collect();
+        let config = BotConfig {
+            max_lookahead: Some(5),
+            ..BotConfig::default()
+        };
+        let mut bot = bot_with(config, T, &queue);
+        let mut rng = SmallRng::seed_from_u64(0);
+        assert_eq!(bot.known, 5);
+        assert_eq!(bot.state().1.len(), 20);
+
+        bot.search_until(&mut rng, |nodes| nodes >= 2000);
+
+        // playing a move makes room for exactly one more piece of the queue
+        let mv = bot.suggest()[0];
+        bot.advance(mv).unwrap();
+        assert_eq!(bot.known, 5);
+        assert_eq!(bot.state().1.len(), 19);
+        bot.new_piece(T);
+        assert!(bot.options.speculate);
+        assert_eq!(bot.known, 5);
+        assert_eq!(bot.state().1.len(), 20);
+    }
+
+    #[test]
+    fn advance_waits_for_the_next_piece() {
+        use Piece::*;
+        let mut bot = bot(T, &[]);
+        let mv = Placement {
+            location: PieceLocation {
+                piece: T,
+                rotation: Rotation::North,
+                x: 4,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+        assert_eq!(bot.advance(mv).unwrap_err(), AdvanceError::NoNextPiece);
+        assert_eq!(bot.state().0.board, Board::default());
+
+        bot.new_piece(I);
+        bot.advance(mv).unwrap();
+        assert_eq!(bot.state().0.reserve, I);
+    }
+
+    #[test]
+    fn placement_queries_follow_move_rules() {
+        use Piece::*;
+        // an I can only fill the bottom row under the overhang by soft dropping and sliding left
+        let board = Board::from_ascii("###.......\n..........");
+        let state = GameState::new(board, I, EnumSet::all(), 0, 0);
+        let tuck = PieceLocation {
+            piece: I,
+            rotation: Rotation::North,
+            x: 1,
+            y: 0,
+        };
+        let free = BotConfig {
+            seed: Some(0),
+            ..BotConfig::default()
+        };
+        let no_soft_drop = BotConfig {
+            max_soft_drop: Some(0),
+            ..free.clone()
+        };
+
+        let tucks = |config| {
+            score_placements(config, &state, I)
+                .iter()
+                .any(|(mv, _)| mv.location == tuck)
+        };
+        assert!(tucks(&free));
+        assert!(!tucks(&no_soft_drop));
+
+        for budget in [0, 2000] {
+            let best = best_placement_for(&free, &state, I, budget).unwrap();
+            assert_eq!(best.location, tuck);
+            let best = best_placement_for(&no_soft_drop, &state, I, budget).unwrap();
+            assert_eq!(best.location.piece, I);
+            assert_ne!(best.location, tuck);
+        }
+    }
+
+    #[test]
+    fn impossible_new_piece_stops_speculation() {
+        use Piece::*;
+        let mut bot = bot(T, &[I, O]);
+        bot.new_piece(I);
+        assert_eq!(bot.state().1, [I, O, I]);
+        assert!(!bot.options.speculate);
+        assert_eq!(bot.state().0.bag, EnumSet::all());
+
+        // without a bag to check against, repeats can't be told apart from duplicates
+        bot.new_piece(I);
+        assert_eq!(bot.state().1, [I, O, I, I]);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            bot.do_work(&mut rng);
+        }
+        let mv = bot.suggest()[0];
+        bot.advance(mv).unwrap();
+    }
+
+    #[test]
+    fn missing_sprint_weights_use_defaults() {
+        let mut json = serde_json::to_value(BotConfig::default()).unwrap();
+        json.as_object_mut().unwrap().remove("sprint_weights");
+        let (config, unknown) = BotConfig::from_json(json).unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(
+            serde_json::to_value(config.sprint_weights).unwrap(),
+            serde_json::to_value(BotConfig::default().sprint_weights).unwrap(),
+        );
+    }
+
+    #[test]
+    fn out_of_range_weights_are_rejected() {
+        let mut json = serde_json::to_value(BotConfig::default()).unwrap();
+        json["freestyle_weights"]["holes"] = serde_json::json!(1e39);
+        let error = BotConfig::from_json(json).unwrap_err().to_string();
+        assert!(error.contains("freestyle_weights.holes"), "{}", error);
+
+        let mut weights = serde_json::to_value(BotConfig::default().freestyle_weights).unwrap();
+        weights["normal_clears"][4] = serde_json::json!(-1e39);
+        let error = BotConfig::default()
+            .with_weights(Some(weights), None, None)
+            .unwrap_err();
+        assert!(error.contains("normal_clears.4"), "{}", error);
+    }
+
+    #[test]
+    fn well_column_off_the_board_is_rejected() {
+        let mut json = serde_json::to_value(BotConfig::default()).unwrap();
+        json["sprint_weights"]["well_column"] = serde_json::json!(10);
+        let error = BotConfig::from_json(json).unwrap_err().to_string();
+        assert!(
+            error.contains("sprint_weights: well_column 10"),
+            "{}",
+            error
+        );
+
+        let mut weights = serde_json::to_value(BotConfig::default().freestyle_weights).unwrap();
+        weights["well_column"] = serde_json::json!(10);
+        let error = BotConfig::default()
+            .with_weights(Some(weights), None, None)
+            .unwrap_err();
+        assert!(error.contains("well_column 10"), "{}", error);
+    }
+
+    #[test]
+    fn zero_beam_width_is_rejected() {
+        let mut json = serde_json::to_value(BotConfig::default()).unwrap();
+        json["beam_width"] = serde_json::json!(0);
+        let error = BotConfig::from_json(json.clone()).unwrap_err().to_string();
+        assert!(error.contains("beam_width"), "{}", error);
+
+        json["beam_width"] = serde_json::json!(1);
+        assert!(BotConfig::from_json(json).is_ok());
+    }
+
+    #[test]
+    fn pinned_well_stays_open() {
+        use rand::Rng;
+
+        let mut config = BotConfig::default();
+        config.freestyle_weights.well_column = Some(9);
+        for seed in 0..3 {
+            let mut rng = SmallRng::seed_from_u64(seed);
+            let mut bag = EnumSet::all();
+            let mut draw = |rng: &mut SmallRng| {
+                let piece = bag.iter().nth(rng.gen_range(0..bag.len())).unwrap();
+                bag.remove(piece);
+                if bag.is_empty() {
+                    bag = EnumSet::all();
+                }
+                piece
+            };
+            let current = draw(&mut rng);
+            let queue: Vec<_> = (0..5).map(|_| draw(&mut rng)).collect();
+            let mut bot = bot_with(config.clone(), current, &queue);
+
+            let mut open = 0;
+            for _ in 0..40 {
+                let mut stats = Statistics::default();
+                while stats.nodes < 300 {
+                    let step = bot.do_work(&mut rng);
+                    if step.expansions == 0 {
+                        break;
+                    }
+                    stats.accumulate(step);
+                }
+                let mv = bot.suggest()[0];
+                bot.advance(mv).unwrap();
+                bot.new_piece(draw(&mut rng));
+                if bot.state().0.board.cols[9] == 0 {
+                    open += 1;
+                }
+            }
+            assert!(
+                open >= 30,
+                "seed {}: well open after {} of 40 moves",
+                seed,
+                open
+            );
+        }
+    }
+
+    #[test]
+    fn search_stops_at_end_of_queue_only_when_configured() {
+        use Piece::*;
+        /// Searches until nothing is left to expand, giving up after `limit` steps.
+        fn steps_to_exhaust(bot: Bot, limit: usize) -> Option<usize> {
+            let mut rng = SmallRng::seed_from_u64(0);
+            (0..limit).find(|_| bot.do_work(&mut rng).expansions == 0)
+        }
+
+        let stop = BotConfig {
+            queue_exhausted: QueueExhausted::Stop,
+            ..BotConfig::default()
+        };
+        // the root and then its children are expanded, after which the queue has run out
+        let steps = steps_to_exhaust(bot_with(stop, T, &[I, O]), 500);
+        assert!(steps.is_some_and(|steps| steps > 1), "{:?}", steps);
+        assert!(steps_to_exhaust(bot(T, &[I, O]), 500).is_none());
+    }
+
+    #[test]
+    fn downstack_hands_back_below_low_thresholds() {
+        use Piece::*;
+        let options = |height| BotOptions {
+            speculate: true,
+            config: Arc::new(BotConfig {
+                downstack_height: Some(height),
+                ..BotConfig::default()
+            }),
+        };
+        let garbage = |lines| {
+            let mut board = Board::default();
+            board.add_garbage(0, lines);
+            GameState::new(board, T, EnumSet::all(), 0, 0)
+        };
+
+        let tall = options(10);
+        assert!(Downstack::should_start(&tall, &garbage(11)));
+        assert!(!Downstack::should_stop(&tall, &garbage(7)));
+        assert!(Downstack::should_stop(&tall, &garbage(6)));
+
+        // thresholds below the hysteresis used to never hand back, even with an empty board
+        for height in [0, 2, 4] {
+            let low = options(height);
+            assert!(Downstack::should_start(&low, &garbage(height as u8 + 1)));
+            assert!(!Downstack::should_stop(&low, &garbage(1)));
+            assert!(Downstack::should_stop(&low, &garbage(0)));
+        }
+
+        let bot = Bot::new(options(2), garbage(12), &[I, O, L, J]);
+        assert!(matches!(bot.mode, ModeEnum::Downstack(_)));
+    }
+
+    #[test]
+    fn plays_out_a_perfect_clear_opening() {
+        use Piece::*;
+        let config = BotConfig {
+            perfect_clear_solver: true,
+            ..BotConfig::default()
+        };
+        let mut bot = bot_with(config, T, &[I, O, L, J, S, Z, T, I, O, L]);
+        assert!(matches!(bot.mode, ModeEnum::PerfectClear(_)));
+
+        // an empty board takes 10 pieces to perfect clear
+        let mut last = None;
+        for _ in 0..10 {
+            assert!(!last.is_some_and(|info: PlacementInfo| info.perfect_clear));
+            last = Some(bot.advance(bot.suggest()[0]).unwrap());
+        }
+        assert!(last.unwrap().perfect_clear);
+        assert!(matches!(bot.mode, ModeEnum::Freestyle(_)));
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use enumset::EnumSet;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use super::freestyle::Freestyle;
+use super::{BotOptions, Mode, ModeSwitch, Statistics};
+use crate::data::*;
+use crate::map::StateMap;
+
+/// Positions with a known best move, for playing openings exactly.
+///
+/// The file is JSON lines, one entry per line in the format of `Entry`. Entries are stored by
+/// the same hash of the game state the search uses, so a book is small enough to load for every
+/// game.
+pub struct OpeningBook {
+    moves: StateMap<Placement, ahash::RandomState, 1>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cols: [u64; 10],
+    bag: Vec<Piece>,
+    reserve: Piece,
+    b2b: u16,
+    combo: u8,
+    placement: Placement,
+}
+
+impl OpeningBook {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+        let moves = StateMap::default();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+            let state = GameState::new(
+                Board::new(entry.cols),
+                entry.reserve,
+                entry.bag.into_iter().collect::<EnumSet<_>>(),
+                entry.b2b,
+                entry.combo,
+            );
+            *moves.get_or_insert_with(&state, || entry.placement) = entry.placement;
+        }
+        Ok(OpeningBook { moves })
+    }
+
+    pub fn get(&self, state: &GameState) -> Option<Placement> {
+        self.moves.get(state).map(|mv| *mv)
+    }
+}
+
+/// Plays moves straight from an opening book for as long as the position is in it, then hands
+/// over to freestyle. Freestyle searches in the background meanwhile, so its tree is ready to
+/// take over from when the book runs out.
+pub struct Book {
+    book: OpeningBook,
+    root: GameState,
+    queue: VecDeque<Piece>,
+    /// The roots before each move with the piece taken from the queue, for rewinding.
+    history: Vec<(GameState, Piece)>,
+    /// Only taken when switching to freestyle.
+    search: Option<Box<Freestyle>>,
+}
+
+impl Book {
+    pub fn new(options: &BotOptions, book: OpeningBook, root: GameState, queue: &[Piece]) -> Self {
+        Book {
+            book,
+            root,
+            queue: queue.iter().copied().collect(),
+            history: vec![],
+            search: Some(Box::new(Freestyle::new(options, root, queue))),
+        }
+    }
+
+    /// The search running in the background, to keep playing with once out of book.
+    pub fn take_search(&mut self) -> Freestyle {
+        *self.search.take().expect("search already taken")
+    }
+
+    fn search(&self) -> &Freestyle {
+        self.search.as_ref().expect("search already taken")
+    }
+
+    fn search_mut(&mut self) -> &mut Freestyle {
+        self.search.as_mut().expect("search already taken")
+    }
+
+    /// The book move for the current root, if the piece it uses is available.
+    fn book_move(&self) -> Option<Placement> {
+        let mv = self.book.get(&self.root)?;
+        let piece = mv.location.piece;
+        (piece == self.root.reserve || Some(&piece) == self.queue.front()).then_some(mv)
+    }
+}
+
+impl Mode for Book {
+    fn advance(&mut self, options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch> {
+        profile_function!();
+        self.search_mut().advance(options, info);
+        let next = self
+            .queue
+            .pop_front()
+            .expect("cannot advance without next piece");
+        self.history.push((self.root, next));
+        self.root.advance(next, info.placement);
+        self.book
+            .get(&self.root)
+            .is_none()
+            .then_some(ModeSwitch::Freestyle)
+    }
+
+    fn new_piece(&mut self, options: &BotOptions, piece: Piece) {
+        profile_function!();
+        self.queue.push_back(piece);
+        self.search_mut().new_piece(options, piece);
+    }
+
+    fn suggest(&self, options: &BotOptions) -> Vec<Placement> {
+        profile_function!();
+        match self.book_move() {
+            Some(mv) => vec![mv],
+            None => self.search().suggest(options),
+        }
+    }
+
+    fn confidence(&self, options: &BotOptions) -> Option<f64> {
+        self.search().confidence(options)
+    }
+
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)> {
+        self.search().principal_variation(options)
+    }
+
+    fn root_moves(&self, options: &BotOptions) -> Vec<(Placement, f64, u32)> {
+        self.search().root_moves(options)
+    }
+
+    fn depth(&self, options: &BotOptions) -> u32 {
+        self.search().depth(options)
+    }
+
+    fn node_count(&self, options: &BotOptions) -> usize {
+        self.search().node_count(options)
+    }
+
+    fn tree_size(&self, options: &BotOptions) -> (usize, usize) {
+        self.search().tree_size(options)
+    }
+
+    fn evict(&mut self, options: &BotOptions, target: usize) -> usize {
+        self.search_mut().evict(options, target)
+    }
+
+    fn rewind(&mut self, options: &BotOptions, info: &PlacementInfo) {
+        self.search_mut().rewind(options, info);
+        if let Some((root, next)) = self.history.pop() {
+            self.queue.push_front(next);
+            self.root = root;
+        }
+    }
+
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]) {
+        self.root = root;
+        self.queue = queue.iter().copied().collect();
+        self.history.clear();
+        self.search_mut().reset(options, root, queue);
+    }
+
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics {
+        self.search().do_work(options, rng)
+    }
+}
@@ -0,0 +1,111 @@
+use rand::RngCore;
+
+use super::freestyle::{self, Eval};
+use super::{BotOptions, Mode, ModeSwitch, Statistics};
+use crate::dag::Dag;
+use crate::data::*;
+
+/// How far the stack must drop below `downstack_height` before going back to freestyle, so the
+/// bot doesn't switch back and forth when the stack hovers around the threshold. Thresholds
+/// lower than this wait for an empty board instead.
+const HYSTERESIS: u32 = 4;
+
+/// Digs down when the stack is dangerously high, ignoring attack in favor of clearing lines and
+/// uncovering holes. Hands back to freestyle once the stack is low again.
+pub struct Downstack {
+    dag: Dag<Eval>,
+}
+
+impl Downstack {
+    pub fn new(options: &BotOptions, root: GameState, queue: &[Piece]) -> Self {
+        Downstack {
+            dag: Dag::new(root, queue, options.config.keep_history),
+        }
+    }
+
+    /// Whether freestyle should hand over to downstacking with the stack this high.
+    pub fn should_start(options: &BotOptions, state: &GameState) -> bool {
+        options
+            .config
+            .downstack_height
+            .is_some_and(|height| stack_height(state) > height)
+    }
+
+    /// Whether the stack is low enough to go back to freestyle. With `downstack_height` at 0,
+    /// downstacking starts as soon as anything is on the board and lasts until it is empty.
+    pub fn should_stop(options: &BotOptions, state: &GameState) -> bool {
+        options
+            .config
+            .downstack_height
+            .is_none_or(|height| stack_height(state) <= height.saturating_sub(HYSTERESIS))
+    }
+}
+
+fn stack_height(state: &GameState) -> u32 {
+    state.heights().iter().copied().max().unwrap_or(0) as u32
+}
+
+impl Mode for Downstack {
+    fn advance(&mut self, _options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch> {
+        profile_function!();
+        self.dag.advance(info.placement);
+        None
+    }
+
+    fn new_piece(&mut self, _options: &BotOptions, piece: Piece) {
+        profile_function!();
+        self.dag.add_piece(piece);
+    }
+
+    fn suggest(&self, _options: &BotOptions) -> Vec<Placement> {
+        profile_function!();
+        self.dag.suggest()
+    }
+
+    fn confidence(&self, _options: &BotOptions) -> Option<f64> {
+        freestyle::confidence(&self.dag)
+    }
+
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)> {
+        freestyle::principal_variation(&self.dag, options)
+    }
+
+    fn root_moves(&self, _options: &BotOptions) -> Vec<(Placement, f64, u32)> {
+        freestyle::root_moves(&self.dag)
+    }
+
+    fn depth(&self, _options: &BotOptions) -> u32 {
+        self.dag.depth()
+    }
+
+    fn node_count(&self, _options: &BotOptions) -> usize {
+        self.dag.node_count()
+    }
+
+    fn tree_size(&self, _options: &BotOptions) -> (usize, usize) {
+        self.dag.tree_size()
+    }
+
+    fn evict(&mut self, _options: &BotOptions, target: usize) -> usize {
+        self.dag.evict(target)
+    }
+
+    fn rewind(&mut self, _options: &BotOptions, _info: &PlacementInfo) {
+        self.dag.rewind();
+    }
+
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]) {
+        self.dag = Dag::new(root, queue, options.config.keep_history);
+    }
+
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics {
+        profile_function!();
+        freestyle::do_work(
+            &self.dag,
+            options,
+            &options.config.downstack_weights,
+            options.config.exploitation(),
+            rng,
+        )
+    }
+}
@@ -1,99 +1,253 @@
+use std::cell::Cell;
 use std::ops::Add;
 
 use enum_map::EnumMap;
 use enumset::EnumSet;
 use ordered_float::OrderedFloat;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
-use super::{BotOptions, Mode, ModeSwitch, Statistics};
+use super::{BotOptions, Mode, ModeSwitch, QueueExhausted, Statistics, Tiebreak};
 use crate::dag::{ChildData, Dag, Evaluation};
 use crate::data::*;
-use crate::movegen::find_moves;
+use crate::movegen::{find_moves_with_rules, MoveRules, Scratch, SPAWN_Y};
 
 pub struct Freestyle {
     dag: Dag<Eval>,
 }
 
 impl Freestyle {
-    pub fn new(_options: &BotOptions, root: GameState, queue: &[Piece]) -> Self {
+    pub fn new(options: &BotOptions, root: GameState, queue: &[Piece]) -> Self {
         Freestyle {
-            dag: Dag::new(root, queue),
+            dag: Dag::new(root, queue, options.config.keep_history),
         }
     }
 }
 
 impl Mode for Freestyle {
-    fn advance(&mut self, _options: &BotOptions, mv: Placement) -> Option<ModeSwitch> {
-        puffin::profile_function!();
-        self.dag.advance(mv);
+    fn advance(&mut self, _options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch> {
+        profile_function!();
+        self.dag.advance(info.placement);
         None
     }
 
     fn new_piece(&mut self, _options: &BotOptions, piece: Piece) {
-        puffin::profile_function!();
+        profile_function!();
         self.dag.add_piece(piece);
     }
 
     fn suggest(&self, _options: &BotOptions) -> Vec<Placement> {
-        puffin::profile_function!();
+        profile_function!();
         self.dag.suggest()
     }
 
-    fn do_work(&self, options: &BotOptions) -> Statistics {
-        puffin::profile_function!();
-        let mut new_stats = Statistics::default();
-        new_stats.selections += 1;
+    fn confidence(&self, _options: &BotOptions) -> Option<f64> {
+        confidence(&self.dag)
+    }
 
-        if let Some(node) = self
-            .dag
-            .select(options.speculate, options.config.freestyle_exploitation)
-        {
-            let (state, next) = node.state();
-            let next_possibilities = next.map(EnumSet::only).unwrap_or(state.bag);
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)> {
+        principal_variation(&self.dag, options)
+    }
 
-            let mut moves = EnumMap::default();
-            {
-                puffin::profile_scope!("movegen");
-                for piece in next_possibilities | state.reserve {
-                    moves[piece] = find_moves(&state.board, piece);
-                }
+    fn root_moves(&self, _options: &BotOptions) -> Vec<(Placement, f64, u32)> {
+        root_moves(&self.dag)
+    }
+
+    fn depth(&self, _options: &BotOptions) -> u32 {
+        self.dag.depth()
+    }
+
+    fn node_count(&self, _options: &BotOptions) -> usize {
+        self.dag.node_count()
+    }
+
+    fn tree_size(&self, _options: &BotOptions) -> (usize, usize) {
+        self.dag.tree_size()
+    }
+
+    fn evict(&mut self, _options: &BotOptions, target: usize) -> usize {
+        self.dag.evict(target)
+    }
+
+    fn rewind(&mut self, _options: &BotOptions, _info: &PlacementInfo) {
+        self.dag.rewind();
+    }
+
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]) {
+        self.dag = Dag::new(root, queue, options.config.keep_history);
+    }
+
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics {
+        profile_function!();
+        do_work(
+            &self.dag,
+            options,
+            &options.config.freestyle_weights,
+            options.config.exploitation(),
+            rng,
+        )
+    }
+}
+
+#[cfg(test)]
+impl Freestyle {
+    pub(crate) fn check_invariants(&self) {
+        self.dag.check_invariants();
+    }
+}
+
+pub(super) fn confidence(dag: &Dag<Eval>) -> Option<f64> {
+    let evals = dag.root_child_evals();
+    let best = evals.iter().max()?.value.0;
+    let average = evals.iter().map(|e| e.value.0).sum::<f32>() / evals.len() as f32;
+    Some((best - average) as f64)
+}
+
+pub(super) fn principal_variation(
+    dag: &Dag<Eval>,
+    options: &BotOptions,
+) -> Vec<(PlacementInfo, f64)> {
+    dag.principal_variation(&options.config.attack_table)
+        .into_iter()
+        .map(|(info, eval)| (info, eval.value.0 as f64))
+        .collect()
+}
+
+pub(super) fn root_moves(dag: &Dag<Eval>) -> Vec<(Placement, f64, u32)> {
+    dag.root_moves()
+        .into_iter()
+        .map(|(mv, eval, visits)| (mv, eval.value.0 as f64, visits))
+        .collect()
+}
+
+/// Limits on the `height_danger` term. Evaluations are averaged during backpropagation, so an
+/// infinite term would poison every ancestor with NaN.
+const MAX_DANGER_EXPONENT: u32 = 20;
+const MAX_DANGER: f32 = 1e6;
+
+type MoveLists = EnumMap<Piece, Vec<(Placement, u32)>>;
+
+thread_local! {
+    static MOVEGEN_BUFFERS: Cell<(Scratch, MoveLists)> = Default::default();
+}
+
+/// Performs one selection and expansion of `dag`, evaluating the new nodes using `weights`.
+pub(super) fn do_work(
+    dag: &Dag<Eval>,
+    options: &BotOptions,
+    weights: &Weights,
+    exploitation: f64,
+    rng: &mut dyn RngCore,
+) -> Statistics {
+    let mut new_stats = Statistics::default();
+    new_stats.selections += 1;
+
+    let speculate =
+        options.speculate && options.config.queue_exhausted == QueueExhausted::Speculate;
+    if let Some(node) = dag.select(speculate, exploitation, rng) {
+        let (state, next) = node.state();
+        let next_possibilities = next.map(EnumSet::only).unwrap_or(state.bag);
+        let parent = state;
+
+        let (mut scratch, mut moves) = MOVEGEN_BUFFERS.with(Cell::take);
+        let rules = options.config.move_rules();
+        {
+            profile_scope!("movegen");
+            for piece in next_possibilities | state.reserve {
+                find_moves_with_rules(&state.board, piece, &rules, &mut scratch, &mut moves[piece]);
             }
+        }
 
-            let mut children: EnumMap<_, Vec<_>> = EnumMap::default();
+        let mut children: EnumMap<_, Vec<_>> = EnumMap::default();
 
-            {
-                puffin::profile_scope!("eval");
-                for next in next_possibilities {
-                    let moves = moves[next].iter().chain(if next == state.reserve {
-                        [].iter()
-                    } else {
-                        moves[state.reserve].iter()
-                    });
-                    for &(mv, sd_distance) in moves {
-                        let mut state = state;
-                        let info = state.advance(next, mv);
-
-                        let (eval, reward) =
-                            evaluate(&options.config.freestyle_weights, state, &info, sd_distance);
-
-                        children[next].push(ChildData {
-                            resulting_state: state,
-                            mv,
-                            eval,
-                            reward,
-                        });
+        {
+            profile_scope!("eval");
+            for next in next_possibilities {
+                // Either `next` is placed, or it is held and the reserve piece is placed instead.
+                // Holding a piece identical to the reserve changes nothing, so it isn't a
+                // separate choice.
+                let place_next = moves[next].iter();
+                let hold_next = match next == state.reserve {
+                    true => [].iter(),
+                    false => moves[state.reserve].iter(),
+                };
+                let mut list = vec![];
+                for &(mv, sd_distance) in place_next.chain(hold_next) {
+                    let mut state = state;
+                    let info = state.advance_with(next, mv, &options.config.attack_table);
+
+                    let (mut eval, reward) = evaluate(weights, state, &info, sd_distance, &parent);
+                    if let Some(known) = options
+                        .config
+                        .warm_start
+                        .as_ref()
+                        .and_then(|w| w.get(&state))
+                    {
+                        eval.value = known.into();
                     }
 
-                    new_stats.nodes += children[next].len() as u64;
+                    let child = ChildData {
+                        resulting_state: state,
+                        mv,
+                        eval,
+                        reward,
+                    };
+                    list.push((info.lines_cleared, child));
                 }
-            }
 
-            new_stats.expansions += 1;
-            node.expand(children);
+                // Children are sorted by evaluation with a stable sort, so this order decides
+                // between children that evaluate the same.
+                match options.config.tiebreak {
+                    Tiebreak::MoveOrder => {}
+                    Tiebreak::ClearNow => list.sort_by_key(|&(lines, _)| std::cmp::Reverse(lines)),
+                    Tiebreak::Setup => list.sort_by_key(|&(lines, _)| lines),
+                }
+                children[next].extend(list.into_iter().map(|(_, child)| child));
+
+                if let Some(width) = options.config.beam_width {
+                    let list = &mut children[next];
+                    list.sort_by_key(|c| std::cmp::Reverse(c.eval + c.reward));
+                    list.truncate(width);
+                }
+
+                new_stats.nodes += children[next].len() as u64;
+            }
         }
+        MOVEGEN_BUFFERS.with(|buffers| buffers.set((scratch, moves)));
 
-        new_stats
+        new_stats.expansions += 1;
+        node.expand(children);
     }
+
+    new_stats
+}
+
+/// Evaluates every placement of `piece` reachable under `rules` without searching further.
+pub(super) fn score_placements(
+    weights: &Weights,
+    attack: &AttackTable,
+    rules: &MoveRules,
+    state: &GameState,
+    piece: Piece,
+) -> Vec<(Placement, f64)> {
+    profile_function!();
+    let mut moves = vec![];
+    find_moves_with_rules(
+        &state.board,
+        piece,
+        rules,
+        &mut Scratch::default(),
+        &mut moves,
+    );
+    moves
+        .into_iter()
+        .map(|(mv, sd_distance)| {
+            let mut resulting = *state;
+            let info = resulting.advance_with(piece, mv, attack);
+            let (eval, reward) = evaluate(weights, resulting, &info, sd_distance, state);
+            (mv, (eval + reward).value.0 as f64)
+        })
+        .collect()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -102,23 +256,189 @@ pub struct Weights {
     pub max_cell_covered_height: u32,
     pub holes: f32,
     pub row_transitions: f32,
+    #[serde(default)]
+    pub height_variance: f32,
+    /// Penalty per cell of height difference between neighboring columns, skipping over the well.
+    #[serde(default)]
+    pub bumpiness: f32,
+    /// Like `bumpiness`, but with each difference squared so single deep steps cost more.
+    #[serde(default)]
+    pub bumpiness_sq: f32,
+    /// Penalty per cell of imbalance between filled cells on the two checkerboard colors.
+    #[serde(default)]
+    pub parity: f32,
     pub height: f32,
     pub height_upper_half: f32,
     pub height_upper_quarter: f32,
+    /// Weight of `height_danger_exp ^ (height - danger_threshold)` for stacks above
+    /// `danger_threshold`, so each extra row near the top costs more than the last.
+    #[serde(default)]
+    pub height_danger: f32,
+    /// Base of the `height_danger` term, at least 1.
+    #[serde(default = "default_height_danger_exp")]
+    pub height_danger_exp: f32,
+    /// Height above which `height_danger` applies. When unset, it never does.
+    #[serde(default)]
+    pub danger_threshold: Option<u32>,
+    /// Divided by one more than the number of empty rows between the stack and the spawn row, so
+    /// it grows steeply as the stack approaches topping out.
+    #[serde(default)]
+    pub spawn_clearance: f32,
     pub tetris_well_depth: f32,
+    /// Penalty per filled cell above the floor of the well, the same column `tetris_well_depth`
+    /// measures.
+    #[serde(default)]
+    pub covered_well: f32,
+    /// Column (0 to 9) to keep as the well. When unset, the well is whichever column is
+    /// currently lowest.
+    #[serde(default)]
+    pub well_column: Option<u8>,
+    /// Penalty per filled cell of `well_column`, so that the well isn't filled in before it is
+    /// ready for a tetris. Only applies with `well_column` set.
+    #[serde(default)]
+    pub filled_well: f32,
     pub tslot: [f32; 4],
+    /// `flat` skips the well and T-slot terms entirely.
+    #[serde(default)]
+    pub strategy: Strategy,
 
     pub has_back_to_back: f32,
+    /// Penalty per clear in the back-to-back chain for ending it with an easy line clear.
+    #[serde(default)]
+    pub back_to_back_break: f32,
     pub wasted_t: f32,
+    #[serde(default)]
+    pub wasted_i: f32,
+    #[serde(default)]
+    pub hold_swap: f32,
+    #[serde(default)]
+    pub reserved_piece: Option<Piece>,
+    #[serde(default)]
+    pub reserved_piece_value: f32,
     pub softdrop: f32,
 
     pub normal_clears: [f32; 5],
     pub mini_spin_clears: [f32; 3],
     pub spin_clears: [f32; 4],
     pub back_to_back_clear: f32,
+    /// Extra reward for back-to-back clears by position in the chain, starting with the first
+    /// back-to-back clear. Chains longer than the list use the last entry.
+    #[serde(default)]
+    pub back_to_back_chain: Vec<f32>,
     pub combo_attack: f32,
+    /// Reward per line of garbage sent, as counted by the configured attack table.
+    #[serde(default)]
+    pub attack: f32,
+    /// Reward for clearing lines while a combo is active.
+    #[serde(default)]
+    pub combo_continue: f32,
+    /// Penalty for not clearing lines while a combo is active.
+    #[serde(default)]
+    pub combo_break: f32,
     pub perfect_clear: f32,
     pub perfect_clear_override: bool,
+    /// Reward per cleared line containing garbage. Requires the `garbage-plane` feature.
+    #[serde(default)]
+    pub garbage_clear: f32,
+    /// A structure to build toward, such as an opener or a named setup.
+    #[serde(default)]
+    pub pattern: Option<Pattern>,
+}
+
+/// A target shape for the bottom of the stack, drawn as text with the top row first in the same
+/// format as `Board::from_ascii`. Each filled cell of the pattern that is filled on the board
+/// counts as progress, and each filled cell of the board among the pattern's rows that the
+/// pattern leaves empty counts against it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "PatternRows", into = "PatternRows")]
+pub struct Pattern {
+    pub rows: Vec<String>,
+    /// Reward per cell of progress toward the pattern.
+    pub weight: f32,
+    board: Board,
+    height: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct PatternRows {
+    rows: Vec<String>,
+    weight: f32,
+}
+
+impl From<PatternRows> for Pattern {
+    fn from(p: PatternRows) -> Self {
+        let board = Board::from_ascii(&p.rows.join("\n"));
+        let height = board
+            .cols
+            .iter()
+            .map(|&c| 64 - c.leading_zeros())
+            .max()
+            .unwrap();
+        Pattern {
+            rows: p.rows,
+            weight: p.weight,
+            board,
+            height,
+        }
+    }
+}
+
+impl From<Pattern> for PatternRows {
+    fn from(p: Pattern) -> Self {
+        PatternRows {
+            rows: p.rows,
+            weight: p.weight,
+        }
+    }
+}
+
+impl Weights {
+    /// Rejects settings which can't be evaluated, such as a well column off the board.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(column) = self.well_column.filter(|&c| c >= 10) {
+            return Err(format!("well_column {} is off the board", column));
+        }
+        if !(1.0..f32::INFINITY).contains(&self.height_danger_exp) {
+            return Err(format!(
+                "height_danger_exp {} must be finite and at least 1",
+                self.height_danger_exp
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn default_height_danger_exp() -> f32 {
+    2.0
+}
+
+impl Pattern {
+    fn progress(&self, board: &Board) -> i32 {
+        // extra cells the pattern doesn't have only matter within the pattern's rows
+        let rows = (1u64 << self.height) - 1;
+        let (filled, wrong) = self
+            .board
+            .cols
+            .iter()
+            .zip(&board.cols)
+            .map(|(&pattern, &actual)| {
+                let missing = pattern & !actual;
+                let extra = actual & !pattern & rows;
+                (pattern.count_ones(), (missing | extra).count_ones())
+            })
+            .fold((0, 0), |(f, w), (cf, cw)| (f + cf, w + cw));
+        filled as i32 - wrong as i32
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Stack with a tetris well and build T-slots.
+    #[default]
+    Well,
+    /// Keep the whole stack flat, for when wells are not worth maintaining (e.g. cheese).
+    Flat,
 }
 
 fn evaluate(
@@ -126,6 +446,7 @@ fn evaluate(
     mut state: GameState,
     info: &PlacementInfo,
     softdrop: u32,
+    parent: &GameState,
 ) -> (Eval, Reward) {
     let mut eval = 0.0;
     let mut reward = 0.0;
@@ -137,13 +458,32 @@ fn evaluate(
     if !info.perfect_clear || !weights.perfect_clear_override {
         if info.back_to_back {
             reward += weights.back_to_back_clear;
+            // b2b counts the hard clear which started the chain, which wasn't back-to-back
+            let chain_index = info.b2b_chain as usize - 2;
+            if let Some(&bonus) = weights
+                .back_to_back_chain
+                .get(chain_index)
+                .or_else(|| weights.back_to_back_chain.last())
+            {
+                reward += bonus;
+            }
         }
         match info.placement.spin {
+            // an immobile I piece is a spin under all-spin, but a tetris is still a tetris
+            _ if info.lines_cleared == 4 => reward += weights.normal_clears[4],
             Spin::None => reward += weights.normal_clears[info.lines_cleared as usize],
             Spin::Mini => reward += weights.mini_spin_clears[info.lines_cleared as usize],
             Spin::Full => reward += weights.spin_clears[info.lines_cleared as usize],
         }
         reward += weights.combo_attack * (info.combo.saturating_sub(1) / 2) as f32;
+        reward += weights.attack * info.attack as f32;
+    }
+    reward += weights.garbage_clear * info.garbage_cleared as f32;
+    if parent.combo > 0 {
+        match info.lines_cleared {
+            0 => reward += weights.combo_break,
+            _ => reward += weights.combo_continue,
+        }
     }
 
     // checklist
@@ -152,15 +492,29 @@ fn evaluate(
     {
         reward += weights.wasted_t;
     }
-    if state.back_to_back {
+    if state.b2b > 0 {
         eval += weights.has_back_to_back;
     }
+    if info.lines_cleared > 0 && state.b2b == 0 {
+        reward += weights.back_to_back_break * parent.b2b as f32;
+    }
+    if weights.reserved_piece == Some(state.reserve) {
+        eval += weights.reserved_piece_value;
+    }
     reward += weights.softdrop * softdrop as f32;
+    if info.hold {
+        reward += weights.hold_swap;
+    }
 
     // cutouts
-    let cutout_count = state.bag.contains(Piece::T) as usize
-        + (state.reserve == Piece::T) as usize
-        + (state.bag.len() <= 3) as usize;
+    let cutout_count = match weights.strategy {
+        Strategy::Well => {
+            state.bag.contains(Piece::T) as usize
+                + (state.reserve == Piece::T) as usize
+                + (state.bag.len() <= 3) as usize
+        }
+        Strategy::Flat => 0,
+    };
     for _ in 0..cutout_count {
         let location =
             well_known_tslot_left(&state.board).or_else(|| well_known_tslot_right(&state.board));
@@ -173,7 +527,7 @@ fn evaluate(
         eval += weights.tslot[board.line_clears().count_ones() as usize];
         if board.line_clears().count_ones() > 1 {
             board.remove_lines(board.line_clears());
-            state.board = board;
+            state.set_board(board);
         }
     }
 
@@ -183,8 +537,8 @@ fn evaluate(
             .board
             .cols
             .iter()
-            .map(|&c| {
-                let height = 64 - c.leading_zeros();
+            .zip(state.heights())
+            .map(|(&c, height)| {
                 let underneath = (1 << height) - 1;
                 let holes = !c & underneath;
                 holes.count_ones()
@@ -193,8 +547,8 @@ fn evaluate(
 
     // cell coveredness
     let mut coveredness = 0;
-    for &c in &state.board.cols {
-        let height = 64 - c.leading_zeros();
+    for (&c, height) in state.board.cols.iter().zip(state.heights()) {
+        let height = height as u32;
         let underneath = (1 << height) - 1;
         let mut holes = !c & underneath;
         while holes != 0 {
@@ -206,33 +560,82 @@ fn evaluate(
     eval += weights.cell_coveredness * coveredness as f32;
 
     // tetris well depth
-    let (tetris_well_column, tetris_well_height) = state
-        .board
-        .cols
-        .iter()
-        .enumerate()
-        .map(|(i, &c)| (i, 64 - c.leading_zeros()))
-        .min_by_key(|&(_, h)| h)
-        .unwrap();
-    let full_lines_except_well = state
-        .board
-        .cols
+    let mut tetris_well_column = None;
+    if weights.strategy == Strategy::Well {
+        let well_column = weights.well_column.map(|x| x as usize);
+        let (column, tetris_well_height) = match well_column {
+            Some(x) => (x, state.heights()[x] as u32),
+            None => state
+                .heights()
+                .iter()
+                .enumerate()
+                .map(|(i, &h)| (i, h as u32))
+                .min_by_key(|&(_, h)| h)
+                .unwrap(),
+        };
+        let full_lines_except_well = state
+            .board
+            .cols
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != column)
+            .map(|(_, &c)| c)
+            .fold(!0, |a, b| a & b);
+        let tetris_well_depth = (full_lines_except_well >> tetris_well_height).trailing_ones();
+        eval += tetris_well_depth as f32 * weights.tetris_well_depth;
+        if info.placement.location.piece == Piece::I
+            && info.lines_cleared < 4
+            && tetris_well_depth >= 4
+        {
+            reward += weights.wasted_i;
+        }
+        tetris_well_column = Some(column);
+
+        let well = state.board.cols[column];
+        eval += weights.covered_well * (well >> well.trailing_ones()).count_ones() as f32;
+        if well_column.is_some() {
+            eval += weights.filled_well * well.count_ones() as f32;
+        }
+    }
+
+    // height variance, excluding the well if there is one
+    let heights = state.heights();
+    if weights.height_variance != 0.0 {
+        let stack_heights = heights
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != tetris_well_column)
+            .map(|(_, &h)| h as f32);
+        let columns = stack_heights.clone().count() as f32;
+        let mean = stack_heights.clone().sum::<f32>() / columns;
+        let variance = stack_heights.map(|h| (h - mean) * (h - mean)).sum::<f32>() / columns;
+        eval += weights.height_variance * variance;
+    }
+
+    // bumpiness, excluding the well if there is one
+    let (_, bumpiness, bumpiness_sq) = heights
         .iter()
         .enumerate()
-        .filter(|&(i, _)| i != tetris_well_column)
-        .map(|(_, &c)| c)
-        .fold(!0, |a, b| a & b);
-    let tetris_well_depth = (full_lines_except_well >> tetris_well_height).trailing_ones();
-    eval += tetris_well_depth as f32 * weights.tetris_well_depth;
+        .filter(|&(i, _)| Some(i) != tetris_well_column)
+        .map(|(_, &h)| h as i32)
+        .fold((None, 0, 0), |(prev, b, sq), h| match prev {
+            Some(p) => {
+                let d: i32 = h - p;
+                (Some(h), b + d.abs(), sq + d * d)
+            }
+            None => (Some(h), b, sq),
+        });
+    eval += weights.bumpiness * bumpiness as f32;
+    eval += weights.bumpiness_sq * bumpiness_sq as f32;
+
+    eval += weights.parity * parity(&state.board) as f32;
+
+    if let Some(pattern) = &weights.pattern {
+        eval += pattern.weight * pattern.progress(&state.board) as f32;
+    }
 
     // height
-    let highest_point = state
-        .board
-        .cols
-        .iter()
-        .map(|&c| 64 - c.leading_zeros())
-        .max()
-        .unwrap();
+    let highest_point = *state.heights().iter().max().unwrap() as u32;
     eval += weights.height * highest_point as f32;
     if highest_point > 10 {
         eval += weights.height_upper_half * (highest_point - 10) as f32;
@@ -240,6 +643,17 @@ fn evaluate(
     if highest_point > 15 {
         eval += weights.height_upper_quarter * (highest_point - 15) as f32;
     }
+    if let Some(threshold) = weights.danger_threshold.filter(|&t| highest_point > t) {
+        // the stack can reach well past the spawn row, so cap the exponent to keep this finite
+        let exponent = (highest_point - threshold).min(MAX_DANGER_EXPONENT);
+        let danger = weights
+            .height_danger_exp
+            .powi(exponent as i32)
+            .min(MAX_DANGER);
+        eval += (weights.height_danger * danger).clamp(-MAX_DANGER, MAX_DANGER);
+    }
+    let clearance = (SPAWN_Y as u32).saturating_sub(highest_point);
+    eval += weights.spawn_clearance / (clearance + 1) as f32;
 
     // row transitions
     let mut row_transitions = 0;
@@ -308,31 +722,52 @@ fn well_known_tslot_right(board: &Board) -> Option<PieceLocation> {
     None
 }
 
+/// How many more filled cells there are on one color of a checkerboard than the other.
+fn parity(board: &Board) -> u32 {
+    const EVEN: u64 = 0x5555_5555_5555_5555;
+    let (even, odd) = board
+        .cols
+        .iter()
+        .enumerate()
+        .map(|(x, &c)| match x % 2 {
+            0 => (c & EVEN, c & !EVEN),
+            _ => (c & !EVEN, c & EVEN),
+        })
+        .fold((0, 0), |(e, o), (ce, co)| {
+            (e + ce.count_ones(), o + co.count_ones())
+        });
+    even.abs_diff(odd)
+}
+
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
-struct Eval {
+pub(super) struct Eval {
     value: OrderedFloat<f32>,
 }
 
 #[derive(Copy, Clone, Debug)]
-struct Reward {
+pub(super) struct Reward {
     value: OrderedFloat<f32>,
 }
 
 impl Evaluation for Eval {
     type Reward = Reward;
 
-    fn average(of: impl Iterator<Item = Option<Self>>) -> Self {
-        let mut count = 0;
+    fn average(of: impl Iterator<Item = (f32, Option<Self>)>) -> Self {
+        let mut total = 0.0;
         let sum: f32 = of
-            .map(|v| {
-                count += 1;
-                v.map(|e| e.value.0).unwrap_or(-1000.0)
+            .map(|(weight, v)| {
+                total += weight;
+                weight * v.map(|e| e.value.0).unwrap_or(-1000.0)
             })
             .sum();
         Eval {
-            value: (sum / count as f32).into(),
+            value: (sum / total).into(),
         }
     }
+
+    fn value(&self) -> f64 {
+        self.value.0 as f64
+    }
 }
 
 impl Add<Reward> for Eval {
@@ -344,3 +779,639 @@ impl Add<Reward> for Eval {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::bot::BotConfig;
+
+    /// The default freestyle weights with every number set to 0, so tests can turn on only the
+    /// terms they look at.
+    fn zero_weights() -> Weights {
+        fn zero(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::Number(n) => *n = 0.into(),
+                serde_json::Value::Array(values) => values.iter_mut().for_each(zero),
+                serde_json::Value::Object(fields) => fields.values_mut().for_each(zero),
+                _ => {}
+            }
+        }
+        let mut json = serde_json::to_value(&BotConfig::default().freestyle_weights).unwrap();
+        zero(&mut json);
+        serde_json::from_value(json).unwrap()
+    }
+
+    /// The evaluation plus reward of playing `mv` from `parent`, without holding.
+    fn score(weights: &Weights, parent: &GameState, mv: Placement) -> f32 {
+        let mut state = *parent;
+        let info = state.advance_with(mv.location.piece, mv, &AttackTable::default());
+        let (eval, reward) = evaluate(weights, state, &info, 0, parent);
+        (eval + reward).value.0
+    }
+
+    fn placement(piece: Piece, rotation: Rotation, x: i8, y: i8, spin: Spin) -> Placement {
+        Placement {
+            location: PieceLocation {
+                piece,
+                rotation,
+                x,
+                y,
+            },
+            spin,
+        }
+    }
+
+    /// Two 7-bags, the first five pieces of which are known from the start.
+    const SEQUENCE: [Piece; 14] = {
+        use Piece::*;
+        [T, I, O, L, J, S, Z, Z, L, O, I, J, T, S]
+    };
+
+    #[test]
+    fn search_keeps_tree_consistent() {
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let mut dag = Dag::new(root, &SEQUENCE[..5], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for &piece in &SEQUENCE[5..] {
+            for i in 0..300 {
+                do_work(
+                    &dag,
+                    &options,
+                    &config.freestyle_weights,
+                    config.exploitation(),
+                    &mut rng,
+                );
+                if i % 50 == 0 {
+                    dag.check_invariants();
+                }
+            }
+            dag.check_invariants();
+            dag.add_piece(piece);
+            dag.check_invariants();
+            let mv = dag.suggest()[0];
+            dag.advance(mv);
+            dag.check_invariants();
+        }
+    }
+
+    #[test]
+    fn classic_search_weighs_repeats_less() {
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let mut root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        root.memoryless = true;
+        root.last_drawn = Some(Piece::I);
+        let mut dag = Dag::new(root, &SEQUENCE[..2], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for &piece in &SEQUENCE[2..6] {
+            for _ in 0..300 {
+                do_work(
+                    &dag,
+                    &options,
+                    &config.freestyle_weights,
+                    config.exploitation(),
+                    &mut rng,
+                );
+            }
+            // speculated evaluations are the averages weighted by the reroll
+            dag.check_invariants();
+            dag.add_piece(piece);
+            let mv = dag.suggest()[0];
+            dag.advance(mv);
+            dag.check_invariants();
+        }
+
+        let eval = |value: f32| {
+            Some(Eval {
+                value: value.into(),
+            })
+        };
+        let average = Eval::average([(2.0, eval(-10.0)), (9.0, eval(1.0))].into_iter());
+        assert_eq!(average.value.0, -1.0);
+    }
+
+    #[test]
+    fn new_piece_updates_evaluations_above_it() {
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let mut dag = Dag::new(root, &SEQUENCE[..2], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..300 {
+            do_work(
+                &dag,
+                &options,
+                &config.freestyle_weights,
+                config.exploitation(),
+                &mut rng,
+            );
+        }
+        let before = dag.root_moves();
+
+        // the layer after the known queue was speculated; once the piece is known its nodes only
+        // keep the children for that piece, so their evaluations and those above them change
+        dag.add_piece(SEQUENCE[2]);
+        dag.check_invariants();
+        assert_ne!(
+            before.iter().map(|&(_, eval, _)| eval).collect::<Vec<_>>(),
+            dag.root_moves()
+                .iter()
+                .map(|&(_, eval, _)| eval)
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn selection_visits_better_moves_more() {
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let dag = Dag::new(root, &SEQUENCE[..5], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..2000 {
+            do_work(
+                &dag,
+                &options,
+                &config.freestyle_weights,
+                config.exploitation(),
+                &mut rng,
+            );
+        }
+
+        // root moves come best first
+        let moves = dag.root_moves();
+        let visits: Vec<u32> = moves.iter().map(|&(_, _, visits)| visits).collect();
+        let third = moves.len() / 3;
+        let best: u32 = visits[..third].iter().sum();
+        let worst: u32 = visits[moves.len() - third..].iter().sum();
+        assert!(best > 2 * worst, "best {} worst {}", best, worst);
+        assert!(visits.iter().all(|&v| v > 0));
+        assert_eq!(visits.iter().max(), visits.first());
+    }
+
+    #[test]
+    fn parallel_search_expands_each_node_once() {
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let dag = Dag::new(root, &SEQUENCE[..5], false);
+        let expansions: u64 = std::thread::scope(|s| {
+            let workers: Vec<_> = (0..4)
+                .map(|seed| {
+                    let (dag, options, config) = (&dag, &options, &config);
+                    s.spawn(move || {
+                        let mut rng = SmallRng::seed_from_u64(seed);
+                        (0..500)
+                            .map(|_| {
+                                do_work(
+                                    dag,
+                                    options,
+                                    &config.freestyle_weights,
+                                    config.exploitation(),
+                                    &mut rng,
+                                )
+                                .expansions
+                            })
+                            .sum::<u64>()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).sum()
+        });
+        dag.check_invariants();
+        assert_eq!(dag.expanded_nodes() as u64, expansions);
+    }
+
+    #[test]
+    fn panic_during_expansion_releases_the_node() {
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let dag = Dag::<Eval>::new(root, &SEQUENCE[..5], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _selection = dag.select(true, 1.0, &mut rng).unwrap();
+            panic!("injected");
+        }));
+        assert!(result.is_err());
+        // the root was left unexpanded, so it is selected again rather than skipped as busy
+        let selection = dag.select(true, 1.0, &mut rng).unwrap();
+        assert_eq!(selection.state().0, root);
+    }
+
+    #[test]
+    fn tiebreak_orders_equally_evaluated_moves() {
+        /// Lines cleared by the move suggested after expanding only the root, where every move
+        /// evaluates the same.
+        fn first_choice_clears(tiebreak: Tiebreak) -> u32 {
+            let config = Arc::new(BotConfig {
+                tiebreak,
+                ..BotConfig::default()
+            });
+            let options = BotOptions {
+                speculate: true,
+                config,
+            };
+            let root = GameState::new(
+                Board::from_ascii("####..####"),
+                Piece::O,
+                EnumSet::all(),
+                0,
+                0,
+            );
+            let dag = Dag::new(root, &[Piece::O], false);
+            let mut rng = SmallRng::seed_from_u64(0);
+            do_work(&dag, &options, &zero_weights(), 1.0, &mut rng);
+            let mut state = root;
+            state.advance(Piece::O, dag.suggest()[0]).lines_cleared
+        }
+
+        assert_eq!(first_choice_clears(Tiebreak::ClearNow), 1);
+        assert_eq!(first_choice_clears(Tiebreak::Setup), 0);
+    }
+
+    #[test]
+    fn long_back_to_back_chain_outweighs_better_clear() {
+        let mut weights = zero_weights();
+        weights.normal_clears[1] = 1.2;
+        weights.spin_clears[1] = 1.0;
+        weights.back_to_back_chain = vec![0.0, 0.0, 0.0, 0.0, 0.5];
+
+        // the same T either spins into the slot, keeping the chain going, or drops in plainly
+        let board = Board::from_ascii("###...####");
+        let spin = placement(Piece::T, Rotation::North, 4, 0, Spin::Full);
+        let plain = placement(Piece::T, Rotation::North, 4, 0, Spin::None);
+
+        // the spin clear would be the 6th in a row
+        let long_chain = GameState::new(board, Piece::T, EnumSet::all(), 5, 0);
+        assert!(score(&weights, &long_chain, spin) > score(&weights, &long_chain, plain));
+
+        // but with a short chain the normal clear scores better
+        let short_chain = GameState::new(board, Piece::T, EnumSet::all(), 1, 0);
+        assert!(score(&weights, &short_chain, spin) < score(&weights, &short_chain, plain));
+    }
+
+    #[test]
+    fn breaking_back_to_back_costs_the_chain() {
+        let mut weights = zero_weights();
+        weights.back_to_back_break = -1.0;
+        weights.normal_clears[1] = 1.0;
+        let board = Board::from_ascii("########..");
+        let single = placement(Piece::O, Rotation::North, 8, 0, Spin::None);
+        let stack = placement(Piece::O, Rotation::North, 0, 1, Spin::None);
+
+        // a single is worth taking, unless it throws away a chain of 3
+        let no_chain = GameState::new(board, Piece::O, EnumSet::all(), 0, 0);
+        assert_eq!(score(&weights, &no_chain, single), 1.0);
+        assert_eq!(score(&weights, &no_chain, stack), 0.0);
+        let chain = GameState::new(board, Piece::O, EnumSet::all(), 3, 0);
+        assert_eq!(score(&weights, &chain, single), -2.0);
+        assert_eq!(score(&weights, &chain, stack), 0.0);
+
+        // a tetris keeps the chain going
+        let well = Board::from_ascii(&"#########.\n".repeat(5));
+        let chain = GameState::new(well, Piece::I, EnumSet::all(), 3, 0);
+        let tetris = placement(Piece::I, Rotation::West, 9, 1, Spin::None);
+        assert_eq!(score(&weights, &chain, tetris), 0.0);
+    }
+
+    #[test]
+    fn parity_counts_checkerboard_imbalance() {
+        assert_eq!(parity(&Board::default()), 0);
+        assert_eq!(parity(&Board::from_ascii("##........")), 0);
+        assert_eq!(parity(&Board::from_ascii("#.#.......")), 2);
+        // a T always covers three cells of one color and one of the other
+        assert_eq!(parity(&Board::from_ascii("....#.....\n...###....")), 2);
+        // an O covers two of each
+        assert_eq!(parity(&Board::from_ascii("......##..\n......##..")), 0);
+        // a full row is balanced, and so is any stack of them
+        assert_eq!(parity(&Board::from_ascii("##########\n##########")), 0);
+        assert_eq!(
+            parity(&Board::from_ascii("#.........\n#.........\n#.........")),
+            1
+        );
+    }
+
+    #[test]
+    fn covered_well_counts_cells_over_the_gap() {
+        let mut weights = zero_weights();
+        weights.covered_well = -1.0;
+        // the holes in column 0 keep the S below from clearing lines
+        let board = Board::from_ascii(
+            "########..\n########..\n########..\n.#######..\n.########.\n#########.",
+        );
+        let root = GameState::new(board, Piece::S, EnumSet::all(), 0, 0);
+
+        // an S hanging off column 8 leaves two cells in the well with an open row underneath
+        let hanging = placement(Piece::S, Rotation::East, 8, 2, Spin::None);
+        assert_eq!(score(&weights, &root, hanging), -2.0);
+
+        // an I filling the well from the bottom covers nothing
+        let filling = placement(Piece::I, Rotation::West, 9, 1, Spin::None);
+        assert_eq!(score(&weights, &root, filling), 0.0);
+
+        // pinning the well doesn't change what counts as covered
+        weights.well_column = Some(9);
+        assert_eq!(score(&weights, &root, hanging), -2.0);
+        assert_eq!(score(&weights, &root, filling), 0.0);
+    }
+
+    #[test]
+    fn filled_well_counts_every_cell_in_a_pinned_well() {
+        let mut weights = zero_weights();
+        weights.filled_well = -1.0;
+        let board = Board::from_ascii(
+            "########..\n########..\n########..\n.#######..\n.########.\n#########.",
+        );
+        let root = GameState::new(board, Piece::S, EnumSet::all(), 0, 0);
+        let hanging = placement(Piece::S, Rotation::East, 8, 2, Spin::None);
+        let filling = placement(Piece::I, Rotation::West, 9, 1, Spin::None);
+
+        // only applies to a pinned well
+        assert_eq!(score(&weights, &root, filling), 0.0);
+
+        weights.well_column = Some(9);
+        assert_eq!(score(&weights, &root, hanging), -2.0);
+        // the bottom row clears, leaving three of the I's cells in the well
+        assert_eq!(score(&weights, &root, filling), -3.0);
+    }
+
+    #[test]
+    fn combo_terms_only_apply_during_a_combo() {
+        let mut weights = zero_weights();
+        weights.combo_continue = 1.0;
+        weights.combo_break = -2.0;
+        let board = Board::from_ascii("####..####");
+        let clear = placement(Piece::O, Rotation::North, 4, 0, Spin::None);
+        let stack = placement(Piece::O, Rotation::North, 0, 1, Spin::None);
+
+        let combo = GameState::new(board, Piece::O, EnumSet::all(), 0, 2);
+        assert_eq!(score(&weights, &combo, clear), 1.0);
+        assert_eq!(score(&weights, &combo, stack), -2.0);
+
+        let no_combo = GameState::new(board, Piece::O, EnumSet::all(), 0, 0);
+        assert_eq!(score(&weights, &no_combo, clear), 0.0);
+        assert_eq!(score(&weights, &no_combo, stack), 0.0);
+    }
+
+    #[test]
+    fn wasted_i_applies_beside_a_tetris_ready_well() {
+        let mut weights = zero_weights();
+        weights.wasted_i = -1.0;
+        weights.well_column = Some(9);
+        // a well 4 deep, with only an I in hand
+        let board = Board::from_ascii(&"#########.\n".repeat(4));
+        let root = GameState::new(board, Piece::I, EnumSet::all(), 0, 0);
+        let tetris = placement(Piece::I, Rotation::West, 9, 1, Spin::None);
+        let info = root
+            .clone()
+            .advance_with(Piece::I, tetris, &AttackTable::default());
+        assert_eq!(info.lines_cleared, 4);
+        assert_eq!(score(&weights, &root, tetris), 0.0);
+        let on_top = placement(Piece::I, Rotation::North, 1, 4, Spin::None);
+        assert_eq!(score(&weights, &root, on_top), -1.0);
+
+        // a well too shallow for a tetris isn't worth saving the I for
+        let board = Board::from_ascii(&"#########.\n".repeat(3));
+        let shallow = GameState::new(board, Piece::I, EnumSet::all(), 0, 0);
+        let on_top = placement(Piece::I, Rotation::North, 1, 3, Spin::None);
+        assert_eq!(score(&weights, &shallow, on_top), 0.0);
+    }
+
+    #[test]
+    fn reserved_piece_is_kept_in_hold() {
+        let mut weights = zero_weights();
+        weights.reserved_piece = Some(Piece::I);
+        weights.reserved_piece_value = 2.0;
+        // an I in hand on a flat board, with an O coming
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        let score_next = |weights: &Weights, mv: Placement| {
+            let mut state = root;
+            let info = state.advance_with(Piece::O, mv, &AttackTable::default());
+            let (eval, reward) = evaluate(weights, state, &info, 0, &root);
+            (eval + reward).value.0
+        };
+        let keep = placement(Piece::O, Rotation::North, 4, 0, Spin::None);
+        let spend = placement(Piece::I, Rotation::North, 4, 0, Spin::None);
+        assert_eq!(score_next(&weights, keep), 2.0);
+        assert_eq!(score_next(&weights, spend), 0.0);
+
+        // on top of the default weights, search plays around the I
+        let mut config = BotConfig::default();
+        config.freestyle_weights.reserved_piece = Some(Piece::I);
+        config.freestyle_weights.reserved_piece_value = 5.0;
+        let config = Arc::new(config);
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let dag = Dag::new(root, &[Piece::O, Piece::T, Piece::L], false);
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..1000 {
+            do_work(
+                &dag,
+                &options,
+                &config.freestyle_weights,
+                config.exploitation(),
+                &mut rng,
+            );
+        }
+        assert_ne!(dag.suggest()[0].location.piece, Piece::I);
+    }
+
+    #[test]
+    fn spawn_clearance_grows_near_the_spawn_row() {
+        let mut weights = zero_weights();
+        weights.spawn_clearance = -1.0;
+        let board = Board::from_ascii(&"#.#.#.#.#.\n".repeat(14));
+        let root = GameState::new(board, Piece::I, EnumSet::all(), 0, 0);
+
+        // three empty rows below the spawn row
+        let low = placement(Piece::I, Rotation::North, 3, 15, Spin::None);
+        assert_eq!(score(&weights, &root, low), -0.25);
+        // one empty row
+        let high = placement(Piece::I, Rotation::East, 0, 16, Spin::None);
+        assert_eq!(score(&weights, &root, high), -0.5);
+        // reaching past the spawn row costs no more than reaching it
+        let over = placement(Piece::I, Rotation::East, 0, 18, Spin::None);
+        assert_eq!(score(&weights, &root, over), -1.0);
+    }
+
+    #[test]
+    fn height_danger_grows_past_the_threshold() {
+        let mut weights = zero_weights();
+        weights.height_danger = -1.0;
+        weights.height_danger_exp = 2.0;
+        let root = GameState::new(Board::default(), Piece::I, EnumSet::all(), 0, 0);
+        // a standing I makes the stack 4 tall
+        let mv = placement(Piece::I, Rotation::West, 0, 1, Spin::None);
+        assert_eq!(score(&weights, &root, mv), 0.0);
+
+        weights.danger_threshold = Some(2);
+        assert_eq!(score(&weights, &root, mv), -4.0);
+        weights.danger_threshold = Some(4);
+        assert_eq!(score(&weights, &root, mv), 0.0);
+
+        // a huge base is clamped rather than overflowing
+        weights.danger_threshold = Some(0);
+        weights.height_danger_exp = 1e30;
+        assert_eq!(score(&weights, &root, mv), -MAX_DANGER);
+        weights.height_danger = 0.0;
+        assert_eq!(score(&weights, &root, mv), 0.0);
+    }
+
+    #[test]
+    fn height_danger_base_below_one_or_infinite_is_rejected() {
+        let mut weights = BotConfig::default().freestyle_weights;
+        assert!(weights.validate().is_ok());
+        weights.height_danger_exp = 0.5;
+        assert!(weights.validate().is_err());
+        for exp in [f32::INFINITY, f32::NAN] {
+            weights.height_danger_exp = exp;
+            let e = weights.validate().unwrap_err();
+            assert!(e.ends_with("must be finite and at least 1"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn height_variance_penalizes_a_stepped_stack() {
+        let mut weights = zero_weights();
+        weights.height_variance = -1.0;
+        weights.well_column = Some(9);
+        // the O brings the first two columns up to the rest, leaving heights 2 everywhere
+        let flat = Board::from_ascii("..#######.\n..#######.");
+        // here it leaves heights 2 2 1 1 2 2 3 3 4, never more than 1 apart
+        let stepped = Board::from_ascii("........#.\n......###.\n....#####.\n..#######.");
+        let o = placement(Piece::O, Rotation::North, 0, 0, Spin::None);
+        let score_on = |board| {
+            let root = GameState::new(board, Piece::O, EnumSet::all(), 0, 0);
+            score(&weights, &root, o)
+        };
+        assert_eq!(score_on(flat), 0.0);
+        assert!(score_on(stepped) < -0.5);
+    }
+
+    #[test]
+    fn bumpiness_skips_the_well() {
+        let root = GameState::new(Board::default(), Piece::L, EnumSet::all(), 0, 0);
+        // column heights 0 0 0 0 0 0 1 1 2 0
+        let mv = placement(Piece::L, Rotation::North, 7, 0, Spin::None);
+        let mut weights = zero_weights();
+        weights.strategy = Strategy::Flat;
+        weights.bumpiness = -1.0;
+        assert_eq!(score(&weights, &root, mv), -4.0);
+        weights.bumpiness = 0.0;
+        weights.bumpiness_sq = -1.0;
+        assert_eq!(score(&weights, &root, mv), -6.0);
+
+        // the step down into the well isn't counted
+        weights.strategy = Strategy::Well;
+        weights.well_column = Some(9);
+        assert_eq!(score(&weights, &root, mv), -2.0);
+        weights.bumpiness = -1.0;
+        weights.bumpiness_sq = 0.0;
+        assert_eq!(score(&weights, &root, mv), -2.0);
+    }
+
+    #[test]
+    fn pattern_progress_counts_matching_and_stray_cells() {
+        let pattern: Pattern = serde_json::from_value(serde_json::json!({
+            "rows": ["##........", "###......."],
+            "weight": 1.0,
+        }))
+        .unwrap();
+        assert_eq!(pattern.progress(&Board::default()), 0);
+        assert_eq!(pattern.progress(&Board::from_ascii("###.......")), 3);
+        assert_eq!(
+            pattern.progress(&Board::from_ascii("##........\n###.......")),
+            5
+        );
+        // a stray cell in the pattern's rows costs as much as a cell of the pattern earns
+        assert_eq!(
+            pattern.progress(&Board::from_ascii("##.......#\n###.......")),
+            4
+        );
+        // but above the pattern it doesn't matter
+        assert_eq!(
+            pattern.progress(&Board::from_ascii("#.........\n##........\n###.......")),
+            5
+        );
+    }
+
+    #[test]
+    fn weights_with_only_the_original_fields_load() {
+        let weights: Weights = serde_json::from_value(serde_json::json!({
+            "cell_coveredness": -0.2,
+            "max_cell_covered_height": 6,
+            "holes": -1.5,
+            "row_transitions": -0.2,
+            "height": -0.4,
+            "height_upper_half": -1.5,
+            "height_upper_quarter": -5.0,
+            "tetris_well_depth": 0.3,
+            "tslot": [0.1, 1.5, 2.0, 4.0],
+            "has_back_to_back": 0.5,
+            "wasted_t": -1.5,
+            "softdrop": -0.2,
+            "normal_clears": [0.0, -2.0, -1.5, -1.0, 3.5],
+            "mini_spin_clears": [0.0, -1.5, -1.0],
+            "spin_clears": [0.0, 1.0, 4.0, 6.0],
+            "back_to_back_clear": 1.0,
+            "combo_attack": 1.5,
+            "perfect_clear": 15.0,
+            "perfect_clear_override": true,
+        }))
+        .unwrap();
+        assert!(weights.validate().is_ok());
+        assert_eq!(weights.height_danger, 0.0);
+        assert_eq!(weights.height_danger_exp, 2.0);
+        assert_eq!(weights.danger_threshold, None);
+    }
+
+    #[test]
+    fn missing_weights_default_to_zero() {
+        let mut json = serde_json::to_value(&BotConfig::default().freestyle_weights).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        fields.remove("combo_continue");
+        fields.remove("combo_break");
+        fields.remove("spawn_clearance");
+        fields.remove("garbage_clear");
+        fields.remove("pattern");
+        fields.remove("attack");
+        fields.remove("bumpiness");
+        fields.remove("bumpiness_sq");
+        fields.remove("well_column");
+        fields.remove("filled_well");
+        let weights: Weights = serde_json::from_value(json).unwrap();
+        assert_eq!(weights.combo_continue, 0.0);
+        assert_eq!(weights.combo_break, 0.0);
+        assert_eq!(weights.spawn_clearance, 0.0);
+        assert_eq!(weights.garbage_clear, 0.0);
+        assert!(weights.pattern.is_none());
+        assert_eq!(weights.attack, 0.0);
+        assert_eq!(weights.bumpiness, 0.0);
+        assert_eq!(weights.bumpiness_sq, 0.0);
+        assert_eq!(weights.well_column, None);
+        assert_eq!(weights.filled_well, 0.0);
+    }
+}
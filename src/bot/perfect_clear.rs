@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{Duration, Instant};
+
+use ahash::AHashSet;
+use rand::RngCore;
+
+use super::freestyle::Freestyle;
+use super::{BotOptions, Mode, ModeSwitch, Statistics};
+use crate::data::*;
+use crate::movegen::{find_perfect_clear_moves, MoveRules, Scratch};
+
+/// The solver only looks for perfect clears on boards at most this tall.
+const MAX_HEIGHT: u32 = 4;
+
+/// How many positions the solver may visit before giving up, so that a queue with no perfect
+/// clear doesn't hold up the game.
+const NODE_BUDGET: u32 = 200_000;
+
+/// How long the solver may run before giving up. It runs while the bot is locked, so this bounds
+/// how long playing a move can hold up suggestions. There are no clocks on `wasm32`, where only
+/// the node budget applies.
+#[cfg(not(target_arch = "wasm32"))]
+const TIME_BUDGET: Duration = Duration::from_millis(50);
+
+/// The clock is only read this often, since the budget needn't be exact.
+#[cfg(not(target_arch = "wasm32"))]
+const NODES_PER_CLOCK_CHECK: u32 = 64;
+
+/// Plays out a perfect clear found by exhaustive search of the known queue, then hands back to
+/// freestyle. Freestyle searches in the background meanwhile, in case the frontend plays
+/// something else.
+pub struct PerfectClear {
+    solution: VecDeque<Placement>,
+    /// The moves of the solution played so far, for rewinding.
+    played: Vec<Placement>,
+    /// Only taken when switching to freestyle.
+    search: Option<Box<Freestyle>>,
+}
+
+impl PerfectClear {
+    pub fn new(
+        options: &BotOptions,
+        root: GameState,
+        queue: &[Piece],
+        solution: Vec<Placement>,
+    ) -> Self {
+        PerfectClear {
+            solution: solution.into(),
+            played: vec![],
+            search: Some(Box::new(Freestyle::new(options, root, queue))),
+        }
+    }
+
+    /// The search running in the background, to keep playing with after the perfect clear.
+    pub fn take_search(&mut self) -> Freestyle {
+        *self.search.take().expect("search already taken")
+    }
+
+    fn search(&self) -> &Freestyle {
+        self.search.as_ref().expect("search already taken")
+    }
+
+    fn search_mut(&mut self) -> &mut Freestyle {
+        self.search.as_mut().expect("search already taken")
+    }
+}
+
+/// Looks for a sequence of moves which perfect clears using only the pieces of `queue`,
+/// holding as needed. Gives up if the board is too tall, the queue is too short, or no
+/// solution turns up within the node or time budget.
+pub fn solve(rules: &MoveRules, root: &GameState, queue: &[Piece]) -> Option<Vec<Placement>> {
+    profile_function!();
+    let height = root.heights().iter().copied().max().unwrap_or(0) as u32;
+    if height > MAX_HEIGHT || !root.board.perfect_clear_possible(queue.len() as u32) {
+        return None;
+    }
+    let mut solver = Solver {
+        rules,
+        scratch: Scratch::default(),
+        failed: AHashSet::new(),
+        nodes: 0,
+        #[cfg(not(target_arch = "wasm32"))]
+        deadline: Instant::now() + TIME_BUDGET,
+        out_of_budget: false,
+        path: vec![],
+    };
+    solver.search(*root, queue).then_some(solver.path)
+}
+
+struct Solver<'a> {
+    rules: &'a MoveRules,
+    scratch: Scratch,
+    /// Positions already known not to lead to a perfect clear, with how many pieces were left.
+    failed: AHashSet<(GameState, usize)>,
+    nodes: u32,
+    #[cfg(not(target_arch = "wasm32"))]
+    deadline: Instant,
+    /// Once set, every search fails without being recorded in `failed`.
+    out_of_budget: bool,
+    path: Vec<Placement>,
+}
+
+impl Solver<'_> {
+    fn search(&mut self, state: GameState, queue: &[Piece]) -> bool {
+        let (&next, rest) = match queue.split_first() {
+            Some(split) => split,
+            None => return false,
+        };
+        if self.over_budget() || self.failed.contains(&(state, queue.len())) {
+            return false;
+        }
+        self.nodes += 1;
+
+        let mut pieces = vec![state.reserve];
+        if next != state.reserve {
+            pieces.push(next);
+        }
+        let mut moves = vec![];
+        for piece in pieces {
+            find_perfect_clear_moves(
+                &state.board,
+                piece,
+                rest.len() as u32,
+                self.rules,
+                &mut self.scratch,
+                &mut moves,
+            );
+            for &(mv, _) in &moves {
+                let mut child = state;
+                let info = child.advance(next, mv);
+                self.path.push(mv);
+                if info.perfect_clear || self.search(child, rest) {
+                    return true;
+                }
+                self.path.pop();
+            }
+        }
+
+        if !self.out_of_budget {
+            self.failed.insert((state, queue.len()));
+        }
+        false
+    }
+
+    fn over_budget(&mut self) -> bool {
+        self.out_of_budget |= self.nodes >= NODE_BUDGET;
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.nodes.is_multiple_of(NODES_PER_CLOCK_CHECK) {
+            self.out_of_budget |= Instant::now() >= self.deadline;
+        }
+        self.out_of_budget
+    }
+}
+
+impl Mode for PerfectClear {
+    fn advance(&mut self, options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch> {
+        profile_function!();
+        self.search_mut().advance(options, info);
+        if self.solution.front() == Some(&info.placement) {
+            self.solution.pop_front();
+            self.played.push(info.placement);
+        } else {
+            // The frontend went its own way, so the solution no longer applies
+            self.solution.clear();
+        }
+        self.solution.is_empty().then_some(ModeSwitch::Freestyle)
+    }
+
+    fn new_piece(&mut self, options: &BotOptions, piece: Piece) {
+        profile_function!();
+        self.search_mut().new_piece(options, piece);
+    }
+
+    fn suggest(&self, options: &BotOptions) -> Vec<Placement> {
+        profile_function!();
+        match self.solution.front() {
+            Some(&mv) => vec![mv],
+            None => self.search().suggest(options),
+        }
+    }
+
+    fn confidence(&self, options: &BotOptions) -> Option<f64> {
+        self.search().confidence(options)
+    }
+
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)> {
+        self.search().principal_variation(options)
+    }
+
+    fn root_moves(&self, options: &BotOptions) -> Vec<(Placement, f64, u32)> {
+        self.search().root_moves(options)
+    }
+
+    fn depth(&self, options: &BotOptions) -> u32 {
+        self.search().depth(options)
+    }
+
+    fn node_count(&self, options: &BotOptions) -> usize {
+        self.search().node_count(options)
+    }
+
+    fn tree_size(&self, options: &BotOptions) -> (usize, usize) {
+        self.search().tree_size(options)
+    }
+
+    fn evict(&mut self, options: &BotOptions, target: usize) -> usize {
+        self.search_mut().evict(options, target)
+    }
+
+    fn rewind(&mut self, options: &BotOptions, info: &PlacementInfo) {
+        self.search_mut().rewind(options, info);
+        if self.played.last() == Some(&info.placement) {
+            self.played.pop();
+            self.solution.push_front(info.placement);
+        }
+    }
+
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]) {
+        // The solution was for the board without the garbage
+        self.solution.clear();
+        self.search_mut().reset(options, root, queue);
+    }
+
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics {
+        self.search().do_work(options, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use enumset::EnumSet;
+
+    use super::*;
+    use crate::BotConfig;
+
+    #[test]
+    fn gives_up_within_the_time_budget() {
+        use Piece::*;
+        // exhausting the node budget on this queue takes far longer than the time budget
+        let queue = [I, J, L, T, O, S, Z, J, S, Z, O, L];
+        let root = GameState::new(Board::default(), queue[0], EnumSet::all(), 0, 0);
+        let rules = BotConfig::default().move_rules();
+        let start = Instant::now();
+        solve(&rules, &root, &queue[1..]);
+        assert!(start.elapsed() < TIME_BUDGET * 4, "{:?}", start.elapsed());
+    }
+}
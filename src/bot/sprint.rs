@@ -0,0 +1,89 @@
+use rand::RngCore;
+
+use super::freestyle::{self, Eval};
+use super::{BotOptions, Mode, ModeSwitch, Statistics};
+use crate::dag::Dag;
+use crate::data::*;
+
+/// Clears a fixed number of lines using as few pieces as possible, ignoring attack. Hands off to
+/// freestyle once the target has been reached.
+pub struct Sprint {
+    dag: Dag<Eval>,
+    lines_remaining: u32,
+}
+
+impl Sprint {
+    pub fn new(options: &BotOptions, root: GameState, queue: &[Piece], lines: u32) -> Self {
+        Sprint {
+            dag: Dag::new(root, queue, options.config.keep_history),
+            lines_remaining: lines,
+        }
+    }
+}
+
+impl Mode for Sprint {
+    fn advance(&mut self, _options: &BotOptions, info: &PlacementInfo) -> Option<ModeSwitch> {
+        profile_function!();
+        self.dag.advance(info.placement);
+        self.lines_remaining = self.lines_remaining.saturating_sub(info.lines_cleared);
+        (self.lines_remaining == 0).then_some(ModeSwitch::Freestyle)
+    }
+
+    fn new_piece(&mut self, _options: &BotOptions, piece: Piece) {
+        profile_function!();
+        self.dag.add_piece(piece);
+    }
+
+    fn suggest(&self, _options: &BotOptions) -> Vec<Placement> {
+        profile_function!();
+        self.dag.suggest()
+    }
+
+    fn confidence(&self, _options: &BotOptions) -> Option<f64> {
+        freestyle::confidence(&self.dag)
+    }
+
+    fn principal_variation(&self, options: &BotOptions) -> Vec<(PlacementInfo, f64)> {
+        freestyle::principal_variation(&self.dag, options)
+    }
+
+    fn root_moves(&self, _options: &BotOptions) -> Vec<(Placement, f64, u32)> {
+        freestyle::root_moves(&self.dag)
+    }
+
+    fn depth(&self, _options: &BotOptions) -> u32 {
+        self.dag.depth()
+    }
+
+    fn node_count(&self, _options: &BotOptions) -> usize {
+        self.dag.node_count()
+    }
+
+    fn tree_size(&self, _options: &BotOptions) -> (usize, usize) {
+        self.dag.tree_size()
+    }
+
+    fn evict(&mut self, _options: &BotOptions, target: usize) -> usize {
+        self.dag.evict(target)
+    }
+
+    fn rewind(&mut self, _options: &BotOptions, info: &PlacementInfo) {
+        self.dag.rewind();
+        self.lines_remaining += info.lines_cleared;
+    }
+
+    fn reset(&mut self, options: &BotOptions, root: GameState, queue: &[Piece]) {
+        self.dag = Dag::new(root, queue, options.config.keep_history);
+    }
+
+    fn do_work(&self, options: &BotOptions, rng: &mut dyn RngCore) -> Statistics {
+        profile_function!();
+        freestyle::do_work(
+            &self.dag,
+            options,
+            &options.config.sprint_weights,
+            options.config.exploitation(),
+            rng,
+        )
+    }
+}
@@ -0,0 +1,73 @@
+use std::convert::TryFrom;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use enumset::EnumSet;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Board, GameState, Piece};
+
+/// A table of previously computed evaluations, used to seed new nodes instead of evaluating them
+/// from scratch.
+///
+/// The file is JSON lines, one entry per line in the format of `Entry`. Configs refer to the
+/// table by path, which is loaded once when the config is.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "PathBuf", into = "PathBuf")]
+pub struct WarmStart {
+    path: PathBuf,
+    table: Arc<AHashMap<GameState, f32>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cols: [u64; 10],
+    bag: Vec<Piece>,
+    reserve: Piece,
+    b2b: u16,
+    combo: u8,
+    eval: f32,
+}
+
+impl WarmStart {
+    pub fn get(&self, state: &GameState) -> Option<f32> {
+        self.table.get(state).copied()
+    }
+}
+
+impl TryFrom<PathBuf> for WarmStart {
+    type Error = String;
+
+    fn try_from(path: PathBuf) -> Result<Self, String> {
+        let file = std::fs::File::open(&path)
+            .map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+        let mut table = AHashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+            let state = GameState::new(
+                Board::new(entry.cols),
+                entry.reserve,
+                entry.bag.into_iter().collect::<EnumSet<_>>(),
+                entry.b2b,
+                entry.combo,
+            );
+            table.insert(state, entry.eval);
+        }
+        Ok(WarmStart {
+            path,
+            table: Arc::new(table),
+        })
+    }
+}
+
+impl From<WarmStart> for PathBuf {
+    fn from(w: WarmStart) -> PathBuf {
+        w.path
+    }
+}
@@ -1,10 +1,13 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
 use bumpalo_herd::Herd;
 use enum_map::EnumMap;
 use once_cell::sync::Lazy;
 use ouroboros::self_referencing;
+use rand::RngCore;
 
-use crate::data::Placement;
-use crate::data::{GameState, Piece};
+use crate::data::{AttackTable, GameState, Piece};
+use crate::data::{Placement, PlacementInfo};
 
 mod known;
 mod speculated;
@@ -14,12 +17,19 @@ pub trait Evaluation:
 {
     type Reward: Copy;
 
-    fn average(of: impl Iterator<Item = Option<Self>>) -> Self;
+    /// The average of evaluations weighted by how likely each is. `None` is a dead end.
+    fn average(of: impl Iterator<Item = (f32, Option<Self>)>) -> Self;
+
+    /// The evaluation as a number, which selection weighs against how rarely a move was tried.
+    fn value(&self) -> f64;
 }
 
 pub struct Dag<E: Evaluation> {
     root: GameState,
     top_layer: Box<LayerCommon<E>>,
+    /// The roots passed through since the DAG was created, when it retains history. The current
+    /// root then lives in the layer this many steps below `top_layer`.
+    history: Option<Vec<GameState>>,
 }
 
 pub struct Selection<'a, E: Evaluation> {
@@ -38,6 +48,10 @@ pub struct ChildData<E: Evaluation> {
 struct LayerCommon<E: Evaluation> {
     next_layer: Lazy<Box<LayerCommon<E>>>,
     kind: WithBump<E>,
+    /// Whether any node in this layer has been expanded.
+    expanded: AtomicBool,
+    /// How many nodes expansion has created in this layer and eviction hasn't removed.
+    nodes: AtomicUsize,
 }
 
 #[self_referencing]
@@ -58,6 +72,8 @@ struct Child<E: Evaluation> {
     mv: Placement,
     reward: E::Reward,
     cached_eval: E,
+    /// The raw index of the node this move leads to in the next layer.
+    node: u64,
 }
 
 enum SelectResult {
@@ -74,7 +90,13 @@ struct BackpropUpdate {
 }
 
 impl<E: Evaluation> Dag<E> {
-    pub fn new(root: GameState, queue: &[Piece]) -> Self {
+    /// Creates a DAG rooted at `root` with the known `queue`.
+    ///
+    /// With `retain_history`, advancing keeps the layers above the new root instead of dropping
+    /// them so that `rewind` can return to an earlier position and explore a different move. This
+    /// is meant for analysis: nothing is ever freed, so memory grows with every move played
+    /// until the DAG is dropped.
+    pub fn new(root: GameState, queue: &[Piece], retain_history: bool) -> Self {
         let mut top_layer = LayerCommon::default();
         top_layer.kind.initialize_root(&root);
 
@@ -87,11 +109,27 @@ impl<E: Evaluation> Dag<E> {
         Dag {
             root,
             top_layer: Box::new(top_layer),
+            history: retain_history.then(Vec::new),
         }
     }
 
     pub fn advance(&mut self, mv: Placement) {
-        puffin::profile_function!();
+        profile_function!();
+        if self.history.is_some() {
+            let layer = self.current_layer();
+            let piece = layer
+                .kind
+                .piece()
+                .expect("cannot advance without next piece");
+            let mut root = self.root;
+            root.advance(piece, mv);
+            layer.next_layer.kind.initialize_root(&root);
+            let previous = std::mem::replace(&mut self.root, root);
+            if let Some(history) = &mut self.history {
+                history.push(previous);
+            }
+            return;
+        }
         let top_layer = std::mem::take(&mut *self.top_layer);
         self.root.advance(
             top_layer
@@ -105,31 +143,199 @@ impl<E: Evaluation> Dag<E> {
         self.top_layer.kind.initialize_root(&self.root);
     }
 
+    /// Returns to the root before the most recent `advance`. Does nothing and returns false if
+    /// history isn't retained or there is nothing to go back to.
+    pub fn rewind(&mut self) -> bool {
+        match self.history.as_mut().and_then(|h| h.pop()) {
+            Some(root) => {
+                self.root = root;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The layer containing the root.
+    fn current_layer(&self) -> &LayerCommon<E> {
+        let depth = self.history.as_ref().map_or(0, |h| h.len());
+        let mut layer = &*self.top_layer;
+        for _ in 0..depth {
+            layer = &layer.next_layer;
+        }
+        layer
+    }
+
     pub fn add_piece(&mut self, piece: Piece) {
-        puffin::profile_function!();
+        profile_function!();
         let mut layer = &mut self.top_layer;
-        loop {
-            if layer.kind.despeculate(piece) {
-                // TODO: backprop despeculated values
-                return;
+        let mut depth = 0;
+        let mut next = loop {
+            if let Some(updates) = layer.kind.despeculate(piece) {
+                break updates;
             }
             layer = &mut layer.next_layer;
+            depth += 1;
+        };
+
+        // The despeculated nodes now only have the children for the piece that actually came, so
+        // their evaluations changed. Propagate that to everything above them.
+        let mut layers = vec![&*self.top_layer];
+        for _ in 0..depth {
+            layers.push(&layers.last().unwrap().next_layer);
+        }
+        let mut next_layer = layers.pop().unwrap();
+        while let Some(layer) = layers.pop() {
+            if next.is_empty() {
+                break;
+            }
+            next = layer.kind.backprop(next, next_layer);
+            next_layer = layer;
         }
     }
 
+    /// Reads the best moves at the root as currently known. Never expands nodes.
     pub fn suggest(&self) -> Vec<Placement> {
-        puffin::profile_function!();
-        self.top_layer.kind.suggest(&self.root)
+        profile_function!();
+        self.current_layer().kind.suggest(&self.root)
     }
 
-    pub fn select(&self, speculate: bool, exploration: f64) -> Option<Selection<E>> {
-        puffin::profile_function!();
-        let mut layers = vec![&*self.top_layer];
+    /// The evaluations of the moves available from the root.
+    pub fn root_child_evals(&self) -> Vec<E> {
+        self.current_layer().kind.child_evals(&self.root)
+    }
+
+    /// Follows the best move from the root for as long as the upcoming pieces are known, giving
+    /// each placement along with the evaluation of the node it leads to.
+    pub fn principal_variation(&self, attack: &AttackTable) -> Vec<(PlacementInfo, E)> {
+        profile_function!();
+        let mut pv = vec![];
+        let mut layer = self.current_layer();
+        let mut state = self.root;
+        while let Some(piece) = layer.kind.piece() {
+            let mv = match layer.kind.best_child(&state) {
+                Some(mv) => mv,
+                None => break,
+            };
+            let info = state.advance_with(piece, mv, attack);
+            layer = &layer.next_layer;
+            pv.push((info, layer.kind.node_eval(&state)));
+        }
+        pv
+    }
+
+    /// How many layers below the root, counting the root's own, have had a node expanded.
+    pub fn depth(&self) -> u32 {
+        self.searched_layers().len() as u32 - 1
+    }
+
+    /// How many nodes search has created below the root and not evicted.
+    pub fn node_count(&self) -> usize {
+        self.searched_layers()
+            .iter()
+            .map(|l| l.nodes.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// `node_count` along with roughly how many bytes the maps holding those nodes take. Both come
+    /// from the same counters rather than the maps themselves, so nothing is locked.
+    pub fn tree_size(&self) -> (usize, usize) {
+        self.searched_layers()
+            .iter()
+            .map(|l| {
+                let nodes = l.nodes.load(Ordering::Relaxed);
+                (nodes, l.kind.approx_bytes(nodes))
+            })
+            .fold((0, 0), |(n, b), (ln, lb)| (n + ln, b + lb))
+    }
+
+    /// Removes unexpanded nodes until at most `target` remain, starting with the deepest layer
+    /// and, within a layer, with the worst evaluated. A node is kept if it is the best move of
+    /// any of its parents, so evaluations higher up don't change and nothing is backpropagated.
+    /// Returns how many nodes were removed.
+    ///
+    /// Only the map entries are freed. Parent and child lists live in the layer's bump arena,
+    /// which can only be freed all at once, so the evicted moves are cut out of their parents'
+    /// lists but the memory stays allocated until the layer is dropped after the game moves
+    /// past it. Expanded nodes are never evicted, as their children would be left without a
+    /// parent.
+    pub fn evict(&mut self, target: usize) -> usize {
+        profile_function!();
+        let layers = self.searched_layers();
+        let mut count: usize = layers.iter().map(|l| l.nodes.load(Ordering::Relaxed)).sum();
+        let mut removed = 0;
+        // The current layer has no parent layer to unlink from; its nodes go when it is dropped.
+        for i in (1..layers.len()).rev() {
+            if count <= target {
+                break;
+            }
+            let (layer, parent_layer) = (layers[i], layers[i - 1]);
+            let mut leaves = layer.kind.leaves();
+            leaves.sort_unstable_by_key(|&(_, eval)| eval);
+            for (index, _) in leaves {
+                if count <= target {
+                    break;
+                }
+                let parents = layer.kind.parents(index);
+                let best_somewhere = parents.is_empty()
+                    || parents.iter().any(|&(parent, mv, piece)| {
+                        parent_layer.kind.is_best_child(parent, mv, piece)
+                    });
+                if best_somewhere {
+                    continue;
+                }
+                for &(parent, mv, piece) in &parents {
+                    parent_layer.kind.remove_child(parent, mv, piece);
+                }
+                layer.kind.remove(index);
+                layer.nodes.fetch_sub(1, Ordering::Relaxed);
+                count -= 1;
+                removed += 1;
+            }
+            layer.kind.shrink_to_fit();
+        }
+        removed
+    }
+
+    /// The layer containing the root followed by every layer search has reached below it.
+    fn searched_layers(&self) -> Vec<&LayerCommon<E>> {
+        let mut layers = vec![self.current_layer()];
+        while let Some(&layer) = layers.last().filter(|l| l.expanded.load(Ordering::Relaxed)) {
+            // expanding a node creates its children in the next layer, so this doesn't allocate
+            layers.push(&layer.next_layer);
+        }
+        layers
+    }
+
+    /// The moves available from the root along with their evaluations and how many times search
+    /// has visited the positions they lead to. Empty if the next piece isn't known.
+    pub fn root_moves(&self) -> Vec<(Placement, E, u32)> {
+        let layer = self.current_layer();
+        layer
+            .kind
+            .children(&self.root)
+            .into_iter()
+            .map(|(mv, eval, node)| (mv, eval, layer.next_layer.kind.visits(node)))
+            .collect()
+    }
+
+    pub fn select(
+        &self,
+        speculate: bool,
+        exploitation: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<Selection<'_, E>> {
+        profile_function!();
+        let mut layers = vec![self.current_layer()];
         let mut game_state = self.root;
         loop {
             let &layer = layers.last().unwrap();
 
-            match layer.kind.select(&game_state, speculate, exploration) {
+            let next_layer = &layer.next_layer;
+            let child_visits = |node| next_layer.kind.visits(node);
+            match layer
+                .kind
+                .select(&game_state, speculate, exploitation, &child_visits, rng)
+            {
                 SelectResult::Failed => return None,
                 SelectResult::Done => return Some(Selection { layers, game_state }),
                 SelectResult::Advance(next, placement) => {
@@ -141,20 +347,55 @@ impl<E: Evaluation> Dag<E> {
     }
 }
 
+#[cfg(test)]
+impl<E: Evaluation + std::fmt::Debug> Dag<E> {
+    /// Panics if the tree below the root is inconsistent: an expanded node's evaluation isn't
+    /// that of its children, a node links to a parent which doesn't have it as a child, a
+    /// parent's cached evaluation of a child is stale, or a node is linked to the same parent
+    /// twice, which would mean it was expanded twice. Only meaningful while nothing is searching.
+    pub fn check_invariants(&self) {
+        let layers = self.searched_layers();
+        for layer in &layers {
+            layer.kind.check_evals();
+        }
+        for pair in layers.windows(2) {
+            let (parent_layer, layer) = (pair[0], pair[1]);
+            for (_, eval, parents) in layer.kind.nodes() {
+                let mut seen = std::collections::HashSet::new();
+                for &(parent, mv, piece) in &parents {
+                    assert!(seen.insert((parent, mv, piece)), "duplicate parent link");
+                    if let Some((cached, reward)) = parent_layer.kind.link(parent, mv, piece) {
+                        assert_eq!(cached, eval + reward, "stale cached evaluation");
+                    }
+                }
+            }
+        }
+    }
+
+    /// How many nodes below the root have been expanded.
+    pub fn expanded_nodes(&self) -> usize {
+        self.searched_layers()
+            .iter()
+            .map(|layer| layer.kind.expanded())
+            .sum()
+    }
+}
+
 impl<E: Evaluation> Selection<'_, E> {
     pub fn state(&self) -> (GameState, Option<Piece>) {
         (self.game_state, self.layers.last().unwrap().kind.piece())
     }
 
-    pub fn expand(self, children: EnumMap<Piece, Vec<ChildData<E>>>) {
-        puffin::profile_function!();
-        let mut layers = self.layers;
+    pub fn expand(mut self, children: EnumMap<Piece, Vec<ChildData<E>>>) {
+        profile_function!();
+        let mut layers = std::mem::take(&mut self.layers);
         let start_layer = layers.pop().unwrap();
+        start_layer.expanded.store(true, Ordering::Relaxed);
         let mut next = start_layer
             .kind
             .expand(&start_layer.next_layer, self.game_state, children);
 
-        puffin::profile_scope!("backprop");
+        profile_scope!("backprop");
         let mut next_layer = start_layer;
         while let Some(layer) = layers.pop() {
             next = layer.kind.backprop(next, next_layer);
@@ -167,36 +408,99 @@ impl<E: Evaluation> Selection<'_, E> {
     }
 }
 
+/// A selection dropped without being expanded, such as when generating its children panics,
+/// releases the node so it can be selected again.
+impl<E: Evaluation> Drop for Selection<'_, E> {
+    fn drop(&mut self) {
+        if let Some(layer) = self.layers.last() {
+            layer.kind.clear_expanding(&self.game_state);
+        }
+    }
+}
+
+/// Picks the child to explore with UCB1: the highest `value + c * sqrt(ln(N) / n)`, where `N` is
+/// `parent_visits`, `n` is the child's node's `child_visits`, and `c` is the reciprocal of
+/// `exploitation`. Values are rescaled so the siblings span 0 to 1, which keeps `c` meaningful
+/// whatever the scale of the evaluation. Children which were never visited come first, best
+/// first, since the list is sorted. `children` must be nonempty.
+fn select_child<'a, E: Evaluation>(
+    children: &'a [Child<E>],
+    parent_visits: u32,
+    child_visits: &dyn Fn(u64) -> u32,
+    exploitation: f64,
+) -> &'a Child<E> {
+    debug_assert!(!children.is_empty());
+    let c = 1.0 / exploitation.max(1e-6);
+    let best = children[0].cached_eval.value();
+    let worst = children[children.len() - 1].cached_eval.value();
+    let range = best - worst;
+    let normalize = |v: f64| match range > 0.0 {
+        true => (v - worst) / range,
+        false => 1.0,
+    };
+    let total = (parent_visits as f64).max(1.0);
+    // Other threads may visit children meanwhile, but slightly stale counts don't matter here
+    let ucb = |child: &Child<E>| match child_visits(child.node) {
+        0 => f64::INFINITY,
+        n => normalize(child.cached_eval.value()) + c * (total.ln() / n as f64).sqrt(),
+    };
+    let mut best = 0;
+    let mut best_score = f64::NEG_INFINITY;
+    for (i, score) in children.iter().map(ucb).enumerate() {
+        if score > best_score {
+            best = i;
+            best_score = score;
+            if score == f64::INFINITY {
+                break;
+            }
+        }
+    }
+    &children[best]
+}
+
+/// Updates the evaluation of the child reached by `placement` and keeps `list` sorted. Returns
+/// whether the first child's evaluation may have changed.
 fn update_child<E: Evaluation>(list: &mut [Child<E>], placement: Placement, child_eval: E) -> bool {
-    let mut index = list
+    let index = list
         .iter()
         .enumerate()
-        .find_map(|(i, c)| (c.mv == placement).then(|| i))
+        .find_map(|(i, c)| (c.mv == placement).then_some(i))
         .unwrap();
 
     list[index].cached_eval = child_eval + list[index].reward;
+    let eval = list[index].cached_eval;
 
-    if index > 0 && list[index - 1].cached_eval < list[index].cached_eval {
-        // Shift up until the list is in order
-        let hole = list[index];
-        while index > 0 && list[index - 1].cached_eval < hole.cached_eval {
-            list[index] = list[index - 1];
-            index -= 1;
-        }
-        list[index] = hole;
-    } else if index < list.len() - 1 && list[index + 1].cached_eval > list[index].cached_eval {
-        // Shift down until the list is in order
-        let hole = list[index];
-        while index < list.len() - 1 && list[index + 1].cached_eval > hole.cached_eval {
-            list[index] = list[index + 1];
-            index += 1;
-        }
-        list[index] = hole;
+    // Move the child up or down until the list is in order
+    let above = list[..index].partition_point(|c| c.cached_eval >= eval);
+    if above < index {
+        list[above..=index].rotate_right(1);
+        return above == 0;
     }
-
+    // If the best child got worse, whatever is first now is the new best
+    let below = index + 1 + list[index + 1..].partition_point(|c| c.cached_eval > eval);
+    list[index..below].rotate_left(1);
     index == 0
 }
 
+impl<E: Evaluation> LayerCommon<E> {
+    /// Links the nodes `children` lead to to `parent`, creating any that don't exist yet, and
+    /// gives their evaluations and raw indices.
+    fn create_nodes(
+        &self,
+        children: &[ChildData<E>],
+        parent: u64,
+        speculation_piece: Piece,
+    ) -> Vec<(E, u64)> {
+        let nodes = self.kind.create_nodes(children, parent, speculation_piece);
+        let created = nodes.iter().filter(|&&(_, _, created)| created).count();
+        self.nodes.fetch_add(created, Ordering::Relaxed);
+        nodes
+            .into_iter()
+            .map(|(eval, index, _)| (eval, index))
+            .collect()
+    }
+}
+
 impl<E: Evaluation> WithBump<E> {
     fn initialize_root(&self, root: &GameState) {
         self.with(|this| match this.data {
@@ -210,13 +514,20 @@ impl<E: Evaluation> WithBump<E> {
         to_update: Vec<BackpropUpdate>,
         next_layer: &LayerCommon<E>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         self.with(|this| match this.data {
             LayerKind::Known(l) => l.backprop(to_update, next_layer),
             LayerKind::Speculated(l) => l.backprop(to_update, next_layer),
         })
     }
 
+    fn clear_expanding(&self, state: &GameState) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.clear_expanding(state),
+            LayerKind::Speculated(l) => l.clear_expanding(state),
+        })
+    }
+
     fn piece(&self) -> Option<Piece> {
         self.with(|this| match this.data {
             LayerKind::Known(l) => Some(l.piece),
@@ -230,51 +541,129 @@ impl<E: Evaluation> WithBump<E> {
         parent_state: GameState,
         children: EnumMap<Piece, Vec<ChildData<E>>>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         self.with(|this| match this.data {
             LayerKind::Known(l) => l.expand(this.bump, next_layer, parent_state, children),
             LayerKind::Speculated(l) => l.expand(this.bump, next_layer, parent_state, children),
         })
     }
 
-    fn select(&self, game_state: &GameState, speculate: bool, exploration: f64) -> SelectResult {
-        puffin::profile_function!();
+    fn select(
+        &self,
+        game_state: &GameState,
+        speculate: bool,
+        exploitation: f64,
+        child_visits: &dyn Fn(u64) -> u32,
+        rng: &mut dyn RngCore,
+    ) -> SelectResult {
+        profile_function!();
         self.with(|this| match this.data {
-            LayerKind::Known(l) => l.select(game_state, exploration),
-            LayerKind::Speculated(l) if speculate => l.select(game_state, exploration),
+            LayerKind::Known(l) => l.select(game_state, exploitation, child_visits),
+            LayerKind::Speculated(l) if speculate => {
+                l.select(game_state, exploitation, child_visits, rng)
+            }
             LayerKind::Speculated(_) => SelectResult::Failed,
         })
     }
 
     fn suggest(&self, state: &GameState) -> Vec<Placement> {
-        puffin::profile_function!();
+        profile_function!();
         self.with(|this| match this.data {
             LayerKind::Known(l) => l.suggest(state),
             LayerKind::Speculated(l) => l.suggest(state),
         })
     }
 
-    fn despeculate(&mut self, piece: Piece) -> bool {
-        puffin::profile_function!();
+    fn child_evals(&self, state: &GameState) -> Vec<E> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.child_evals(state),
+            LayerKind::Speculated(l) => l.child_evals(state),
+        })
+    }
+
+    // This rebuilds the whole layer, so it relies on nobody else being able to observe it. Taking
+    // `&mut self` guarantees that: the only path here is `Dag::add_piece`, and callers must hold
+    // the bot exclusively, which `BotSyncronizer` does by taking the write lock that workers
+    // hold for reading for the entire duration of `do_work`.
+    fn despeculate(&mut self, piece: Piece) -> Option<Vec<BackpropUpdate>> {
+        profile_function!();
         self.with_mut(|this| {
             let old = match this.data {
-                LayerKind::Known(_) => return false,
+                LayerKind::Known(_) => return None,
                 LayerKind::Speculated(l) => std::mem::take(l),
             };
 
+            let mut updates = vec![];
             let layer = known::Layer {
-                states: old.states.map_values(|node| known::Node {
-                    parents: node.parents,
-                    eval: node.eval,
-                    children: node.children.map(|v| v.into_children(piece)),
-                    expanding: node.expanding,
+                states: old.states.map_entries(|index, node| {
+                    let children = node.children.map(|v| v.into_children(piece));
+                    let eval = match &children {
+                        Some(children) => E::average(std::iter::once((
+                            1.0,
+                            children.first().map(|c| c.cached_eval),
+                        ))),
+                        None => node.eval,
+                    };
+                    if eval != node.eval {
+                        for &(parent, mv, speculation_piece) in node.parents {
+                            updates.push(BackpropUpdate {
+                                parent,
+                                mv,
+                                speculation_piece,
+                                child: index,
+                            });
+                        }
+                    }
+                    known::Node {
+                        parents: node.parents,
+                        eval,
+                        children,
+                        expanding: node.expanding,
+                        visits: node.visits,
+                    }
                 }),
                 piece,
             };
 
             *this.data = LayerKind::Known(layer);
 
-            true
+            Some(updates)
+        })
+    }
+
+    fn best_child(&self, state: &GameState) -> Option<Placement> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.best_child(state),
+            LayerKind::Speculated(_) => None,
+        })
+    }
+
+    fn children(&self, state: &GameState) -> Vec<(Placement, E, u64)> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.children(state),
+            LayerKind::Speculated(_) => vec![],
+        })
+    }
+
+    /// How many times selection has passed through the node at raw index `index`.
+    fn visits(&self, index: u64) -> u32 {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l
+                .states
+                .get_raw(index)
+                .map(|n| n.visits.load(Ordering::Relaxed)),
+            LayerKind::Speculated(l) => l
+                .states
+                .get_raw(index)
+                .map(|n| n.visits.load(Ordering::Relaxed)),
+        })
+        .unwrap_or(0)
+    }
+
+    fn node_eval(&self, state: &GameState) -> E {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.states.get(state).unwrap().eval,
+            LayerKind::Speculated(l) => l.states.get(state).unwrap().eval,
         })
     }
 
@@ -285,12 +674,61 @@ impl<E: Evaluation> WithBump<E> {
         })
     }
 
+    fn leaves(&self) -> Vec<(u64, E)> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.leaves(),
+            LayerKind::Speculated(l) => l.leaves(),
+        })
+    }
+
+    fn parents(&self, index: u64) -> Vec<(u64, Placement, Piece)> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.parents(index).to_vec(),
+            LayerKind::Speculated(l) => l.parents(index).to_vec(),
+        })
+    }
+
+    fn is_best_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) -> bool {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.is_best_child(parent, mv, speculation_piece),
+            LayerKind::Speculated(l) => l.is_best_child(parent, mv, speculation_piece),
+        })
+    }
+
+    fn remove_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.remove_child(parent, mv, speculation_piece),
+            LayerKind::Speculated(l) => l.remove_child(parent, mv, speculation_piece),
+        })
+    }
+
+    fn remove(&self, index: u64) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.remove(index),
+            LayerKind::Speculated(l) => l.remove(index),
+        })
+    }
+
+    fn approx_bytes(&self, nodes: usize) -> usize {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.states.approx_bytes(nodes),
+            LayerKind::Speculated(l) => l.states.approx_bytes(nodes),
+        })
+    }
+
+    fn shrink_to_fit(&self) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.states.shrink_to_fit(),
+            LayerKind::Speculated(l) => l.states.shrink_to_fit(),
+        })
+    }
+
     fn create_nodes(
         &self,
         children: &[ChildData<E>],
         parent: u64,
         speculation_piece: Piece,
-    ) -> Vec<E> {
+    ) -> Vec<(E, u64, bool)> {
         self.with(|this| match this.data {
             LayerKind::Known(l) => {
                 let bump = this.bump.get();
@@ -310,6 +748,52 @@ impl<E: Evaluation> WithBump<E> {
     }
 }
 
+#[cfg(test)]
+impl<E: Evaluation + std::fmt::Debug> WithBump<E> {
+    fn check_evals(&self) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.check_evals(),
+            LayerKind::Speculated(l) => l.check_evals(),
+        })
+    }
+
+    fn link(&self, parent: u64, mv: Placement, piece: Piece) -> Option<(E, E::Reward)> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.link(parent, mv, piece),
+            LayerKind::Speculated(l) => l.link(parent, mv, piece),
+        })
+    }
+
+    /// How many nodes have been expanded.
+    fn expanded(&self) -> usize {
+        let mut expanded = 0;
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l
+                .states
+                .for_each(|_, n| expanded += n.children.is_some() as usize),
+            LayerKind::Speculated(l) => l
+                .states
+                .for_each(|_, n| expanded += n.children.is_some() as usize),
+        });
+        expanded
+    }
+
+    /// Every node with its evaluation and parent links.
+    #[allow(clippy::type_complexity)]
+    fn nodes(&self) -> Vec<(u64, E, Vec<(u64, Placement, Piece)>)> {
+        let mut nodes = vec![];
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l
+                .states
+                .for_each(|i, n| nodes.push((i, n.eval, n.parents.to_vec()))),
+            LayerKind::Speculated(l) => l
+                .states
+                .for_each(|i, n| nodes.push((i, n.eval, n.parents.to_vec()))),
+        });
+        nodes
+    }
+}
+
 impl<E: Evaluation> Default for WithBump<E> {
     fn default() -> Self {
         WithBump::new(Herd::new(), |_| LayerKind::Speculated(Default::default()))
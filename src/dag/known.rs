@@ -1,14 +1,14 @@
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU32};
 
 use bumpalo_herd::{Herd, Member};
 use enum_map::EnumMap;
-use rand::prelude::*;
 
 use crate::data::{GameState, Piece, Placement};
 use crate::map::StateMap;
 
 use super::{
-    update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon, SelectResult,
+    select_child, update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon,
+    SelectResult,
 };
 
 pub(super) struct Layer<'bump, E: Evaluation> {
@@ -21,6 +21,8 @@ pub(super) struct Node<'bump, E: Evaluation> {
     pub eval: E,
     pub children: Option<&'bump mut [Child<E>]>,
     pub expanding: AtomicBool,
+    /// How many times selection has passed through this node.
+    pub visits: AtomicU32,
 }
 
 impl<'bump, E: Evaluation> Layer<'bump, E> {
@@ -30,11 +32,12 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             eval: E::default(),
             children: None,
             expanding: AtomicBool::new(false),
+            visits: AtomicU32::new(0),
         });
     }
 
     pub fn suggest(&self, state: &GameState) -> Vec<Placement> {
-        puffin::profile_function!();
+        profile_function!();
         let node = self.states.get(state).unwrap();
         let children = match &node.children {
             Some(children) => children,
@@ -48,12 +51,42 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         candidates.into_iter().map(|c| c.mv).collect()
     }
 
-    pub fn select(&self, game_state: &GameState, exploration: f64) -> SelectResult {
-        puffin::profile_function!();
+    pub fn children(&self, state: &GameState) -> Vec<(Placement, E, u64)> {
+        let node = self.states.get(state).unwrap();
+        node.children
+            .iter()
+            .flat_map(|children| children.iter())
+            .map(|c| (c.mv, c.cached_eval, c.node))
+            .collect()
+    }
+
+    pub fn best_child(&self, state: &GameState) -> Option<Placement> {
+        let node = self.states.get(state)?;
+        let child = node.children.as_ref()?.first()?;
+        Some(child.mv)
+    }
+
+    pub fn child_evals(&self, state: &GameState) -> Vec<E> {
+        let node = self.states.get(state).unwrap();
+        node.children
+            .iter()
+            .flat_map(|children| children.iter())
+            .map(|c| c.cached_eval)
+            .collect()
+    }
+
+    pub fn select(
+        &self,
+        game_state: &GameState,
+        exploitation: f64,
+        child_visits: &dyn Fn(u64) -> u32,
+    ) -> SelectResult {
+        profile_function!();
         let node = self
             .states
             .get(game_state)
             .expect("Link to non-existent node?");
+        let visits = node.visits.fetch_add(1, atomic::Ordering::Relaxed) + 1;
 
         let children = match &node.children {
             None => {
@@ -70,37 +103,99 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             return SelectResult::Failed;
         }
 
-        let s: f64 = thread_rng().gen();
-        let i = ((-s.ln() / exploration) % children.len() as f64) as usize;
-        SelectResult::Advance(self.piece, children[i].mv)
+        SelectResult::Advance(
+            self.piece,
+            select_child(children, visits, child_visits, exploitation).mv,
+        )
+    }
+
+    pub fn clear_expanding(&self, state: &GameState) {
+        if let Some(node) = self.states.get(state) {
+            node.expanding.store(false, atomic::Ordering::Relaxed);
+        }
     }
 
     pub fn get_eval(&self, raw: u64) -> E {
         self.states.get_raw(raw).unwrap().eval
     }
 
+    /// The nodes which haven't been expanded, with their evaluations.
+    pub fn leaves(&self) -> Vec<(u64, E)> {
+        let mut leaves = vec![];
+        self.states.for_each(|index, node| {
+            if node.children.is_none() {
+                leaves.push((index, node.eval));
+            }
+        });
+        leaves
+    }
+
+    pub fn parents(&self, index: u64) -> &'bump [(u64, Placement, Piece)] {
+        self.states.get_raw(index).unwrap().parents
+    }
+
+    /// Whether `parent`'s move `mv` is its best. Links for other pieces than this layer's are
+    /// left over from before the piece was known and lead nowhere, so they never are.
+    pub fn is_best_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) -> bool {
+        if speculation_piece != self.piece {
+            return false;
+        }
+        let node = self.states.get_raw(parent).unwrap();
+        let children = node.children.as_deref().unwrap_or(&[]);
+        children.first().is_none_or(|c| c.mv == mv)
+    }
+
+    /// Forgets `parent`'s move `mv`, keeping the rest of its children in order. The memory the
+    /// child used stays allocated until the layer is dropped.
+    pub fn remove_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) {
+        if speculation_piece != self.piece {
+            return;
+        }
+        let mut node = self.states.get_raw_mut(parent).unwrap();
+        let children = match node.children.take() {
+            Some(children) => children,
+            None => return,
+        };
+        let len = match children.iter().position(|c| c.mv == mv) {
+            Some(i) => {
+                children[i..].rotate_left(1);
+                children.len() - 1
+            }
+            None => children.len(),
+        };
+        node.children = Some(&mut children[..len]);
+    }
+
+    pub fn remove(&self, index: u64) {
+        self.states.remove_raw(index);
+    }
+
     pub fn create_node(
         &self,
         bump: &Member<'bump>,
         child: &ChildData<E>,
         parent: u64,
         speculation_piece: Piece,
-    ) -> E {
-        let mut node = self
-            .states
-            .get_or_insert_with(&child.resulting_state, || Node {
+    ) -> (E, u64, bool) {
+        let mut created = false;
+        let index = self.states.index(&child.resulting_state);
+        let mut node = self.states.get_raw_or_insert_with(index, || {
+            created = true;
+            Node {
                 parents: &[],
                 eval: child.eval,
                 children: None,
                 expanding: AtomicBool::new(false),
-            });
+                visits: AtomicU32::new(0),
+            }
+        });
         node.parents = bump.alloc_slice_fill_with(node.parents.len() + 1, |i| {
             node.parents
                 .get(i)
                 .copied()
                 .unwrap_or((parent, child.mv, speculation_piece))
         });
-        node.eval
+        (node.eval, index, created)
     }
 
     pub fn expand(
@@ -110,7 +205,7 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         parent_state: GameState,
         children: EnumMap<Piece, Vec<ChildData<E>>>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         let mut childs = Vec::with_capacity(children[self.piece].len());
 
         // We need to acquire the lock on the parent since the backprop routine needs the children
@@ -119,23 +214,24 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         let mut parent = self.states.get_raw_mut(parent_index).unwrap();
 
         {
-            puffin::profile_scope!("create nodes");
-            let evals =
-                next_layer
-                    .kind
-                    .create_nodes(&children[self.piece], parent_index, self.piece);
-            for (child, eval) in children[self.piece].iter().zip(evals.into_iter()) {
+            profile_scope!("create nodes");
+            let evals = next_layer.create_nodes(&children[self.piece], parent_index, self.piece);
+            for (child, (eval, index)) in children[self.piece].iter().zip(evals) {
                 childs.push(Child {
                     mv: child.mv,
                     cached_eval: eval + child.reward,
                     reward: child.reward,
+                    node: index,
                 });
             }
         }
 
         childs.sort_by(|a, b| a.cached_eval.cmp(&b.cached_eval).reverse());
 
-        parent.eval = E::average(std::iter::once(childs.first().map(|c| c.cached_eval)));
+        parent.eval = E::average(std::iter::once((
+            1.0,
+            childs.first().map(|c| c.cached_eval),
+        )));
         parent.children = Some(herd.get().alloc_slice_copy(&childs));
 
         let mut next = vec![];
@@ -157,7 +253,7 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         to_update: Vec<BackpropUpdate>,
         next_layer: &LayerCommon<E>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         let mut new_updates = vec![];
 
         for update in to_update {
@@ -193,3 +289,40 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         new_updates
     }
 }
+
+#[cfg(test)]
+impl<E: Evaluation + std::fmt::Debug> Layer<'_, E> {
+    /// Panics unless every expanded node's children are sorted and its evaluation is its best
+    /// child's.
+    pub fn check_evals(&self) {
+        self.states.for_each(|_, node| {
+            if let Some(children) = &node.children {
+                assert!(children
+                    .windows(2)
+                    .all(|w| w[0].cached_eval >= w[1].cached_eval));
+                let best = children.first().map(|c| c.cached_eval);
+                assert_eq!(node.eval, E::average(std::iter::once((1.0, best))));
+            }
+        });
+    }
+
+    /// The cached evaluation and reward of `parent`'s move `mv`, or `None` for a link left over
+    /// from before the piece was known. Panics if the link should exist but doesn't.
+    pub fn link(
+        &self,
+        parent: u64,
+        mv: Placement,
+        speculation_piece: Piece,
+    ) -> Option<(E, E::Reward)> {
+        if speculation_piece != self.piece {
+            return None;
+        }
+        let node = self.states.get_raw(parent).expect("parent doesn't exist");
+        let children = node.children.as_deref().expect("parent isn't expanded");
+        let child = children
+            .iter()
+            .find(|c| c.mv == mv)
+            .expect("parent lacks move");
+        Some((child.cached_eval, child.reward))
+    }
+}
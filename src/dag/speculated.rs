@@ -1,5 +1,5 @@
 use std::ops::{Index, IndexMut};
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU32};
 
 use bumpalo_herd::{Herd, Member};
 use enum_map::EnumMap;
@@ -10,7 +10,8 @@ use crate::data::{GameState, Piece, Placement};
 use crate::map::StateMap;
 
 use super::{
-    update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon, SelectResult,
+    select_child, update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon,
+    SelectResult,
 };
 
 #[derive(Default)]
@@ -23,8 +24,11 @@ pub(super) struct Node<'bump, E: Evaluation> {
     pub eval: E,
     pub children: Option<PackedChildren<'bump, E>>,
     pub expanding: AtomicBool,
+    /// How many times selection has passed through this node.
+    pub visits: AtomicU32,
     // we need this info while backpropagating, but we don't have access to the game state then
     bag: EnumSet<Piece>,
+    last_drawn: Option<Piece>,
 }
 
 impl<'bump, E: Evaluation> Layer<'bump, E> {
@@ -34,33 +38,63 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             eval: E::default(),
             children: None,
             expanding: AtomicBool::new(false),
+            visits: AtomicU32::new(0),
             bag: root.bag,
+            last_drawn: root.last_drawn,
         });
     }
 
     pub fn suggest(&self, state: &GameState) -> Vec<Placement> {
-        puffin::profile_function!();
+        profile_function!();
         let node = self.states.get(state).unwrap();
         let children = match &node.children {
             Some(children) => children,
             None => return vec![],
         };
 
+        // The next piece isn't known yet, so the only piece we can actually play is the one in
+        // reserve; holding it would mean playing a piece the frontend hasn't shown us.
         let mut candidates: Vec<&_> = vec![];
         for piece in state.bag {
-            candidates.extend(children[piece].first());
+            candidates.extend(
+                children[piece]
+                    .iter()
+                    .find(|c| c.mv.location.piece == state.reserve),
+            );
         }
         candidates.sort_by(|a, b| a.cached_eval.partial_cmp(&b.cached_eval).unwrap().reverse());
 
         candidates.into_iter().map(|c| c.mv).collect()
     }
 
-    pub fn select(&self, game_state: &GameState, exploration: f64) -> SelectResult {
-        puffin::profile_function!();
+    pub fn child_evals(&self, state: &GameState) -> Vec<E> {
+        let node = self.states.get(state).unwrap();
+        let children = match &node.children {
+            Some(children) => children,
+            None => return vec![],
+        };
+        state
+            .bag
+            .iter()
+            .flat_map(|piece| children[piece].iter())
+            .filter(|c| c.mv.location.piece == state.reserve)
+            .map(|c| c.cached_eval)
+            .collect()
+    }
+
+    pub fn select(
+        &self,
+        game_state: &GameState,
+        exploitation: f64,
+        child_visits: &dyn Fn(u64) -> u32,
+        rng: &mut dyn RngCore,
+    ) -> SelectResult {
+        profile_function!();
         let node = self
             .states
             .get(game_state)
             .expect("Link to non-existent node?");
+        let visits = node.visits.fetch_add(1, atomic::Ordering::Relaxed) + 1;
 
         let children = match &node.children {
             None => {
@@ -73,48 +107,117 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             Some(children) => children,
         };
 
-        let next = game_state
-            .bag
-            .iter()
-            .nth(thread_rng().gen_range(0..game_state.bag.len()))
-            .unwrap();
+        let next = match game_state.last_drawn {
+            None => game_state
+                .bag
+                .iter()
+                .nth(rng.gen_range(0..game_state.bag.len()))
+                .unwrap(),
+            Some(last) => {
+                let total: f32 = game_state
+                    .bag
+                    .iter()
+                    .map(|p| p.draw_weight(Some(last)))
+                    .sum();
+                let mut roll = rng.gen_range(0.0..total);
+                game_state
+                    .bag
+                    .iter()
+                    .find(|p| {
+                        roll -= p.draw_weight(Some(last));
+                        roll < 0.0
+                    })
+                    .or(game_state.bag.iter().last())
+                    .unwrap()
+            }
+        };
 
         if children[next].is_empty() {
             return SelectResult::Failed;
         }
 
-        let s: f64 = thread_rng().gen();
-        let i = ((-s.ln() / exploration) % children[next].len() as f64) as usize;
-        SelectResult::Advance(next, children[next][i].mv)
+        SelectResult::Advance(
+            next,
+            select_child(&children[next], visits, child_visits, exploitation).mv,
+        )
+    }
+
+    pub fn clear_expanding(&self, state: &GameState) {
+        if let Some(node) = self.states.get(state) {
+            node.expanding.store(false, atomic::Ordering::Relaxed);
+        }
     }
 
     pub fn get_eval(&self, raw: u64) -> E {
         self.states.get_raw(raw).unwrap().eval
     }
 
+    /// The nodes which haven't been expanded, with their evaluations.
+    pub fn leaves(&self) -> Vec<(u64, E)> {
+        let mut leaves = vec![];
+        self.states.for_each(|index, node| {
+            if node.children.is_none() {
+                leaves.push((index, node.eval));
+            }
+        });
+        leaves
+    }
+
+    pub fn parents(&self, index: u64) -> &'bump [(u64, Placement, Piece)] {
+        self.states.get_raw(index).unwrap().parents
+    }
+
+    /// Whether `parent`'s move `mv` is its best when the next piece is `speculation_piece`.
+    pub fn is_best_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) -> bool {
+        let node = self.states.get_raw(parent).unwrap();
+        node.children
+            .as_ref()
+            .and_then(|children| children[speculation_piece].first())
+            .is_none_or(|c| c.mv == mv)
+    }
+
+    /// Forgets `parent`'s move `mv` when the next piece is `speculation_piece`, keeping the rest
+    /// of its children in order. The memory the child used stays allocated until the layer is
+    /// dropped.
+    pub fn remove_child(&self, parent: u64, mv: Placement, speculation_piece: Piece) {
+        let mut node = self.states.get_raw_mut(parent).unwrap();
+        if let Some(children) = &mut node.children {
+            children.remove(speculation_piece, mv);
+        }
+    }
+
+    pub fn remove(&self, index: u64) {
+        self.states.remove_raw(index);
+    }
+
     pub fn create_node(
         &self,
         bump: &Member<'bump>,
         child: &ChildData<E>,
         parent: u64,
         speculation_piece: Piece,
-    ) -> E {
-        let mut node = self
-            .states
-            .get_or_insert_with(&child.resulting_state, || Node {
+    ) -> (E, u64, bool) {
+        let mut created = false;
+        let index = self.states.index(&child.resulting_state);
+        let mut node = self.states.get_raw_or_insert_with(index, || {
+            created = true;
+            Node {
                 parents: &[],
                 eval: child.eval,
                 children: None,
                 expanding: AtomicBool::new(false),
+                visits: AtomicU32::new(0),
                 bag: child.resulting_state.bag,
-            });
+                last_drawn: child.resulting_state.last_drawn,
+            }
+        });
         node.parents = bump.alloc_slice_fill_with(node.parents.len() + 1, |i| {
             node.parents
                 .get(i)
                 .copied()
                 .unwrap_or((parent, child.mv, speculation_piece))
         });
-        node.eval
+        (node.eval, index, created)
     }
 
     pub fn expand(
@@ -124,7 +227,7 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         parent_state: GameState,
         children: EnumMap<Piece, Vec<ChildData<E>>>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         let mut childs_data = vec![];
         let mut childs_indices = [0; 8];
 
@@ -134,18 +237,19 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         let mut parent = self.states.get_raw_mut(parent_index).unwrap();
 
         {
-            puffin::profile_scope!("create nodes");
+            profile_scope!("create nodes");
             for speculation_piece in EnumSet::all() {
-                let evals = next_layer.kind.create_nodes(
+                let evals = next_layer.create_nodes(
                     &children[speculation_piece],
                     parent_index,
                     speculation_piece,
                 );
-                for (child, eval) in children[speculation_piece].iter().zip(evals.into_iter()) {
+                for (child, (eval, index)) in children[speculation_piece].iter().zip(evals) {
                     childs_data.push(Child {
                         mv: child.mv,
                         cached_eval: eval + child.reward,
                         reward: child.reward,
+                        node: index,
                     });
                 }
                 childs_indices[speculation_piece as usize + 1] = childs_data.len() as u16;
@@ -161,12 +265,7 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             children[p].sort_by(|a, b| a.cached_eval.cmp(&b.cached_eval).reverse());
         }
 
-        let next_possibilities = parent.bag;
-        parent.eval = E::average(
-            next_possibilities
-                .iter()
-                .map(|p| children[p].first().map(|c| c.cached_eval)),
-        );
+        parent.eval = average_best(&parent, &children);
 
         parent.children = Some(children);
 
@@ -189,23 +288,20 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         to_update: Vec<BackpropUpdate>,
         next_layer: &LayerCommon<E>,
     ) -> Vec<BackpropUpdate> {
-        puffin::profile_function!();
+        profile_function!();
         let mut new_updates = vec![];
 
         for update in to_update {
             let mut parent = self.states.get_raw_mut(update.parent).unwrap();
             let child_eval = next_layer.kind.get_eval(update.child);
 
-            let parent_bag = parent.bag;
             let children = parent.children.as_mut().unwrap();
             let list = &mut children[update.speculation_piece];
 
             let is_best = update_child(list, update.mv, child_eval);
 
             if is_best {
-                let best_for = |p: Piece| children[p].first().map(|c| c.cached_eval);
-
-                let eval = E::average(parent_bag.iter().map(best_for));
+                let eval = average_best(&parent, parent.children.as_ref().unwrap());
 
                 if parent.eval != eval {
                     parent.eval = eval;
@@ -226,6 +322,17 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
     }
 }
 
+/// The evaluation of `node` given its `children`: the average over the pieces that can come next
+/// of the best child for each, weighted by how likely that piece is.
+fn average_best<E: Evaluation>(node: &Node<E>, children: &PackedChildren<E>) -> E {
+    E::average(node.bag.iter().map(|p| {
+        (
+            p.draw_weight(node.last_drawn),
+            children[p].first().map(|c| c.cached_eval),
+        )
+    }))
+}
+
 pub(super) struct PackedChildren<'bump, E: Evaluation> {
     data: &'bump mut [Child<E>],
     start_indices: [u16; 8],
@@ -250,9 +357,60 @@ impl<'bump, E: Evaluation> IndexMut<Piece> for PackedChildren<'bump, E> {
 }
 
 impl<'bump, E: Evaluation> PackedChildren<'bump, E> {
+    fn remove(&mut self, piece: Piece, mv: Placement) {
+        let start = self.start_indices[piece as usize] as usize;
+        let index = match self[piece].iter().position(|c| c.mv == mv) {
+            Some(i) => start + i,
+            None => return,
+        };
+        // Shift everything after the child down so the lists for later pieces stay contiguous
+        let data = std::mem::take(&mut self.data);
+        data[index..].rotate_left(1);
+        let len = data.len() - 1;
+        self.data = &mut data[..len];
+        for start in &mut self.start_indices[piece as usize + 1..] {
+            *start -= 1;
+        }
+    }
+
     pub(super) fn into_children(self, piece: Piece) -> &'bump mut [Child<E>] {
         let start = self.start_indices[piece as usize] as usize;
         let end = self.start_indices[piece as usize + 1] as usize;
         &mut self.data[start..end]
     }
 }
+
+#[cfg(test)]
+impl<E: Evaluation + std::fmt::Debug> Layer<'_, E> {
+    /// Panics unless every expanded node's children are sorted and its evaluation is the weighted
+    /// average over the possible next pieces of its best child for each.
+    pub fn check_evals(&self) {
+        self.states.for_each(|_, node| {
+            if let Some(children) = &node.children {
+                for piece in EnumSet::all() {
+                    assert!(children[piece]
+                        .windows(2)
+                        .all(|w| w[0].cached_eval >= w[1].cached_eval));
+                }
+                assert_eq!(node.eval, average_best(node, children));
+            }
+        });
+    }
+
+    /// The cached evaluation and reward of `parent`'s move `mv` when the next piece is
+    /// `speculation_piece`. Panics if there is no such move.
+    pub fn link(
+        &self,
+        parent: u64,
+        mv: Placement,
+        speculation_piece: Piece,
+    ) -> Option<(E, E::Reward)> {
+        let node = self.states.get_raw(parent).expect("parent doesn't exist");
+        let children = node.children.as_ref().expect("parent isn't expanded");
+        let child = children[speculation_piece]
+            .iter()
+            .find(|c| c.mv == mv)
+            .expect("parent lacks move");
+        Some((child.cached_eval, child.reward))
+    }
+}
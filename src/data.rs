@@ -1,20 +1,55 @@
 use enum_map::Enum;
 use enumset::{EnumSet, EnumSetType};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use std::hash::{Hash, Hasher};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
-#[serde(from = "Vec<[Option<char>; 10]>")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "Vec<Vec<Option<char>>>", into = "Vec<[Option<char>; 10]>")]
 pub struct Board {
     pub cols: [u64; 10],
+    /// Which filled cells came from garbage rather than placed pieces.
+    #[cfg(feature = "garbage-plane")]
+    pub garbage: [u64; 10],
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct GameState {
+    /// Replace it with `set_board` rather than assigning it, so `heights` stays in step.
     pub board: Board,
     pub bag: EnumSet<Piece>,
+    /// Whether pieces are drawn independently of each other rather than from a 7-bag, in which
+    /// case `bag` always holds every piece.
+    pub memoryless: bool,
+    /// With the classic (NES) randomizer, the piece drawn last, which is less likely to be drawn
+    /// again. Always `None` with other randomizers.
+    pub last_drawn: Option<Piece>,
+    /// The piece which may be played instead of the next piece from the queue. Before anything
+    /// has been held this is the current piece, so playing the next queue piece instead
+    /// represents holding the current piece into an empty hold slot.
     pub reserve: Piece,
-    pub back_to_back: bool,
+    /// The number of consecutive hard (tetris or spin) line clears so far. Any nonzero value
+    /// means the next hard clear will be back-to-back.
+    pub b2b: u16,
     pub combo: u8,
+    /// The height of each column of `board`, kept up to date by `advance` so evaluation doesn't
+    /// have to recompute it. Set by `new` and `set_board`, which is why it isn't public.
+    heights: [u8; 10],
+}
+
+impl Hash for GameState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // heights follow from the board and memoryless is the same throughout a game, so hashing
+        // them would only cost time
+        self.board.hash(state);
+        self.bag.hash(state);
+        self.last_drawn.hash(state);
+        self.reserve.hash(state);
+        self.b2b.hash(state);
+        self.combo.hash(state);
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -37,12 +72,81 @@ pub struct Placement {
 pub struct PlacementInfo {
     pub placement: Placement,
     pub lines_cleared: u32,
+    /// The number of consecutive line clearing placements, including this one.
     pub combo: u32,
     pub back_to_back: bool,
+    /// The number of consecutive hard clears after this placement, the same as the resulting
+    /// `GameState::b2b`. A placement which doesn't clear lines leaves it unchanged.
+    pub b2b_chain: u16,
     pub perfect_clear: bool,
+    /// Whether the reserve piece was played instead of the next piece.
+    pub hold: bool,
+    /// How many of the cleared lines contained garbage.
+    pub garbage_cleared: u32,
+    /// Garbage sent by this placement, before cancelling any incoming garbage.
+    pub attack: u32,
+}
+
+/// How much garbage line clears send. The default is the guideline table.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttackTable {
+    /// Indexed by the number of lines cleared.
+    pub normal_clears: [u32; 5],
+    pub mini_spin_clears: [u32; 3],
+    pub spin_clears: [u32; 4],
+    pub back_to_back: u32,
+    /// Indexed by the combo including the clear itself, with the last entry used for any combo
+    /// past the end of the table.
+    pub combo: Vec<u32>,
+    /// Sent instead of everything else by a perfect clear.
+    pub perfect_clear: u32,
+}
+
+impl Default for AttackTable {
+    fn default() -> Self {
+        AttackTable {
+            normal_clears: [0, 0, 1, 2, 4],
+            mini_spin_clears: [0, 0, 1],
+            spin_clears: [0, 2, 4, 6],
+            back_to_back: 1,
+            combo: vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            perfect_clear: 10,
+        }
+    }
 }
 
-#[allow(clippy::derive_hash_xor_eq)]
+/// How the holes in incoming garbage rows are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum GarbagePattern {
+    /// Every garbage row has its hole in the same column.
+    Clean { column: u8 },
+    /// Each batch of garbage picks a random column for all of its rows.
+    CleanRandom,
+    /// Each row moves the hole to a different random column with the given probability.
+    Messy { change_chance: f64 },
+}
+
+impl GarbagePattern {
+    /// Rejects columns off the board and change chances which aren't probabilities.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            GarbagePattern::Clean { column } if column >= 10 => {
+                Err(format!("garbage column {} is off the board", column))
+            }
+            GarbagePattern::Messy { change_chance } if !(0.0..=1.0).contains(&change_chance) => {
+                Err(format!(
+                    "garbage change_chance {} is not between 0 and 1",
+                    change_chance
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(EnumSetType, Enum, Debug, Hash, Serialize, Deserialize)]
 pub enum Piece {
     I,
@@ -83,6 +187,18 @@ impl Piece {
             Piece::Z => [(-1, 1), (0, 1), (0, 0), (1, 0)],
         }
     }
+
+    /// How likely this piece is to be drawn next, relative to the others that can be, when
+    /// `last_drawn` is `GameState::last_drawn`. The NES rolls one of 8 and rerolls one of 7 if it
+    /// rolled the 8th or the last piece, so a repeat comes up 2 times in 56 and every other piece
+    /// 9 times.
+    pub fn draw_weight(self, last_drawn: Option<Piece>) -> f32 {
+        match last_drawn {
+            Some(last) if last == self => 2.0,
+            Some(_) => 9.0,
+            None => 1.0,
+        }
+    }
 }
 
 impl Rotation {
@@ -248,6 +364,48 @@ impl PieceLocation {
 }
 
 impl Board {
+    pub const fn new(cols: [u64; 10]) -> Self {
+        Board {
+            cols,
+            #[cfg(feature = "garbage-plane")]
+            garbage: [0; 10],
+        }
+    }
+
+    /// Parses a board drawn as text, one row per line with the top row first. `.`, `_` and
+    /// spaces are empty cells and anything else is filled. Rows past the 10th column and past the
+    /// 40th row are ignored.
+    pub fn from_ascii(text: &str) -> Self {
+        let mut board = Board::default();
+        let rows: Vec<_> = text.lines().filter(|l| !l.trim().is_empty()).collect();
+        for (y, row) in rows.iter().rev().take(40).enumerate() {
+            for (x, c) in row.chars().take(10).enumerate() {
+                if !matches!(c, '.' | '_' | ' ') {
+                    board.fill(x, y, c);
+                }
+            }
+        }
+        board
+    }
+
+    /// Reads the field of the first page of a `v115@` fumen. Cells are filled as the piece they
+    /// are drawn as, so gray cells count as garbage.
+    pub fn from_fumen(fumen: &str) -> Result<Self, crate::fumen::FumenError> {
+        crate::fumen::decode(fumen)
+    }
+
+    /// Fills a cell drawn as `c`. With the `garbage-plane` feature, cells drawn as `G` (as TBP
+    /// does for garbage) are recorded as garbage.
+    pub fn fill(&mut self, x: usize, y: usize, c: char) {
+        self.cols[x] |= 1 << y;
+        #[cfg(feature = "garbage-plane")]
+        if c == 'G' {
+            self.garbage[x] |= 1 << y;
+        }
+        #[cfg(not(feature = "garbage-plane"))]
+        let _ = c;
+    }
+
     pub const fn occupied(&self, (x, y): (i8, i8)) -> bool {
         if x < 0 || x >= 10 || y < 0 || y >= 40 {
             return true;
@@ -264,6 +422,11 @@ impl Board {
         (!self.cols[x as usize] << (64 - y)).leading_ones() as i8
     }
 
+    /// The height of each column, counting holes as part of the stack.
+    pub fn heights(&self) -> [u8; 10] {
+        self.cols.map(|c| 64 - c.leading_zeros() as u8)
+    }
+
     pub fn place(&mut self, piece: PieceLocation) {
         for &(x, y) in &piece.cells() {
             debug_assert!((0..10).contains(&x));
@@ -272,6 +435,144 @@ impl Board {
         }
     }
 
+    /// Like `place`, but leaves the board untouched and returns the offending cell if the piece
+    /// is out of bounds or overlaps filled cells.
+    pub fn place_checked(&mut self, piece: PieceLocation) -> Result<(), (i8, i8)> {
+        if let Some(&cell) = piece.cells().iter().find(|&&cell| self.occupied(cell)) {
+            return Err(cell);
+        }
+        self.place(piece);
+        Ok(())
+    }
+
+    /// Pushes `lines` rows of garbage in from the bottom of the board, with holes laid out
+    /// according to `pattern`. Returns `true` if any filled cells were pushed out of the top.
+    /// Panics if `pattern` doesn't pass `GarbagePattern::validate`.
+    pub fn insert_garbage(
+        &mut self,
+        lines: u32,
+        pattern: GarbagePattern,
+        rng: &mut impl Rng,
+    ) -> bool {
+        let lines = lines.min(40);
+        let mut hole = match pattern {
+            GarbagePattern::Clean { column } => column as usize,
+            _ => rng.gen_range(0..10),
+        };
+        let mut holes = [0u64; 10];
+        for y in 0..lines {
+            if let GarbagePattern::Messy { change_chance } = pattern {
+                if y != 0 && rng.gen_bool(change_chance) {
+                    hole = (hole + rng.gen_range(1..10)) % 10;
+                }
+            }
+            holes[hole] |= 1 << y;
+        }
+        self.push_garbage(lines, holes)
+    }
+
+    /// Pushes `lines` rows of garbage with the hole in `column` in from the bottom of the board.
+    /// Returns `true` if any filled cells were pushed out of the top. Panics if `column` is off
+    /// the board.
+    pub fn add_garbage(&mut self, column: u8, lines: u8) -> bool {
+        let lines = (lines as u32).min(40);
+        let mut holes = [0u64; 10];
+        holes[column as usize] = (1 << lines) - 1;
+        self.push_garbage(lines, holes)
+    }
+
+    fn push_garbage(&mut self, lines: u32, holes: [u64; 10]) -> bool {
+        let garbage = (1 << lines) - 1;
+        let mut overflowed = false;
+        for (c, h) in self.cols.iter_mut().zip(holes) {
+            overflowed |= *c >> (40 - lines) != 0;
+            *c = (*c << lines | garbage & !h) & ((1 << 40) - 1);
+        }
+        #[cfg(feature = "garbage-plane")]
+        for (c, h) in self.garbage.iter_mut().zip(holes) {
+            *c = (*c << lines | garbage & !h) & ((1 << 40) - 1);
+        }
+        overflowed
+    }
+
+    /// Cheap necessary-condition check for whether the board can still be perfect cleared using
+    /// at most `pieces` more pieces. Boards with holes are rejected, and every region of empty
+    /// cells separated by filled columns must be fillable by whole pieces.
+    pub fn perfect_clear_possible(&self, pieces: u32) -> bool {
+        let heights = self.cols.map(|c| 64 - c.leading_zeros());
+        if self
+            .cols
+            .iter()
+            .zip(heights)
+            .any(|(&c, h)| c.count_ones() != h)
+        {
+            return false;
+        }
+        let filled: u32 = heights.iter().sum();
+        if filled == 0 {
+            return true;
+        }
+        let lowest = heights.iter().copied().max().unwrap().max(1);
+        (lowest..=40)
+            .take_while(|&h| 10 * h - filled <= 4 * pieces)
+            .any(|h| {
+                let mut region = 0;
+                for &height in &heights {
+                    if height == h {
+                        if region % 4 != 0 {
+                            return false;
+                        }
+                        region = 0;
+                    } else {
+                        region += h - height;
+                    }
+                }
+                region % 4 == 0
+            })
+    }
+
+    /// Cells that differ between `self` (expected) and `actual`, as `(x, y, expected, actual)`
+    /// occupancy, ordered by column then row.
+    pub fn diff(&self, actual: &Board) -> Vec<(i8, i8, bool, bool)> {
+        let mut cells = vec![];
+        for x in 0..10 {
+            let mut differing = self.cols[x as usize] ^ actual.cols[x as usize];
+            while differing != 0 {
+                let y = differing.trailing_zeros() as i8;
+                differing &= differing - 1;
+                cells.push((x, y, self.occupied((x, y)), actual.occupied((x, y))));
+            }
+        }
+        cells
+    }
+
+    /// Renders both boards overlaid on each other, top row first, up to the highest filled row of
+    /// either. Cells filled in both are `#`, cells only `self` has are `-`, and cells only
+    /// `actual` has are `+`.
+    pub fn diff_string(&self, actual: &Board) -> String {
+        let height = self
+            .cols
+            .iter()
+            .chain(&actual.cols)
+            .map(|c| 64 - c.leading_zeros() as i8)
+            .max()
+            .unwrap();
+        let mut out = String::new();
+        for y in (0..height).rev() {
+            out.push_str(&format!("{:2} ", y));
+            for x in 0..10 {
+                out.push(match (self.occupied((x, y)), actual.occupied((x, y))) {
+                    (true, true) => '#',
+                    (true, false) => '-',
+                    (false, true) => '+',
+                    (false, false) => '.',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
     pub fn line_clears(&self) -> u64 {
         self.cols.iter().fold(!0, |a, b| a & b)
     }
@@ -280,36 +581,155 @@ impl Board {
         for c in &mut self.cols {
             clear_lines(c, lines);
         }
+        #[cfg(feature = "garbage-plane")]
+        for c in &mut self.garbage {
+            clear_lines(c, lines);
+        }
+    }
+
+    /// The number of rows in `lines` which contain garbage. Always zero without the
+    /// `garbage-plane` feature.
+    pub fn garbage_lines(&self, lines: u64) -> u32 {
+        #[cfg(feature = "garbage-plane")]
+        let garbage_rows = self.garbage.iter().fold(0, |a, b| a | b);
+        #[cfg(not(feature = "garbage-plane"))]
+        let garbage_rows = 0;
+        (garbage_rows & lines).count_ones()
+    }
+}
+
+impl AttackTable {
+    /// The garbage sent by a placement, from everything in `info` but the attack itself.
+    pub fn attack(&self, info: &PlacementInfo) -> u32 {
+        let lines = info.lines_cleared as usize;
+        if lines == 0 {
+            return 0;
+        }
+        if info.perfect_clear {
+            return self.perfect_clear;
+        }
+        let mut attack = match info.placement.spin {
+            // only an I piece can clear 4 lines, and with all-spin it may be immobile doing so
+            _ if lines == 4 => self.normal_clears[4],
+            Spin::None => self.normal_clears[lines],
+            Spin::Mini => self.mini_spin_clears.get(lines).copied().unwrap_or(0),
+            Spin::Full => self.spin_clears[lines],
+        };
+        if info.back_to_back {
+            attack += self.back_to_back;
+        }
+        let combo = (info.combo as usize).min(self.combo.len().saturating_sub(1));
+        attack += self.combo.get(combo).copied().unwrap_or(0);
+        attack
+    }
+}
+
+impl PlacementInfo {
+    /// Uses this placement's attack to cancel `incoming` garbage first, returning the number of
+    /// lines actually sent. Whatever is left in `incoming` should be inserted into the board with
+    /// `Board::insert_garbage` if this placement didn't clear any lines.
+    pub fn cancel_garbage(&self, incoming: &mut u32) -> u32 {
+        let cancelled = self.attack.min(*incoming);
+        *incoming -= cancelled;
+        self.attack - cancelled
     }
 }
 
 impl GameState {
+    /// A state with no hold swaps or line clears to account for other than the given `b2b` and
+    /// `combo`. Pieces come from a 7-bag with `bag` left in it; set `memoryless` (and for the
+    /// classic randomizer, `last_drawn`) afterwards for other randomizers.
+    pub fn new(board: Board, reserve: Piece, bag: EnumSet<Piece>, b2b: u16, combo: u8) -> Self {
+        GameState {
+            board,
+            bag,
+            memoryless: false,
+            last_drawn: None,
+            reserve,
+            b2b,
+            combo,
+            heights: board.heights(),
+        }
+    }
+
+    /// The height of each column of the board, counting holes as part of the stack.
+    pub fn heights(&self) -> [u8; 10] {
+        self.heights
+    }
+
+    /// Replaces the board, keeping the column heights in step with it. Change the board through
+    /// this rather than the `board` field.
+    pub fn set_board(&mut self, board: Board) {
+        self.board = board;
+        self.heights = board.heights();
+    }
+
+    /// Plays `placement`, with `next` as the piece coming out of the queue. Attack is counted
+    /// with the guideline table.
     pub fn advance(&mut self, next: Piece, placement: Placement) -> PlacementInfo {
-        self.bag.remove(next);
-        if self.bag.is_empty() {
-            self.bag = EnumSet::all();
+        static GUIDELINE: Lazy<AttackTable> = Lazy::new(AttackTable::default);
+        self.advance_with(next, placement, &GUIDELINE)
+    }
+
+    /// Plays `placement`, with `next` as the piece coming out of the queue, counting attack with
+    /// `attack`.
+    ///
+    /// `next` is drawn from the bag either way. If `placement` is of the reserve piece rather
+    /// than `next`, `next` is held and becomes the new reserve.
+    pub fn advance_with(
+        &mut self,
+        next: Piece,
+        placement: Placement,
+        attack: &AttackTable,
+    ) -> PlacementInfo {
+        if !self.memoryless {
+            self.bag.remove(next);
+            if self.bag.is_empty() {
+                self.bag = EnumSet::all();
+            }
         }
-        if placement.location.piece != next {
+        if self.last_drawn.is_some() {
+            self.last_drawn = Some(next);
+        }
+        let hold = placement.location.piece != next;
+        if hold {
             self.reserve = next;
         }
         self.board.place(placement.location);
+        for (x, y) in placement.location.cells() {
+            let height = &mut self.heights[x as usize];
+            *height = (*height).max(y as u8 + 1);
+        }
         let cleared_mask = self.board.line_clears();
         let mut back_to_back = false;
+        let garbage_cleared = self.board.garbage_lines(cleared_mask);
         if cleared_mask != 0 {
             self.board.remove_lines(cleared_mask);
+            self.heights = self.board.heights();
             let hard = cleared_mask.count_ones() == 4 || !matches!(placement.spin, Spin::None);
-            back_to_back = hard && self.back_to_back;
-            self.back_to_back = hard;
+            back_to_back = hard && self.b2b > 0;
+            self.b2b = match hard {
+                true => self.b2b.saturating_add(1),
+                false => 0,
+            };
+            self.combo = self.combo.saturating_add(1);
         } else {
             self.combo = 0;
         }
-        PlacementInfo {
+        debug_assert_eq!(self.heights, self.board.heights());
+        let mut info = PlacementInfo {
             placement,
             lines_cleared: cleared_mask.count_ones(),
             combo: self.combo as u32,
             back_to_back,
+            b2b_chain: self.b2b,
             perfect_clear: self.board.cols.iter().all(|&c| c == 0),
-        }
+            hold,
+            garbage_cleared,
+            attack: 0,
+        };
+        info.attack = attack.attack(&info);
+        info
     }
 }
 
@@ -331,3 +751,285 @@ fn clear_lines(col: &mut u64, mut lines: u64) {
         lines >>= 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::movegen::find_moves;
+
+    fn empty_state(reserve: Piece) -> GameState {
+        GameState::new(Board::default(), reserve, EnumSet::all(), 0, 0)
+    }
+
+    #[test]
+    fn heights_follow_random_play() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut state = empty_state(Piece::T);
+        for _ in 0..2000 {
+            let next = state
+                .bag
+                .iter()
+                .nth(rng.gen_range(0..state.bag.len()))
+                .unwrap();
+            let piece = match rng.gen_bool(0.5) {
+                true => next,
+                false => state.reserve,
+            };
+            let moves = find_moves(&state.board, piece);
+            if moves.is_empty() || state.heights().iter().any(|&h| h > 16) {
+                state = empty_state(next);
+                continue;
+            }
+            let (mv, _) = moves[rng.gen_range(0..moves.len())];
+            state.advance(next, mv);
+            assert_eq!(state.heights(), state.board.heights());
+        }
+    }
+
+    #[test]
+    fn heights_follow_set_board() {
+        let mut state = empty_state(Piece::T);
+        let mut board = Board::from_ascii("#.........\n.......#..\n##.#######");
+        state.set_board(board);
+        assert_eq!(state.heights(), [3, 1, 0, 1, 1, 1, 1, 2, 1, 1]);
+        board.add_garbage(2, 2);
+        state.set_board(board);
+        assert_eq!(state.heights(), board.heights());
+        assert_eq!(state.heights(), [5, 3, 0, 3, 3, 3, 3, 4, 3, 3]);
+    }
+
+    #[test]
+    fn combo_counts_consecutive_clears() {
+        let slot = Board::from_ascii("####..####");
+        let o = |x, y| Placement {
+            location: PieceLocation {
+                piece: Piece::O,
+                rotation: Rotation::North,
+                x,
+                y,
+            },
+            spin: Spin::None,
+        };
+        let mut state = GameState::new(slot, Piece::O, EnumSet::all(), 0, 0);
+        let clear = state.advance(Piece::O, o(4, 0));
+        assert_eq!((clear.lines_cleared, clear.combo, state.combo), (1, 1, 1));
+
+        state.set_board(slot);
+        let clear = state.advance(Piece::O, o(4, 0));
+        assert_eq!((clear.lines_cleared, clear.combo, state.combo), (1, 2, 2));
+
+        let stack = state.advance(Piece::O, o(0, 0));
+        assert_eq!((stack.lines_cleared, stack.combo, state.combo), (0, 0, 0));
+    }
+
+    #[test]
+    fn hold_sequence_tracks_board_bag_and_reserve() {
+        use Piece::*;
+        let at = |piece, x, y| Placement {
+            location: PieceLocation {
+                piece,
+                rotation: Rotation::North,
+                x,
+                y,
+            },
+            spin: Spin::None,
+        };
+        // T is the current piece of a fresh bag and hold is empty, so T is the reserve
+        let mut state = GameState::new(Board::default(), T, EnumSet::all() - T, 0, 0);
+        let steps = [
+            // playing T holds I, the first piece of the queue
+            (I, at(T, 1, 0), true, I, J | L | O | S | Z),
+            (O, at(O, 3, 0), false, I, J | L | S | Z),
+            (L, at(I, 6, 0), true, L, J | S | Z),
+            (J, at(L, 6, 1), true, J, S | Z),
+            (S, at(J, 1, 2), true, S, EnumSet::only(Z)),
+            // the last piece of the bag refills it
+            (Z, at(Z, 4, 2), false, S, EnumSet::all()),
+        ];
+        for (next, placement, hold, reserve, bag) in steps {
+            let info = state.advance(next, placement);
+            assert_eq!(info.hold, hold, "{:?}", placement);
+            assert_eq!(state.reserve, reserve, "{:?}", placement);
+            assert_eq!(state.bag, bag, "{:?}", placement);
+        }
+        let expected = Board::from_ascii("#..##.....\n###.##.#..\n.#.#####..\n#########.");
+        assert!(
+            state.board == expected,
+            "{}",
+            expected.diff_string(&state.board)
+        );
+        assert_eq!(state.heights(), [4, 3, 3, 4, 4, 3, 2, 3, 1, 0]);
+    }
+
+    #[test]
+    fn classic_draws_follow_the_last_piece() {
+        use Piece::*;
+        // a repeat is 2 of 56 outcomes and each other piece 9
+        let total: f32 = EnumSet::<Piece>::all()
+            .iter()
+            .map(|p| p.draw_weight(Some(T)))
+            .sum();
+        assert_eq!(total, 56.0);
+        assert_eq!(T.draw_weight(Some(T)), 2.0);
+        assert_eq!(T.draw_weight(None), I.draw_weight(None));
+
+        let drop = |piece, x| Placement {
+            location: PieceLocation {
+                piece,
+                rotation: Rotation::North,
+                x,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+        let mut state = empty_state(T);
+        state.advance(O, drop(T, 1));
+        assert_eq!(state.last_drawn, None);
+        state.memoryless = true;
+        state.last_drawn = Some(O);
+        state.advance(S, drop(O, 5));
+        assert_eq!(state.last_drawn, Some(S));
+    }
+
+    /// The hole column of each of the bottom `lines` rows, which must have exactly one hole each.
+    fn hole_columns(board: &Board, lines: u32) -> Vec<usize> {
+        (0..lines)
+            .map(|y| {
+                let holes: Vec<_> = (0..10).filter(|&x| board.cols[x] & 1 << y == 0).collect();
+                assert_eq!(holes.len(), 1, "row {} has holes {:?}", y, holes);
+                holes[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_garbage_has_one_hole_column() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut board = Board::default();
+        board.insert_garbage(8, GarbagePattern::Clean { column: 3 }, &mut rng);
+        assert_eq!(hole_columns(&board, 8), [3; 8]);
+
+        let mut board = Board::default();
+        board.insert_garbage(8, GarbagePattern::CleanRandom, &mut rng);
+        let holes = hole_columns(&board, 8);
+        assert!(holes.iter().all(|&x| x == holes[0]));
+    }
+
+    #[test]
+    fn messy_garbage_moves_holes() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut board = Board::default();
+        let always = GarbagePattern::Messy { change_chance: 1.0 };
+        board.insert_garbage(20, always, &mut rng);
+        let holes = hole_columns(&board, 20);
+        assert!(holes.windows(2).all(|w| w[0] != w[1]));
+
+        let mut board = Board::default();
+        let never = GarbagePattern::Messy { change_chance: 0.0 };
+        board.insert_garbage(20, never, &mut rng);
+        let holes = hole_columns(&board, 20);
+        assert!(holes.iter().all(|&x| x == holes[0]));
+    }
+
+    #[test]
+    fn garbage_pattern_validation() {
+        assert!(GarbagePattern::Clean { column: 9 }.validate().is_ok());
+        assert!(GarbagePattern::Clean { column: 10 }.validate().is_err());
+        assert!(GarbagePattern::Messy { change_chance: 0.5 }
+            .validate()
+            .is_ok());
+        for change_chance in [-0.1, 1.1, f64::NAN] {
+            assert!(GarbagePattern::Messy { change_chance }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn attack_table_counts_each_kind_of_clear() {
+        let clear = |piece, spin, lines, combo, back_to_back| PlacementInfo {
+            placement: Placement {
+                location: PieceLocation {
+                    piece,
+                    rotation: Rotation::North,
+                    x: 4,
+                    y: 0,
+                },
+                spin,
+            },
+            lines_cleared: lines,
+            combo,
+            back_to_back,
+            b2b_chain: 0,
+            perfect_clear: false,
+            hold: false,
+            garbage_cleared: 0,
+            attack: 0,
+        };
+        let table = AttackTable::default();
+        let tsd = clear(Piece::T, Spin::Full, 2, 1, false);
+        assert_eq!(table.attack(&tsd), 4);
+        assert_eq!(
+            table.attack(&PlacementInfo {
+                back_to_back: true,
+                ..tsd
+            }),
+            5
+        );
+        assert_eq!(table.attack(&clear(Piece::T, Spin::Mini, 1, 1, false)), 0);
+        assert_eq!(table.attack(&clear(Piece::I, Spin::None, 4, 1, false)), 4);
+        // an immobile I clearing 4 lines still counts as a tetris
+        assert_eq!(table.attack(&clear(Piece::I, Spin::Full, 4, 1, false)), 4);
+        assert_eq!(table.attack(&clear(Piece::L, Spin::None, 2, 3, false)), 2);
+        // combos past the end of the table use its last entry
+        assert_eq!(table.attack(&clear(Piece::L, Spin::None, 1, 30, false)), 5);
+        assert_eq!(table.attack(&clear(Piece::L, Spin::None, 0, 0, false)), 0);
+        assert_eq!(
+            table.attack(&PlacementInfo {
+                perfect_clear: true,
+                ..clear(Piece::I, Spin::None, 4, 1, true)
+            }),
+            10
+        );
+
+        let custom = AttackTable {
+            back_to_back: 2,
+            combo: vec![],
+            ..table
+        };
+        let b2b_tetris = clear(Piece::I, Spin::None, 4, 5, true);
+        assert_eq!(custom.attack(&b2b_tetris), 6);
+    }
+
+    #[test]
+    fn tetris_cancels_incoming_garbage() {
+        // the leftover cell on top keeps this from being a perfect clear
+        let board = Board::from_ascii("#.........\n#########.\n#########.\n#########.\n#########.");
+        let tetris = Placement {
+            location: PieceLocation {
+                piece: Piece::I,
+                rotation: Rotation::West,
+                x: 9,
+                y: 1,
+            },
+            spin: Spin::None,
+        };
+        let mut state = GameState::new(board, Piece::I, EnumSet::all(), 0, 0);
+        let info = state.advance(Piece::I, tetris);
+        assert_eq!(info.lines_cleared, 4);
+        assert_eq!(info.attack, 4);
+
+        let mut incoming = 4;
+        assert_eq!(info.cancel_garbage(&mut incoming), 0);
+        assert_eq!(incoming, 0);
+
+        let mut incoming = 6;
+        assert_eq!(info.cancel_garbage(&mut incoming), 0);
+        assert_eq!(incoming, 2);
+
+        let mut incoming = 1;
+        assert_eq!(info.cancel_garbage(&mut incoming), 3);
+        assert_eq!(incoming, 0);
+    }
+}
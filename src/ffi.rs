@@ -0,0 +1,432 @@
+//! A C interface for embedding the bot in frontends not written in Rust, built as part of the
+//! `staticlib` and `cdylib` outputs. Its header, `cold_clear_2.h`, is regenerated with
+//! `cbindgen --config cbindgen.toml --output cold_clear_2.h` after changing this module, and
+//! `examples/ffi.c` plays a short game through it.
+//!
+//! Pieces are numbered I, O, T, L, J, S, Z from 0 to 6, rotations North, West, South, East from 0
+//! to 3 and spins None, Mini, Full from 0 to 2, the same orders as in `data`.
+//!
+//! A `CC2Bot` runs its own search threads. Its functions may be called from any thread, but not
+//! from two threads at once for the same bot. All memory passed in stays owned by the caller and
+//! is only read during the call; nothing returned needs to be freed except the bot itself.
+//!
+//! A panic can't unwind into C, so one inside the bot aborts the process. Arguments are checked
+//! before they reach the bot so that invalid input is reported by a return value instead.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use enumset::EnumSet;
+
+use crate::data::{Board, Piece, PieceLocation, Placement, Rotation, Spin};
+use crate::sync::BotSyncronizer;
+use crate::tbp::{self, Randomizer};
+use crate::{
+    add_first_piece, create_bot, read_config, spawn_workers, start_hold_empty, tbp_moves, BotConfig,
+};
+
+/// Used for `CC2Start::hold` when the hold slot is empty.
+pub const CC2_NO_PIECE: u8 = 255;
+
+pub const CC2_RANDOMIZER_UNKNOWN: u8 = 0;
+pub const CC2_RANDOMIZER_SEVEN_BAG: u8 = 1;
+pub const CC2_RANDOMIZER_MEMORYLESS: u8 = 2;
+pub const CC2_RANDOMIZER_CLASSIC: u8 = 3;
+
+/// Board cell value marking garbage, which only matters with the `garbage-plane` feature. Any
+/// other nonzero value is an ordinary filled cell.
+pub const CC2_GARBAGE_CELL: u8 = 8;
+
+pub struct CC2Bot {
+    bot: Arc<BotSyncronizer>,
+    config: Arc<BotConfig>,
+    workers: Vec<JoinHandle<()>>,
+    waiting_on_first_piece: Option<tbp::Start>,
+    hold_empty: bool,
+}
+
+/// The position to start a game from, as in a TBP `start` message.
+#[repr(C)]
+pub struct CC2Start {
+    /// Cells row by row from the bottom, 10 per row. Zero is empty.
+    pub board: [u8; 400],
+    /// `CC2_NO_PIECE` if the hold slot is empty.
+    pub hold: u8,
+    /// The current piece followed by the next pieces. May be null if `queue_len` is 0.
+    pub queue: *const u8,
+    pub queue_len: usize,
+    pub combo: u32,
+    pub back_to_back: bool,
+    /// One of the `CC2_RANDOMIZER_` constants.
+    pub randomizer: u8,
+    /// For the 7-bag randomizer, the pieces left in the current bag after the queue, with bit `n`
+    /// set for piece `n`.
+    pub bag_state: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct CC2Move {
+    pub piece: u8,
+    pub rotation: u8,
+    pub x: i8,
+    pub y: i8,
+    pub spin: u8,
+    /// Whether playing this move means swapping the current piece with hold.
+    pub hold: bool,
+}
+
+/// Creates a bot and starts its search threads. `config_json` is a nul-terminated JSON bot
+/// configuration, or null for the default one. Returns null if the configuration is invalid.
+///
+/// # Safety
+///
+/// `config_json` must be null or point to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_create(config_json: *const c_char) -> *mut CC2Bot {
+    let config = match config_json.is_null() {
+        true => Arc::new(BotConfig::default()),
+        false => match read_config(CStr::from_ptr(config_json).to_bytes(), false) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("error: invalid config: {}", e);
+                return std::ptr::null_mut();
+            }
+        },
+    };
+    let bot = Arc::new(BotSyncronizer::new());
+    let workers = spawn_workers(&bot, config.threads);
+    Box::into_raw(Box::new(CC2Bot {
+        bot,
+        config,
+        workers,
+        waiting_on_first_piece: None,
+        hold_empty: false,
+    }))
+}
+
+/// Stops the search threads and frees the bot.
+///
+/// # Safety
+///
+/// `bot` must have come from `cc2_create` and must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_destroy(bot: *mut CC2Bot) {
+    if bot.is_null() {
+        return;
+    }
+    let bot = Box::from_raw(bot);
+    bot.bot.shutdown();
+    for worker in bot.workers {
+        let _ = worker.join();
+    }
+}
+
+/// Starts a new game. Returns false, leaving the previous game running, if `start` contains an
+/// invalid piece or randomizer.
+///
+/// # Safety
+///
+/// `bot` must be a live bot and `start` must point to a valid `CC2Start` whose `queue` holds
+/// `queue_len` pieces.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_start(bot: *mut CC2Bot, start: *const CC2Start) -> bool {
+    let bot = &mut *bot;
+    let start = &*start;
+    let queue: &[u8] = match start.queue_len {
+        0 => &[],
+        len => std::slice::from_raw_parts(start.queue, len),
+    };
+    let queue = match queue.iter().map(|&p| piece(p)).collect::<Option<Vec<_>>>() {
+        Some(queue) => queue,
+        None => return false,
+    };
+    let hold = match start.hold {
+        CC2_NO_PIECE => None,
+        p => match piece(p) {
+            Some(p) => Some(p),
+            None => return false,
+        },
+    };
+    let randomizer = match start.randomizer {
+        CC2_RANDOMIZER_UNKNOWN => Randomizer::Unknown,
+        CC2_RANDOMIZER_SEVEN_BAG => match EnumSet::try_from_u8(start.bag_state) {
+            Some(bag_state) => Randomizer::SevenBag { bag_state },
+            None => return false,
+        },
+        CC2_RANDOMIZER_MEMORYLESS => Randomizer::Memoryless,
+        CC2_RANDOMIZER_CLASSIC => Randomizer::Classic,
+        _ => return false,
+    };
+    let mut board = Board::default();
+    for (i, &cell) in start.board.iter().enumerate() {
+        match cell {
+            0 => {}
+            CC2_GARBAGE_CELL => board.fill(i % 10, i / 10, 'G'),
+            _ => board.fill(i % 10, i / 10, '#'),
+        }
+    }
+
+    bot.begin(tbp::Start {
+        board,
+        queue,
+        hold,
+        combo: start.combo,
+        back_to_back: start.back_to_back,
+        b2b_chain: None,
+        randomizer,
+    });
+    true
+}
+
+/// Ends the current game. Search stops until the next `cc2_start`.
+///
+/// # Safety
+///
+/// `bot` must be a live bot.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_stop(bot: *mut CC2Bot) {
+    let bot = &mut *bot;
+    bot.bot.stop();
+    bot.waiting_on_first_piece = None;
+}
+
+/// Adds a piece to the end of the queue. Returns false if `piece_id` isn't a piece.
+///
+/// # Safety
+///
+/// `bot` must be a live bot.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_new_piece(bot: *mut CC2Bot, piece_id: u8) -> bool {
+    let bot = &mut *bot;
+    let piece = match piece(piece_id) {
+        Some(piece) => piece,
+        None => return false,
+    };
+    if let Some(mut start) = bot.waiting_on_first_piece.take() {
+        add_first_piece(&mut start, piece, &bot.config);
+        bot.begin(start);
+    } else {
+        bot.bot.new_piece(piece);
+    }
+    true
+}
+
+/// Plays a move, which need not be one the bot suggested. Returns false, leaving the game as it
+/// was, if `mv` isn't a valid move, no game is in progress, or `mv.piece` and `mv.hold` don't
+/// match the current, hold and next pieces. Also returns false if the move overlaps the board,
+/// which ends the game.
+///
+/// # Safety
+///
+/// `bot` must be a live bot and `mv` must point to a valid `CC2Move`.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_play(bot: *mut CC2Bot, mv: *const CC2Move) -> bool {
+    let bot = &mut *bot;
+    let mv = &*mv;
+    let (piece, rotation, spin) = match (piece(mv.piece), rotation(mv.rotation), spin(mv.spin)) {
+        (Some(piece), Some(rotation), Some(spin)) => (piece, rotation, spin),
+        _ => return false,
+    };
+    let placement = Placement {
+        location: PieceLocation {
+            piece,
+            rotation,
+            x: mv.x,
+            y: mv.y,
+        },
+        spin,
+    };
+    let (state, queue) = match bot.bot.state() {
+        Some(state) => state,
+        None => return false,
+    };
+    // advancing always takes the next piece out of the queue, even when the reserve is played
+    let next = match queue.first() {
+        Some(&next) => next,
+        None => return false,
+    };
+    if piece != state.reserve && piece != next {
+        return false;
+    }
+    let current = match bot.hold_empty {
+        true => state.reserve,
+        false => next,
+    };
+    // swapping two of the same piece makes no difference, so either is fine then
+    if state.reserve != next && mv.hold != (piece != current) {
+        return false;
+    }
+    let held = state.reserve != piece;
+    if bot.bot.advance(placement).is_err() {
+        return false;
+    }
+    if held {
+        bot.hold_empty = false;
+    }
+    true
+}
+
+/// Writes up to `capacity` suggested moves, best first, to `out` and returns how many were
+/// written. With `think_ms` above 0 this first waits until search has run that long since the
+/// last move or reached the node limit. Returns 0 if no game is running or there are no moves.
+///
+/// # Safety
+///
+/// `bot` must be a live bot and `out` must have room for `capacity` moves.
+#[no_mangle]
+pub unsafe extern "C" fn cc2_suggest(
+    bot: *mut CC2Bot,
+    think_ms: u32,
+    out: *mut CC2Move,
+    capacity: usize,
+) -> usize {
+    let bot = &mut *bot;
+    let suggestion = match think_ms {
+        0 => bot.bot.suggest(),
+        ms => bot.bot.suggest_after(Duration::from_millis(ms as u64)),
+    };
+    let moves = match suggestion {
+        Some((moves, _, _)) => moves,
+        None => return 0,
+    };
+    let state = bot.bot.state();
+    let moves = tbp_moves(&bot.config, moves, state.as_ref(), bot.hold_empty);
+    let count = moves.len().min(capacity);
+    for (i, mv) in moves.into_iter().take(count).enumerate() {
+        let location = mv.placement.location;
+        out.add(i).write(CC2Move {
+            piece: location.piece as u8,
+            rotation: location.rotation as u8,
+            x: location.x,
+            y: location.y,
+            spin: mv.placement.spin as u8,
+            hold: mv.hold,
+        });
+    }
+    count
+}
+
+impl CC2Bot {
+    fn begin(&mut self, start: tbp::Start) {
+        let empty = start_hold_empty(&start, &self.config);
+        self.waiting_on_first_piece = match create_bot(start, self.config.clone()) {
+            Ok(new_bot) => {
+                self.bot.start(new_bot);
+                self.hold_empty = empty;
+                None
+            }
+            Err(start) => Some(*start),
+        };
+    }
+}
+
+fn piece(id: u8) -> Option<Piece> {
+    use Piece::*;
+    [I, O, T, L, J, S, Z].get(id as usize).copied()
+}
+
+fn rotation(id: u8) -> Option<Rotation> {
+    use Rotation::*;
+    [North, West, South, East].get(id as usize).copied()
+}
+
+fn spin(id: u8) -> Option<Spin> {
+    [Spin::None, Spin::Mini, Spin::Full]
+        .get(id as usize)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(queue: &[u8]) -> CC2Start {
+        CC2Start {
+            board: [0; 400],
+            hold: CC2_NO_PIECE,
+            queue: queue.as_ptr(),
+            queue_len: queue.len(),
+            combo: 0,
+            back_to_back: false,
+            randomizer: CC2_RANDOMIZER_UNKNOWN,
+            bag_state: 0,
+        }
+    }
+
+    fn drop_at(piece: Piece, x: i8, hold: bool) -> CC2Move {
+        CC2Move {
+            piece: piece as u8,
+            rotation: 0,
+            x,
+            y: 0,
+            spin: 0,
+            hold,
+        }
+    }
+
+    #[test]
+    fn plays_a_game_and_rejects_mismatched_moves() {
+        use Piece::*;
+        let queue = [T, I, O, L, J, S, Z].map(|p| p as u8);
+        unsafe {
+            let bot = cc2_create(std::ptr::null());
+            assert!(cc2_start(bot, &start(&queue)));
+
+            // T is current and I is next, so playing I means holding T
+            assert!(!cc2_play(bot, &drop_at(O, 4, false)));
+            assert!(!cc2_play(bot, &drop_at(I, 4, false)));
+            assert!(!cc2_play(bot, &drop_at(T, 4, true)));
+
+            for _ in 0..5 {
+                let mut moves = [CC2Move::default(); 4];
+                let count = cc2_suggest(bot, 20, moves.as_mut_ptr(), moves.len());
+                assert!(count > 0);
+                assert!(cc2_play(bot, &moves[0]));
+            }
+            cc2_destroy(bot);
+        }
+    }
+
+    #[test]
+    fn play_without_a_next_piece_is_rejected() {
+        let queue = [Piece::T as u8];
+        unsafe {
+            let bot = cc2_create(std::ptr::null());
+            assert!(cc2_start(bot, &start(&queue)));
+            assert!(!cc2_play(bot, &drop_at(Piece::T, 4, false)));
+
+            // it still plays once the next piece arrives
+            assert!(cc2_new_piece(bot, Piece::I as u8));
+            assert!(cc2_play(bot, &drop_at(Piece::T, 4, false)));
+            cc2_destroy(bot);
+        }
+    }
+
+    #[test]
+    fn current_in_hold_tracks_the_hold_slot() {
+        use Piece::*;
+        let mut config = serde_json::to_value(BotConfig::default()).unwrap();
+        config["hold_convention"] = serde_json::json!("current_in_hold");
+        let config = std::ffi::CString::new(config.to_string()).unwrap();
+        let queue = [I, O, L, J, S, Z].map(|p| p as u8);
+        unsafe {
+            let bot = cc2_create(config.as_ptr());
+            assert!(!bot.is_null());
+            let start = CC2Start {
+                hold: T as u8,
+                ..start(&queue)
+            };
+            assert!(cc2_start(bot, &start));
+
+            // holding T to play I, after which O is the current piece
+            assert!(cc2_play(bot, &drop_at(I, 4, true)));
+            assert!(!cc2_play(bot, &drop_at(O, 0, true)));
+            assert!(cc2_play(bot, &drop_at(O, 0, false)));
+            cc2_destroy(bot);
+        }
+    }
+}
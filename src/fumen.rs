@@ -0,0 +1,96 @@
+//! Decoding of the board from fumen (https://harddrop.com/fumen/) strings, so positions can be
+//! shared as the same codes people already pass around. Only the field of the first page is read;
+//! pieces, comments and later pages are ignored.
+
+use std::fmt;
+
+use crate::data::Board;
+
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Fumen fields are 23 visible rows plus the garbage row below the floor, 10 cells each.
+const FIELD_ROWS: usize = 24;
+const FIELD_CELLS: usize = FIELD_ROWS * 10;
+
+/// Cell values are stored offset by this so the difference from the previous page fits unsigned.
+const DIFF_OFFSET: u32 = 8;
+
+/// The pieces cells are drawn as, by their fumen cell value.
+const CELLS: [char; 9] = ['_', 'I', 'L', 'O', 'Z', 'T', 'J', 'S', 'G'];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FumenError {
+    /// The string doesn't start with `v115@`.
+    UnsupportedVersion,
+    /// A character outside of fumen's base64 alphabet.
+    InvalidCharacter(char),
+    /// The string ended before the whole field was read.
+    Truncated,
+    /// The field data describes more cells or a different cell value than fumen allows.
+    InvalidField,
+}
+
+impl fmt::Display for FumenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FumenError::UnsupportedVersion => write!(f, "unsupported fumen version"),
+            FumenError::InvalidCharacter(c) => write!(f, "invalid character {:?} in fumen", c),
+            FumenError::Truncated => write!(f, "fumen ended before the field did"),
+            FumenError::InvalidField => write!(f, "invalid field data in fumen"),
+        }
+    }
+}
+
+impl std::error::Error for FumenError {}
+
+pub(crate) fn decode(fumen: &str) -> Result<Board, FumenError> {
+    let data = fumen
+        .trim()
+        .strip_prefix("v115@")
+        .ok_or(FumenError::UnsupportedVersion)?;
+
+    // Long fumens have a `?` inserted every 47 characters for line wrapping
+    let mut values = data.chars().filter(|&c| c != '?').map(|c| {
+        ALPHABET
+            .iter()
+            .position(|&a| a as char == c)
+            .map(|v| v as u32)
+            .ok_or(FumenError::InvalidCharacter(c))
+    });
+    let mut poll = |digits: u32| -> Result<u32, FumenError> {
+        let mut value = 0;
+        for i in 0..digits {
+            value += values.next().ok_or(FumenError::Truncated)?? * 64u32.pow(i);
+        }
+        Ok(value)
+    };
+
+    // The first page is a diff against an empty field, so each run of cells holds exactly the
+    // difference stored for it.
+    let mut field = [0; FIELD_CELLS];
+    let mut cell = 0;
+    while cell < FIELD_CELLS {
+        let value = poll(2)?;
+        let diff = value / FIELD_CELLS as u32;
+        let run = value as usize % FIELD_CELLS + 1;
+        if diff < DIFF_OFFSET || cell + run > FIELD_CELLS {
+            return Err(FumenError::InvalidField);
+        }
+        field[cell..cell + run].fill(diff - DIFF_OFFSET);
+        cell += run;
+    }
+
+    let mut board = Board::default();
+    // Skip the garbage row below the floor; the row above it is the bottom of the board.
+    for (row, cells) in field.chunks(10).take(FIELD_ROWS - 1).enumerate() {
+        let y = FIELD_ROWS - 2 - row;
+        for (x, &v) in cells.iter().enumerate() {
+            match CELLS.get(v as usize) {
+                Some('_') => {}
+                Some(&c) => board.fill(x, y, c),
+                None => return Err(FumenError::InvalidField),
+            }
+        }
+    }
+    Ok(board)
+}
@@ -1,125 +1,1108 @@
 use std::convert::Infallible;
+use std::io::BufRead;
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-use bot::{BotConfig, BotOptions};
+use bot::{BotOptions, HoldConvention};
 use enumset::EnumSet;
+use futures::channel::oneshot;
+use futures::future::Either;
 use futures::prelude::*;
+use rand::rngs::SmallRng;
+use rand::{thread_rng, RngCore, SeedableRng};
 use tbp::Randomizer;
 
 use crate::bot::Bot;
-use crate::data::GameState;
+pub use crate::bot::{best_placement_for, score_placements, BotConfig};
+use crate::data::{Board, PlacementInfo};
+pub use crate::data::{GameState, Piece, Placement};
+use crate::replay::ReplayLog;
 use crate::sync::BotSyncronizer;
 use crate::tbp::{BotMessage, FrontendMessage};
 
+// Profiling scopes compile to nothing unless the `profiling` feature is enabled.
+macro_rules! profile_function {
+    () => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_function!();
+    };
+}
+
+macro_rules! profile_scope {
+    ($name:expr) => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_scope!($name);
+    };
+}
+
 mod bot;
 mod dag;
+pub mod ffi;
 mod tbp;
 #[macro_use]
 pub mod data;
+pub mod fumen;
 mod map;
 pub mod movegen;
+pub mod replay;
+pub mod self_play;
 mod sync;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 pub async fn run(
     mut incoming: impl Stream<Item = FrontendMessage> + Unpin,
     mut outgoing: impl Sink<BotMessage, Error = Infallible> + Unpin,
-    config: Arc<BotConfig>,
+    mut config: Arc<BotConfig>,
+    mut replay: Option<ReplayLog>,
 ) {
     outgoing
         .send(BotMessage::Info {
             name: "Cold Clear 2",
             version: concat!(env!("CARGO_PKG_VERSION"), " ", env!("GIT_HASH")),
             author: "MinusKelvin",
-            features: &[],
+            features: features(&config),
         })
         .await
         .unwrap();
 
     let bot = Arc::new(BotSyncronizer::new());
 
-    spawn_workers(&bot);
+    spawn_workers(&bot, config.threads);
 
     let mut waiting_on_first_piece = None;
+    let mut send_paths = false;
+    let mut changes_only = false;
+    // Whether the frontend's hold slot is still empty. Until something is held, the bot's reserve
+    // piece is the current piece rather than the held one.
+    let mut hold_empty = false;
+    // `hold_empty` before each move played, so rewinding can restore it.
+    let mut hold_history = vec![];
+
+    // Signalled by a thread waiting out a `think_ms` suggestion, so the loop can keep handling
+    // messages in the meantime.
+    let mut thinking: Option<oneshot::Receiver<()>> = None;
 
-    while let Some(msg) = incoming.next().await {
+    loop {
+        let msg = match &mut thinking {
+            Some(done) => match future::select(incoming.next(), done).await {
+                Either::Left((msg, _)) => msg,
+                Either::Right(_) => {
+                    thinking = None;
+                    Some(FrontendMessage::Suggest { think_ms: None })
+                }
+            },
+            None => incoming.next().await,
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => break,
+        };
         match msg {
             FrontendMessage::Start(start) => {
-                if start.hold.is_none() && start.queue.is_empty() {
-                    waiting_on_first_piece = Some(start);
-                } else {
-                    bot.start(create_bot(start, config.clone()));
-                }
+                thinking = None;
+                let empty = start_hold_empty(&start, &config);
+                waiting_on_first_piece = match create_bot(start, config.clone()) {
+                    Ok(new_bot) => {
+                        bot.start(new_bot);
+                        hold_empty = empty;
+                        hold_history.clear();
+                        None
+                    }
+                    Err(start) => Some(*start),
+                };
             }
             FrontendMessage::Stop => {
+                thinking = None;
                 bot.stop();
                 waiting_on_first_piece = None;
             }
-            FrontendMessage::Suggest => {
-                if let Some((moves, move_info)) = bot.suggest() {
+            FrontendMessage::Suggest { think_ms: Some(ms) } => {
+                let (done, wait) = oneshot::channel();
+                let bot = bot.clone();
+                std::thread::spawn(move || {
+                    bot.wait_to_suggest(Duration::from_millis(ms));
+                    let _ = done.send(());
+                });
+                thinking = Some(wait);
+            }
+            FrontendMessage::Suggest { think_ms: None } => {
+                thinking = None;
+                if let Some((moves, move_info, changed)) = bot.suggest() {
+                    if changes_only && !changed {
+                        outgoing.send(BotMessage::Unchanged).await.unwrap();
+                        continue;
+                    }
+                    let state = bot.state();
+                    let mut moves = tbp_moves(&config, moves, state.as_ref(), hold_empty);
+                    if let (true, Some(mv), Some((state, _))) =
+                        (send_paths, moves.first_mut(), &state)
+                    {
+                        let rules = config.move_rules();
+                        mv.path = movegen::path_to(&state.board, &mv.placement, &rules)
+                            .map(|path| path.inputs);
+                    }
+                    if let (Some(replay), Some((state, queue))) = (&mut replay, &state) {
+                        replay.suggestion(state, queue, &moves, &move_info);
+                    }
                     outgoing
                         .send(BotMessage::Suggestion { moves, move_info })
                         .await
                         .unwrap();
+                } else {
+                    let message = no_game_message(waiting_on_first_piece.is_some());
+                    outgoing.send(BotMessage::Error { message }).await.unwrap();
                 }
             }
             FrontendMessage::Play { mv } => {
-                bot.advance(mv);
+                thinking = None;
+                if let Some(replay) = &mut replay {
+                    replay.play(mv);
+                }
+                let held = bot
+                    .state()
+                    .is_some_and(|(s, _)| s.reserve != mv.location.piece);
+                match bot.advance(mv) {
+                    Ok(()) => {
+                        hold_history.push(hold_empty);
+                        if held {
+                            hold_empty = false;
+                        }
+                    }
+                    Err(message) => outgoing.send(BotMessage::Error { message }).await.unwrap(),
+                }
+                #[cfg(feature = "profiling")]
                 puffin::GlobalProfiler::lock().new_frame();
             }
             FrontendMessage::NewPiece { piece } => {
                 if let Some(mut start) = waiting_on_first_piece.take() {
-                    if let Randomizer::SevenBag { bag_state } = &mut start.randomizer {
-                        if bag_state.is_empty() {
-                            *bag_state = EnumSet::all();
+                    add_first_piece(&mut start, piece, &config);
+                    let empty = start_hold_empty(&start, &config);
+                    match create_bot(start, config.clone()) {
+                        Ok(new_bot) => {
+                            bot.start(new_bot);
+                            hold_empty = empty;
+                            hold_history.clear();
                         }
-                        bag_state.remove(piece);
+                        Err(start) => waiting_on_first_piece = Some(*start),
                     }
-                    start.queue.push(piece);
-                    bot.start(create_bot(start, config.clone()));
                 } else {
                     bot.new_piece(piece);
                 }
             }
-            FrontendMessage::Rules => {
+            FrontendMessage::Rules {
+                features,
+                kick_table,
+            } => {
+                if let Some(kick_table) = kick_table {
+                    config = Arc::new(BotConfig {
+                        kick_table,
+                        ..(*config).clone()
+                    });
+                }
+                send_paths = features.iter().any(|f| f == "move_path");
+                changes_only = features.iter().any(|f| f == "suggest_changes_only");
                 outgoing.send(BotMessage::Ready).await.unwrap();
             }
+            FrontendMessage::GetConfig => {
+                outgoing
+                    .send(BotMessage::Config {
+                        config: Box::new((*config).clone()),
+                    })
+                    .await
+                    .unwrap();
+            }
+            FrontendMessage::GetStats => {
+                outgoing
+                    .send(BotMessage::Stats(bot.game_stats()))
+                    .await
+                    .unwrap();
+            }
+            FrontendMessage::Analyze => {
+                if let Some((pv, moves)) = bot.analysis() {
+                    let pv = pv_steps(pv, bot.state().as_ref(), hold_empty);
+                    let moves = moves
+                        .into_iter()
+                        .map(|(placement, eval, visits)| tbp::MoveStats {
+                            placement,
+                            eval,
+                            visits,
+                        })
+                        .collect();
+                    outgoing
+                        .send(BotMessage::Analysis { pv, moves })
+                        .await
+                        .unwrap();
+                } else {
+                    let message = no_game_message(waiting_on_first_piece.is_some());
+                    outgoing.send(BotMessage::Error { message }).await.unwrap();
+                }
+            }
+            FrontendMessage::SetWeights {
+                freestyle_weights,
+                sprint_weights,
+                downstack_weights,
+            } => match config.with_weights(freestyle_weights, sprint_weights, downstack_weights) {
+                Ok(new) => config = Arc::new(new),
+                Err(e) => {
+                    eprintln!("warning: rejecting new weights: {}", e);
+                    let message = format!("rejected new weights: {}", e);
+                    outgoing.send(BotMessage::Error { message }).await.unwrap();
+                }
+            },
+            FrontendMessage::Garbage { column, .. } if column >= 10 => {
+                let message = format!("garbage column {} is off the board", column);
+                outgoing.send(BotMessage::Error { message }).await.unwrap();
+            }
+            FrontendMessage::Garbage { column, lines } => {
+                if let Some(replay) = &mut replay {
+                    replay.garbage(column, lines);
+                }
+                bot.add_garbage(column, lines);
+            }
+            FrontendMessage::Rewind => {
+                if bot.rewind() {
+                    if let Some(empty) = hold_history.pop() {
+                        hold_empty = empty;
+                    }
+                } else {
+                    eprintln!("warning: cannot rewind, no move to undo or history is not kept");
+                }
+            }
+            FrontendMessage::SetNodeLimit { limit } => {
+                config = Arc::new(BotConfig {
+                    node_limit: limit,
+                    ..(*config).clone()
+                });
+                bot.set_node_limit(config.nodes_per_move());
+            }
+            FrontendMessage::Ping => {
+                outgoing.send(BotMessage::Pong).await.unwrap();
+            }
             FrontendMessage::Quit => break,
-            FrontendMessage::Unknown => {}
+            FrontendMessage::Unknown => {
+                let message = "unknown message type".to_owned();
+                outgoing.send(BotMessage::Error { message }).await.unwrap();
+            }
+            FrontendMessage::Invalid(e) => {
+                eprintln!("warning: ignoring malformed message: {}", e);
+                let message = format!("malformed message: {}", e);
+                outgoing.send(BotMessage::Error { message }).await.unwrap();
+            }
         }
     }
 }
 
-fn create_bot(mut start: tbp::Start, config: Arc<BotConfig>) -> Bot {
+/// Reads line-delimited TBP messages from `input` on a thread of its own, so that `run` can
+/// keep handling a `think_ms` suggestion while the read blocks. The stream ends when the input
+/// closes or fails, which `run` treats the same as a quit message. Blank lines are skipped.
+pub fn read_messages(
+    input: impl BufRead + Send + 'static,
+) -> impl Stream<Item = FrontendMessage> + Unpin {
+    let (send, messages) = futures::channel::mpsc::unbounded();
+    std::thread::spawn(move || {
+        for line in input.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("error: failed to read input: {}", e);
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if send.unbounded_send(parse_message(&line)).is_err() {
+                // `run` has finished
+                break;
+            }
+        }
+    });
+    messages
+}
+
+/// Parses one line of TBP input. Lines which aren't a valid message become
+/// `FrontendMessage::Invalid`, so `run` can tell the frontend what was wrong with them.
+pub fn parse_message(line: &str) -> FrontendMessage {
+    serde_json::from_str(line).unwrap_or_else(|e| FrontendMessage::Invalid(e.to_string()))
+}
+
+/// Explains why there is nothing to suggest or analyze.
+fn no_game_message(waiting_on_first_piece: bool) -> String {
+    match waiting_on_first_piece {
+        true => "the game is waiting for its first piece".to_owned(),
+        false => "no game is in progress".to_owned(),
+    }
+}
+
+/// Reads a bot configuration, warning about any unrecognized fields. In strict mode, unrecognized
+/// fields are an error instead.
+pub fn read_config(reader: impl std::io::Read, strict: bool) -> Result<Arc<BotConfig>, String> {
+    let json = serde_json::from_reader(reader).map_err(|e| e.to_string())?;
+    let (config, unknown) = BotConfig::from_json(json).map_err(|e| e.to_string())?;
+    if strict && !unknown.is_empty() {
+        return Err(format!("unknown config fields: {}", unknown.join(", ")));
+    }
+    for field in unknown {
+        eprintln!("warning: ignoring unknown config field {}", field);
+    }
+    Ok(Arc::new(config))
+}
+
+/// Searches a fixed set of positions on the current thread with a fixed seed, so that the
+/// results are comparable between machines and builds. Returns the total number of nodes searched
+/// and the time it took.
+pub fn bench_search(config: Arc<BotConfig>, nodes_per_position: u64) -> (u64, Duration) {
+    const POSITIONS: [([u64; 10], [Piece; 7]); 3] = {
+        use Piece::*;
+        [
+            ([0; 10], [I, O, T, L, J, S, Z]),
+            (
+                [0b1111, 0b1111, 0b111, 0b111, 0b11, 0b11, 0b1, 0b1, 0, 0b111],
+                [T, S, Z, L, J, I, O],
+            ),
+            (
+                [
+                    0b1101, 0b1011, 0b111, 0b1110, 0b11, 0b1011, 0b111, 0b1101, 0, 0b1111,
+                ],
+                [L, J, T, I, O, S, Z],
+            ),
+        ]
+    };
+
+    let mut rng = SmallRng::seed_from_u64(config.seed.unwrap_or(0));
+    let mut nodes = 0;
+    let start = Instant::now();
+    for (cols, queue) in POSITIONS {
+        let state = GameState::new(Board::new(cols), queue[0], EnumSet::all() - queue[0], 0, 0);
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let mut bot = Bot::new(options, state, &queue[1..]);
+        nodes += bot.search_until(&mut rng, |n| n >= nodes_per_position);
+    }
+    (nodes, start.elapsed())
+}
+
+/// When `analyze` stops searching.
+#[derive(Clone, Copy, Debug)]
+pub enum AnalyzeBudget {
+    /// Stop once this many nodes have been created.
+    Nodes(u64),
+    /// Stop once this much time has passed.
+    Time(Duration),
+}
+
+/// Searches `state` on the current thread until `budget` runs out and returns the suggested
+/// moves, best first. `state.reserve` is the current piece (or the hold piece, if there is one)
+/// and `queue` holds the pieces after it. The result is empty if there are no moves.
+///
+/// Searching is seeded from `config.seed` when it is set, so the same call gives the same result.
+pub fn analyze(
+    config: Arc<BotConfig>,
+    state: GameState,
+    queue: &[Piece],
+    budget: AnalyzeBudget,
+) -> Vec<Placement> {
+    let mut rng: Box<dyn RngCore> = match config.seed {
+        Some(seed) => Box::new(SmallRng::seed_from_u64(seed)),
+        None => Box::new(thread_rng()),
+    };
+    let options = BotOptions {
+        speculate: true,
+        config,
+    };
+    let mut bot = Bot::new(options, state, queue);
+    let start = Instant::now();
+    bot.search_until(&mut rng, |nodes| match budget {
+        AnalyzeBudget::Nodes(limit) => nodes >= limit,
+        AnalyzeBudget::Time(limit) => start.elapsed() >= limit,
+    });
+    bot.suggest()
+}
+
+/// Gives the start message back if it doesn't have a piece to play yet, in which case we wait for
+/// the frontend to send one.
+fn create_bot(mut start: tbp::Start, config: Arc<BotConfig>) -> Result<Bot, Box<tbp::Start>> {
+    if start.hold.is_none() && start.queue.is_empty() {
+        return Err(Box::new(start));
+    }
+
+    // With an empty hold, the current piece takes the reserve slot; suggesting the second piece in
+    // the queue then tells the frontend to hold the first one. Frontends which put the current
+    // piece in the hold slot already give us the reserve piece directly.
+    match config.hold_convention {
+        HoldConvention::Standard if start.hold.is_some() && start.queue.is_empty() => eprintln!(
+            "warning: start has a hold piece but no current piece; \
+            if the frontend sends the current piece as hold, set hold_convention to current_in_hold"
+        ),
+        HoldConvention::CurrentInHold if start.hold.is_none() => eprintln!(
+            "warning: start has no hold piece under hold_convention current_in_hold; \
+            using the first queue piece as the current piece"
+        ),
+        _ => {}
+    }
     let reserve = start.hold.unwrap_or_else(|| start.queue.remove(0));
 
-    let speculate = matches!(start.randomizer, Randomizer::SevenBag { .. });
+    let mut speculate = !matches!(start.randomizer, Randomizer::Unknown);
+    let memoryless = matches!(
+        start.randomizer,
+        Randomizer::Memoryless | Randomizer::Classic
+    );
     let bag = match start.randomizer {
-        Randomizer::Unknown => EnumSet::all(),
-        Randomizer::SevenBag { mut bag_state } => {
-            for &p in start.queue.iter().rev() {
-                if bag_state == EnumSet::all() {
-                    bag_state = EnumSet::empty();
+        Randomizer::Unknown | Randomizer::Memoryless | Randomizer::Classic => EnumSet::all(),
+        Randomizer::SevenBag { bag_state } => {
+            // A current piece taken from the queue was drawn from the bag too, so it is checked
+            // as well, but the bot starts from the bag as it was after drawing it.
+            let current = start.hold.is_none().then_some(reserve);
+            let mut bag = bag_state;
+            let mut after_current = bag_state;
+            for (i, &p) in start.queue.iter().rev().chain(&current).enumerate() {
+                if bag == EnumSet::all() {
+                    bag = EnumSet::empty();
+                }
+                if !bag.insert(p) {
+                    eprintln!(
+                        "warning: queue {:?} is impossible under 7-bag, disabling speculation",
+                        current.iter().chain(&start.queue).collect::<Vec<_>>()
+                    );
+                    speculate = false;
+                    break;
                 }
-                bag_state.insert(p);
+                if i + 1 == start.queue.len() {
+                    after_current = bag;
+                }
+            }
+            match speculate {
+                true => after_current,
+                false => EnumSet::all(),
             }
-            bag_state
         }
     };
 
-    let state = GameState {
-        reserve,
-        back_to_back: start.back_to_back,
-        combo: start.combo.try_into().unwrap_or(255),
-        bag,
-        board: start.board.into(),
+    let b2b = match start.b2b_chain {
+        Some(chain) if (chain > 0) != start.back_to_back => {
+            eprintln!(
+                "warning: b2b_chain {} disagrees with back_to_back {}, ignoring it",
+                chain, start.back_to_back
+            );
+            start.back_to_back as u16
+        }
+        Some(chain) => chain,
+        None => start.back_to_back as u16,
     };
 
-    Bot::new(BotOptions { speculate, config }, state, &start.queue)
+    let combo = start.combo.try_into().unwrap_or(255);
+    let mut state = GameState::new(start.board, reserve, bag, b2b, combo);
+    state.memoryless = memoryless;
+    if matches!(start.randomizer, Randomizer::Classic) {
+        // The piece drawn before the first queue piece is the current one. With a hold piece and
+        // the standard convention it isn't given, so the hold piece stands in for it.
+        state.last_drawn = Some(reserve);
+    }
+
+    Ok(Bot::new(
+        BotOptions { speculate, config },
+        state,
+        &start.queue,
+    ))
+}
+
+/// The features we advertise in our info. Those the frontend can enable in its rules come first,
+/// followed by capabilities that are always on in this build and configuration.
+fn features(config: &BotConfig) -> Vec<&'static str> {
+    let mut features = vec![
+        "move_path",
+        "suggest_changes_only",
+        "speculate",
+        "hold_flag",
+        "memoryless_randomizer",
+        "classic_randomizer",
+    ];
+    if config.rotate_180 {
+        features.push("rotate_180");
+    }
+    if config.all_spin {
+        features.push("all_spin");
+    }
+    if config.keep_history {
+        features.push("rewind");
+    }
+    if cfg!(feature = "garbage-plane") {
+        features.push("garbage_plane");
+    }
+    features
+}
+
+/// Adds a piece to a start message which was waiting for one.
+fn add_first_piece(start: &mut tbp::Start, piece: Piece, config: &BotConfig) {
+    if let Randomizer::SevenBag { bag_state } = &mut start.randomizer {
+        if bag_state.is_empty() {
+            *bag_state = EnumSet::all();
+        }
+        bag_state.remove(piece);
+    }
+    match config.hold_convention {
+        HoldConvention::Standard => start.queue.push(piece),
+        HoldConvention::CurrentInHold => start.hold = Some(piece),
+    }
+}
+
+/// Converts suggested placements to TBP moves, marking those which hold. `state` is the bot's
+/// root state and known queue.
+fn tbp_moves(
+    config: &BotConfig,
+    moves: Vec<Placement>,
+    state: Option<&(GameState, Vec<Piece>)>,
+    hold_empty: bool,
+) -> Vec<tbp::Move> {
+    let current = state.and_then(|(state, queue)| match hold_empty {
+        true => Some(state.reserve),
+        false => queue.first().copied(),
+    });
+    moves
+        .into_iter()
+        .map(|placement| tbp::Move {
+            placement,
+            cells: config.report_cells.then(|| placement.location.cells()),
+            path: None,
+            hold: current.is_some_and(|p| p != placement.location.piece),
+        })
+        .collect()
+}
+
+/// Converts the principal variation to TBP, marking moves which hold the same way `tbp_moves`
+/// does. `state` is the bot's root state and known queue.
+fn pv_steps(
+    pv: Vec<(PlacementInfo, f64)>,
+    state: Option<&(GameState, Vec<Piece>)>,
+    mut hold_empty: bool,
+) -> Vec<tbp::PvStep> {
+    let mut reserve = state.map(|(state, _)| state.reserve);
+    let queue = state.map_or(&[][..], |(_, queue)| queue);
+    pv.into_iter()
+        .enumerate()
+        .map(|(i, (info, eval))| {
+            let piece = info.placement.location.piece;
+            // `info.hold` means the reserve was played, which only holds once something is held
+            let hold = match hold_empty {
+                true => reserve.map_or(!info.hold, |r| r != piece),
+                false => info.hold,
+            };
+            if hold_empty && hold {
+                hold_empty = false;
+            } else if info.hold {
+                // the next piece takes the reserve's place, and past the known queue the
+                // placement doesn't say which piece that was
+                reserve = queue.get(i).copied();
+            }
+            tbp::PvStep {
+                placement: info.placement,
+                eval,
+                lines_cleared: info.lines_cleared,
+                back_to_back: info.back_to_back,
+                b2b_chain: info.b2b_chain,
+                perfect_clear: info.perfect_clear,
+                hold,
+            }
+        })
+        .collect()
+}
+
+/// Whether the frontend's hold slot is empty at the start of a game. Under `current_in_hold`,
+/// `hold` carries the current piece, so the slot starts out empty either way.
+fn start_hold_empty(start: &tbp::Start, config: &BotConfig) -> bool {
+    start.hold.is_none() || config.hold_convention == HoldConvention::CurrentInHold
 }
 
-fn spawn_workers(bot: &Arc<BotSyncronizer>) {
-    for _ in 0..1 {
-        let bot = bot.clone();
-        std::thread::spawn(move || bot.work_loop());
+fn spawn_workers(bot: &Arc<BotSyncronizer>, threads: usize) -> Vec<JoinHandle<()>> {
+    (0..threads.max(1))
+        .map(|worker| {
+            let bot = bot.clone();
+            std::thread::spawn(move || bot.work_loop(worker as u64))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::Receiver;
+
+    use futures::channel::mpsc::UnboundedSender;
+
+    use super::*;
+    use crate::data::{PieceLocation, Rotation, Spin};
+
+    fn start(board: &str, hold: Option<Piece>, queue: &[Piece]) -> tbp::Start {
+        tbp::Start {
+            board: Board::from_ascii(board),
+            queue: queue.to_vec(),
+            hold,
+            combo: 0,
+            back_to_back: false,
+            b2b_chain: None,
+            randomizer: Randomizer::Unknown,
+        }
+    }
+
+    /// Runs the message loop on its own thread, returning a channel to send it messages and one
+    /// its replies arrive on, starting with `info`.
+    fn spawn_run(config: BotConfig) -> (UnboundedSender<FrontendMessage>, Receiver<BotMessage>) {
+        let (to_bot, incoming) = futures::channel::mpsc::unbounded();
+        let (outgoing, from_bot) = std::sync::mpsc::channel();
+        let outgoing = futures::sink::unfold(outgoing, |outgoing, msg| async move {
+            let _ = outgoing.send(msg);
+            Ok::<_, Infallible>(outgoing)
+        });
+        std::thread::spawn(move || {
+            futures::executor::block_on(run(incoming, Box::pin(outgoing), Arc::new(config), None))
+        });
+        assert!(matches!(from_bot.recv().unwrap(), BotMessage::Info { .. }));
+        (to_bot, from_bot)
+    }
+
+    fn search(bot: &Bot, iterations: usize) {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..iterations {
+            bot.do_work(&mut rng);
+        }
+    }
+
+    #[test]
+    fn holds_opener_for_tetris() {
+        use Piece::*;
+        let config = Arc::new(BotConfig::default());
+        let well = "#########.\n#########.\n#########.\n#########.";
+        let start = start(well, None, &[S, I, T, O]);
+        let hold_empty = start_hold_empty(&start, &config);
+        let mut bot = create_bot(start, config.clone()).ok().unwrap();
+        search(&bot, 2000);
+
+        let state = bot.state();
+        let moves = tbp_moves(&config, bot.suggest(), Some(&state), hold_empty);
+        assert_eq!(moves[0].placement.location.piece, I);
+        assert!(moves[0].hold);
+
+        let info = bot.advance(moves[0].placement).unwrap();
+        assert_eq!(info.lines_cleared, 4);
+        let (state, queue) = bot.state();
+        assert_eq!(state.reserve, S);
+        assert_eq!(queue, [T, O]);
+    }
+
+    #[test]
+    fn start_continues_a_back_to_back_chain() {
+        use Piece::*;
+        let mut config = BotConfig::default();
+        config.freestyle_weights.back_to_back_chain = vec![0.0, 0.0, 0.0, 0.0, 100.0];
+        let config = Arc::new(config);
+        // a row left under the tetris, so it isn't a perfect clear
+        let well = "#########.\n#########.\n#########.\n#########.\n##.#######";
+        let tetris_eval = |back_to_back, b2b_chain| {
+            let start = tbp::Start {
+                back_to_back,
+                b2b_chain,
+                ..start(well, None, &[I, S, T, O])
+            };
+            let mut bot = create_bot(start, config.clone()).ok().unwrap();
+            let b2b = bot.state().0.b2b;
+            search(&bot, 1);
+            let (tetris, eval, _) = bot
+                .root_moves()
+                .into_iter()
+                .find(|(mv, _, _)| mv.location.piece == I && mv.location.x == 9)
+                .unwrap();
+            let info = bot.advance(tetris).unwrap();
+            assert_eq!(info.lines_cleared, 4);
+            (b2b, info.b2b_chain, eval)
+        };
+
+        // mid-spike, the tetris extends a chain of 5 into the part of the table worth 100
+        let (b2b, chain, long) = tetris_eval(true, Some(5));
+        assert_eq!((b2b, chain), (5, 6));
+        let (b2b, chain, short) = tetris_eval(true, None);
+        assert_eq!((b2b, chain), (1, 2));
+        assert!((long - short - 100.0).abs() < 0.01, "{} vs {}", long, short);
+
+        // a chain which contradicts back_to_back is ignored
+        assert_eq!(tetris_eval(false, Some(5)).0, 0);
+        assert_eq!(tetris_eval(true, Some(0)).0, 1);
+    }
+
+    #[test]
+    fn impossible_seven_bag_queue_disables_speculation() {
+        use Piece::*;
+        let config = Arc::new(BotConfig::default());
+        let bag_after = |hold, queue: &[Piece]| {
+            let start = tbp::Start {
+                randomizer: Randomizer::SevenBag {
+                    bag_state: EnumSet::all(),
+                },
+                ..start("", hold, queue)
+            };
+            create_bot(start, config.clone())
+                .ok()
+                .unwrap()
+                .state()
+                .0
+                .bag
+        };
+
+        // the bag after the current T still holds the rest of the queue
+        assert_eq!(bag_after(None, &[T, I, O, L]), I | O | L);
+        // two Ts can't come out of one bag, whether or not one of them is the current piece
+        assert_eq!(bag_after(None, &[I, T, O, T]), EnumSet::all());
+        assert_eq!(bag_after(None, &[T, I, T]), EnumSet::all());
+        // a held T came out of an earlier bag
+        assert_ne!(bag_after(Some(T), &[T, I]), EnumSet::all());
+    }
+
+    #[test]
+    fn classic_start_remembers_the_current_piece() {
+        use Piece::*;
+        let config = Arc::new(BotConfig::default());
+        let root = |randomizer, hold| {
+            let start = tbp::Start {
+                randomizer,
+                ..start("", hold, &[T, I, O])
+            };
+            create_bot(start, config.clone()).ok().unwrap().state().0
+        };
+
+        let state = root(Randomizer::Classic, None);
+        assert!(state.memoryless);
+        assert_eq!(state.bag, EnumSet::all());
+        assert_eq!(state.last_drawn, Some(T));
+        assert_eq!(root(Randomizer::Classic, Some(L)).last_drawn, Some(L));
+        assert_eq!(root(Randomizer::Memoryless, None).last_drawn, None);
+    }
+
+    #[test]
+    fn plays_opener_without_hold() {
+        use Piece::*;
+        let config = Arc::new(BotConfig::default());
+        let well = "#########.\n#########.\n#########.\n#########.";
+        let start = start(well, None, &[I, S, T, O]);
+        let hold_empty = start_hold_empty(&start, &config);
+        let bot = create_bot(start, config.clone()).ok().unwrap();
+        search(&bot, 2000);
+
+        let state = bot.state();
+        let moves = tbp_moves(&config, bot.suggest(), Some(&state), hold_empty);
+        assert_eq!(moves[0].placement.location.piece, I);
+        assert!(!moves[0].hold);
+    }
+
+    #[test]
+    fn ping_is_answered_during_search() {
+        use Piece::*;
+        let (to_bot, from_bot) = spawn_run(BotConfig::default());
+        let start = start("", None, &[I, O, T, L, J, S, Z]);
+        to_bot
+            .unbounded_send(FrontendMessage::Start(start))
+            .unwrap();
+        // let the workers get going
+        std::thread::sleep(Duration::from_millis(100));
+
+        let sent = Instant::now();
+        to_bot.unbounded_send(FrontendMessage::Ping).unwrap();
+        let reply = from_bot.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(reply, BotMessage::Pong));
+        assert!(sent.elapsed() < Duration::from_millis(500));
+
+        to_bot.unbounded_send(FrontendMessage::Stop).unwrap();
+        to_bot.unbounded_send(FrontendMessage::Quit).unwrap();
+    }
+
+    #[test]
+    fn messages_are_handled_while_thinking() {
+        use Piece::*;
+        let (to_bot, from_bot) = spawn_run(BotConfig::default());
+        let start = start("", None, &[I, O, T, L, J, S, Z]);
+        to_bot
+            .unbounded_send(FrontendMessage::Start(start))
+            .unwrap();
+        let started = Instant::now();
+        let think = Duration::from_millis(1000);
+        let think_ms = Some(think.as_millis() as u64);
+        to_bot
+            .unbounded_send(FrontendMessage::Suggest { think_ms })
+            .unwrap();
+
+        to_bot.unbounded_send(FrontendMessage::Ping).unwrap();
+        let reply = from_bot.recv_timeout(Duration::from_millis(500)).unwrap();
+        assert!(matches!(reply, BotMessage::Pong));
+
+        let reply = from_bot.recv_timeout(think * 5).unwrap();
+        assert!(matches!(reply, BotMessage::Suggestion { .. }));
+        assert!(started.elapsed() >= think);
+
+        to_bot.unbounded_send(FrontendMessage::Stop).unwrap();
+        to_bot.unbounded_send(FrontendMessage::Quit).unwrap();
+    }
+
+    #[test]
+    fn garbage_off_the_board_is_rejected() {
+        use Piece::*;
+        let (to_bot, from_bot) = spawn_run(BotConfig::default());
+        let start = start("", None, &[I, O, T, L, J, S, Z]);
+        to_bot
+            .unbounded_send(FrontendMessage::Start(start))
+            .unwrap();
+        let garbage = FrontendMessage::Garbage {
+            column: 10,
+            lines: 2,
+        };
+        to_bot.unbounded_send(garbage).unwrap();
+        let reply = from_bot.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(reply, BotMessage::Error { .. }));
+
+        // the bot is still running afterwards
+        to_bot.unbounded_send(FrontendMessage::Ping).unwrap();
+        let reply = from_bot.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(matches!(reply, BotMessage::Pong));
+
+        to_bot.unbounded_send(FrontendMessage::Stop).unwrap();
+        to_bot.unbounded_send(FrontendMessage::Quit).unwrap();
+    }
+
+    #[test]
+    fn seeded_analyze_is_deterministic() {
+        use Piece::*;
+        let config = Arc::new(BotConfig {
+            seed: Some(7),
+            ..BotConfig::default()
+        });
+        let board = Board::from_ascii("#...##....\n###.###.##");
+        let state = GameState::new(board, T, EnumSet::all() - T, 0, 0);
+        let queue = [I, O, L, J, S];
+        let analyze = || analyze(config.clone(), state, &queue, AnalyzeBudget::Nodes(3000));
+
+        let first = analyze();
+        assert!(!first.is_empty());
+        for _ in 0..3 {
+            assert_eq!(analyze(), first);
+        }
+    }
+
+    #[test]
+    fn analyze_without_moves_returns_nothing() {
+        let board = Board::from_ascii(&"##########\n".repeat(40));
+        let state = GameState::new(board, Piece::T, EnumSet::all(), 0, 0);
+        let budget = AnalyzeBudget::Time(Duration::from_secs(10));
+        let start = Instant::now();
+        assert!(analyze(Arc::new(BotConfig::default()), state, &[], budget).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn think_ms_is_answered_while_input_blocks() {
+        use std::io::{BufReader, Write};
+
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let incoming = read_messages(BufReader::new(reader));
+        let (outgoing, from_bot) = std::sync::mpsc::channel();
+        let outgoing = futures::sink::unfold(outgoing, |outgoing, msg| async move {
+            let _ = outgoing.send(msg);
+            Ok::<_, Infallible>(outgoing)
+        });
+        let config = Arc::new(BotConfig::default());
+        std::thread::spawn(move || {
+            futures::executor::block_on(run(incoming, Box::pin(outgoing), config, None))
+        });
+        assert!(matches!(from_bot.recv().unwrap(), BotMessage::Info { .. }));
+
+        writeln!(
+            writer,
+            r#"{{"type":"start","hold":null,"queue":["T","I","O","L","J","S"],"combo":0,"back_to_back":false,"board":{}}}"#,
+            serde_json::to_string(&Board::default()).unwrap()
+        )
+        .unwrap();
+        writeln!(writer, r#"{{"type":"suggest","think_ms":100}}"#).unwrap();
+
+        // nothing else is written, so the reader thread stays blocked
+        let reply = from_bot.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(reply, BotMessage::Suggestion { .. }));
+
+        drop(writer);
+    }
+
+    #[test]
+    fn rewind_restores_the_hold_slot() {
+        use Piece::*;
+        let config = BotConfig {
+            keep_history: true,
+            ..BotConfig::default()
+        };
+        let (to_bot, from_bot) = spawn_run(config);
+        let start = start("", None, &[I, O, T, L, J, S, Z]);
+        to_bot
+            .unbounded_send(FrontendMessage::Start(start))
+            .unwrap();
+        // playing O puts the I in the empty hold slot
+        let mv = Placement {
+            location: PieceLocation {
+                piece: O,
+                rotation: Rotation::North,
+                x: 4,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+        to_bot.unbounded_send(FrontendMessage::Play { mv }).unwrap();
+        to_bot.unbounded_send(FrontendMessage::Rewind).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        to_bot
+            .unbounded_send(FrontendMessage::Suggest { think_ms: None })
+            .unwrap();
+
+        // back before the hold, I is the current piece again
+        match from_bot.recv_timeout(Duration::from_secs(1)).unwrap() {
+            BotMessage::Suggestion { moves, .. } => {
+                assert!(!moves.is_empty());
+                for mv in moves {
+                    assert_eq!(mv.hold, mv.placement.location.piece != I);
+                }
+            }
+            _ => panic!("expected a suggestion"),
+        }
+
+        to_bot.unbounded_send(FrontendMessage::Stop).unwrap();
+        to_bot.unbounded_send(FrontendMessage::Quit).unwrap();
+    }
+
+    #[test]
+    fn play_without_a_next_piece_is_rejected() {
+        use Piece::*;
+        let mv = Placement {
+            location: PieceLocation {
+                piece: T,
+                rotation: Rotation::North,
+                x: 4,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+        for (hold, queue) in [(Some(T), &[][..]), (None, &[T][..])] {
+            let (to_bot, from_bot) = spawn_run(BotConfig::default());
+            let start = start("", hold, queue);
+            to_bot
+                .unbounded_send(FrontendMessage::Start(start))
+                .unwrap();
+            to_bot.unbounded_send(FrontendMessage::Play { mv }).unwrap();
+            let reply = from_bot.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert!(matches!(reply, BotMessage::Error { .. }));
+
+            // the game carries on once the piece arrives
+            to_bot
+                .unbounded_send(FrontendMessage::NewPiece { piece: I })
+                .unwrap();
+            to_bot.unbounded_send(FrontendMessage::Play { mv }).unwrap();
+            to_bot.unbounded_send(FrontendMessage::Ping).unwrap();
+            let reply = from_bot.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert!(matches!(reply, BotMessage::Pong));
+
+            to_bot.unbounded_send(FrontendMessage::Quit).unwrap();
+        }
+    }
+
+    /// The hold flag reported for playing each of `plays` in turn, each dropped in the middle of
+    /// the board, updating whether hold is empty the same way `run` does.
+    fn hold_flags(config: BotConfig, start: tbp::Start, plays: &[Piece]) -> Vec<bool> {
+        let config = Arc::new(config);
+        let mut hold_empty = start_hold_empty(&start, &config);
+        let mut bot = create_bot(start, config.clone()).ok().unwrap();
+        let mut flags = vec![];
+        for &piece in plays {
+            let state = bot.state();
+            let mut location = PieceLocation {
+                piece,
+                rotation: Rotation::North,
+                x: 4,
+                y: 30,
+            };
+            location.y -= location.drop_distance(&state.0.board);
+            let mv = Placement {
+                location,
+                spin: Spin::None,
+            };
+            flags.push(tbp_moves(&config, vec![mv], Some(&state), hold_empty)[0].hold);
+            let held = state.0.reserve != piece;
+            bot.advance(mv).unwrap();
+            if held {
+                hold_empty = false;
+            }
+        }
+        flags
+    }
+
+    #[test]
+    fn hold_conventions_agree_on_hold_flags() {
+        use Piece::*;
+        // the current piece is T with I O L J S Z after it, and nothing has been held
+        let standard = start("", None, &[T, I, O, L, J, S, Z]);
+        let current_in_hold = BotConfig {
+            hold_convention: HoldConvention::CurrentInHold,
+            ..BotConfig::default()
+        };
+        let in_hold = start("", Some(T), &[I, O, L, J, S, Z]);
+
+        // hold T for I, play O, swap O's successor L for T, then play the held L
+        let plays = [I, O, T, L];
+        let expected = [true, false, true, true];
+        assert_eq!(hold_flags(BotConfig::default(), standard, &plays), expected);
+        assert_eq!(hold_flags(current_in_hold, in_hold, &plays), expected);
+    }
+
+    #[test]
+    fn pv_hold_flags_match_suggestions() {
+        use Piece::*;
+        let drop = |piece, x| Placement {
+            location: PieceLocation {
+                piece,
+                rotation: Rotation::North,
+                x,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+        // with T current, I O L after it and hold empty: play T, hold I for O, then swap L for I
+        let root = GameState::new(Board::default(), T, EnumSet::all() - T, 0, 0);
+        let queue = vec![I, O, L];
+        let mut state = root;
+        let pv: Vec<_> = [(I, drop(T, 1)), (O, drop(O, 3)), (L, drop(I, 6))]
+            .into_iter()
+            .map(|(next, mv)| (state.advance(next, mv), 0.0))
+            .collect();
+        let holds: Vec<_> = pv_steps(pv, Some(&(root, queue)), true)
+            .iter()
+            .map(|step| step.hold)
+            .collect();
+        assert_eq!(holds, [false, true, true]);
+
+        // an opener searched for real agrees with its own suggestion
+        let config = Arc::new(BotConfig::default());
+        let start = start("", None, &[I, O, T, L, J, S, Z]);
+        let hold_empty = start_hold_empty(&start, &config);
+        let bot = create_bot(start, config.clone()).ok().unwrap();
+        search(&bot, 2000);
+        let state = bot.state();
+        let moves = tbp_moves(&config, bot.suggest(), Some(&state), hold_empty);
+        let pv = pv_steps(bot.principal_variation(), Some(&state), hold_empty);
+        assert_eq!(pv[0].placement, moves[0].placement);
+        assert_eq!(pv[0].hold, moves[0].hold);
+    }
+
+    #[test]
+    fn bench_search_runs_past_the_node_cap() {
+        // each position searches well past the cap, so the tree has to be evicted along the way
+        let config = Arc::new(BotConfig {
+            max_nodes: Some(1000),
+            ..BotConfig::default()
+        });
+        let (nodes, _) = bench_search(config, 5000);
+        assert!(nodes >= 3 * 5000);
     }
 }
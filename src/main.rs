@@ -1,19 +1,154 @@
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener};
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use cold_clear_2::data::{Board, GameState, Piece, Placement};
+use cold_clear_2::replay::ReplayLog;
+use cold_clear_2::self_play;
+use cold_clear_2::BotConfig;
+use enumset::EnumSet;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct CliOptions {
     /// Enable puffin profiler (requires building with feature `puffin_http`)
     #[structopt(long)]
+    #[cfg_attr(not(feature = "puffin_http"), allow(dead_code))]
     profile: bool,
 
     /// Path to JSON file containing the bot configuration
     #[structopt(short, long)]
     config: Option<PathBuf>,
+
+    /// Refuse to start if the configuration contains unknown fields instead of ignoring them
+    #[structopt(long)]
+    strict_config: bool,
+
+    /// Write a log of every suggestion and played move to this file
+    #[structopt(long)]
+    replay: Option<PathBuf>,
+
+    /// Accept a single TBP connection on this address instead of using stdin and stdout
+    #[structopt(long)]
+    listen: Option<SocketAddr>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Search a fixed set of positions and report the search speed
+    BenchSearch {
+        /// Number of nodes to search in each position
+        #[structopt(long, default_value = "1000000")]
+        nodes: u64,
+    },
+    /// Evaluate the placements of a piece on a board read from stdin, either as a `v115@` fumen
+    /// or drawn with one line per row and `.` for empty cells. Only the total is printed; there
+    /// is no breakdown by evaluation term
+    Score {
+        /// The piece to place
+        piece: String,
+        /// Score this placement (as TBP JSON) instead of reporting the best one
+        #[structopt(long)]
+        placement: Option<String>,
+        /// Print every placement, best first
+        #[structopt(long)]
+        all: bool,
+    },
+    /// Play games with random 7-bag pieces and report how they went
+    SelfPlay {
+        #[structopt(long, default_value = "1")]
+        games: u64,
+        /// Pieces to play in each game
+        #[structopt(long, default_value = "500")]
+        pieces: u32,
+        /// Nodes to search before each move
+        #[structopt(long, default_value = "10000")]
+        nodes: u64,
+        /// Seed of the first game; later games use the following seeds
+        #[structopt(long, default_value = "0")]
+        seed: u64,
+        /// Send garbage with this hole pattern as JSON, such as
+        /// `{"type":"messy","change_chance":0.3}`
+        #[structopt(long)]
+        garbage: Option<String>,
+        /// Lines of garbage in each batch
+        #[structopt(long, default_value = "2")]
+        garbage_lines: u32,
+        /// Pieces between batches of garbage
+        #[structopt(long, default_value = "5")]
+        garbage_interval: u32,
+    },
+}
+
+fn score(config: &BotConfig, piece: &str, placement: Option<&str>, all: bool) {
+    let piece: Piece = serde_json::from_value(piece.to_uppercase().into()).unwrap_or_else(|_| {
+        eprintln!("error: unknown piece {}", piece);
+        std::process::exit(1)
+    });
+    let mut text = String::new();
+    std::io::stdin().read_to_string(&mut text).unwrap();
+    let board = match text.trim_start().starts_with("v115@") {
+        true => Board::from_fumen(&text).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1)
+        }),
+        false => Board::from_ascii(&text),
+    };
+    let state = GameState::new(board, piece, EnumSet::all(), 0, 0);
+
+    let mut scores = cold_clear_2::score_placements(config, &state, piece);
+    scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    if let Some(placement) = placement {
+        let placement: Placement = serde_json::from_str(placement).unwrap_or_else(|e| {
+            eprintln!("error: invalid placement: {}", e);
+            std::process::exit(1)
+        });
+        let canonical = placement.location.canonical_form();
+        scores.retain(|(mv, _)| mv.location == canonical && mv.spin == placement.spin);
+        if scores.is_empty() {
+            eprintln!("error: placement is not reachable");
+            std::process::exit(1);
+        }
+    } else if !all {
+        scores.truncate(1);
+    }
+    for (mv, eval) in scores {
+        println!("{:.3} {}", eval, serde_json::to_string(&mv).unwrap());
+    }
+}
+
+fn self_play(config: Arc<BotConfig>, games: u64, options: self_play::Options) {
+    for game in 0..games {
+        let options = self_play::Options {
+            seed: options.seed + game,
+            ..options
+        };
+        let outcome = self_play::play(config.clone(), &options).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1)
+        });
+        println!(
+            "seed {}: {} pieces, {} lines, {} attack, {} garbage received, 40 lines in {}{}",
+            options.seed,
+            outcome.pieces,
+            outcome.lines,
+            outcome.attack,
+            outcome.garbage,
+            outcome
+                .sprint_pieces
+                .map_or("-".to_owned(), |p| format!("{} pieces", p)),
+            if outcome.topped_out {
+                ", topped out"
+            } else {
+                ""
+            },
+        );
+    }
 }
 
 fn main() {
@@ -33,23 +168,113 @@ fn main() {
 
     let config = options.config.map_or_else(Default::default, |path| {
         let f = BufReader::new(File::open(path).unwrap());
-        Arc::new(serde_json::from_reader(f).unwrap())
+        cold_clear_2::read_config(f, options.strict_config).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            std::process::exit(1)
+        })
     });
 
-    let incoming = futures::stream::repeat_with(|| {
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line).unwrap();
-        serde_json::from_str(&line).unwrap()
-    });
+    match options.command {
+        Some(Command::BenchSearch { nodes }) => {
+            let (nodes, time) = cold_clear_2::bench_search(config, nodes);
+            println!(
+                "{} nodes in {:.2?} ({:.3} Mnps)",
+                nodes,
+                time,
+                nodes as f64 / time.as_secs_f64() / 1_000_000.0
+            );
+            return;
+        }
+        Some(Command::Score {
+            piece,
+            placement,
+            all,
+        }) => {
+            score(&config, &piece, placement.as_deref(), all);
+            return;
+        }
+        Some(Command::SelfPlay {
+            games,
+            pieces,
+            nodes,
+            seed,
+            garbage,
+            garbage_lines,
+            garbage_interval,
+        }) => {
+            let garbage = garbage.map(|json| self_play::Garbage {
+                pattern: serde_json::from_str(&json).unwrap_or_else(|e| {
+                    eprintln!("error: invalid garbage pattern: {}", e);
+                    std::process::exit(1)
+                }),
+                lines: garbage_lines,
+                interval: garbage_interval,
+            });
+            let options = self_play::Options {
+                pieces,
+                nodes,
+                seed,
+                garbage,
+            };
+            self_play(config, games, options);
+            return;
+        }
+        None => {}
+    }
+
+    let replay = options
+        .replay
+        .map(|path| ReplayLog::new(BufWriter::new(File::create(path).unwrap())));
 
-    let outgoing = futures::sink::unfold((), |_, msg| {
-        serde_json::to_writer(std::io::stdout(), &msg).unwrap();
-        println!();
-        async { Ok(()) }
+    match options.listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).unwrap_or_else(|e| {
+                eprintln!("error: failed to listen on {}: {}", addr, e);
+                std::process::exit(1)
+            });
+            let (stream, peer) = listener.accept().unwrap_or_else(|e| {
+                eprintln!("error: failed to accept a connection: {}", e);
+                std::process::exit(1)
+            });
+            // only the one connection is served
+            drop(listener);
+            eprintln!("info: accepted connection from {}", peer);
+            let reader = BufReader::new(stream.try_clone().unwrap());
+            serve(reader, BufWriter::new(stream), config, replay);
+        }
+        None => serve(
+            BufReader::new(std::io::stdin()),
+            std::io::stdout(),
+            config,
+            replay,
+        ),
+    }
+}
+
+/// Runs the bot over line-delimited TBP JSON.
+fn serve(
+    input: impl BufRead + Send + 'static,
+    output: impl Write,
+    config: Arc<BotConfig>,
+    replay: Option<ReplayLog>,
+) {
+    // Lines that don't parse are answered with an error rather than taking the whole bot down.
+    let incoming = cold_clear_2::read_messages(input);
+
+    let outgoing = futures::sink::unfold(output, |mut output, msg| {
+        let written = serde_json::to_writer(&mut output, &msg)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(output))
+            .and_then(|_| output.flush());
+        if let Err(e) = written {
+            // nobody is listening anymore, so there is nothing left to do
+            eprintln!("error: failed to write output: {}", e);
+            std::process::exit(1);
+        }
+        async { Ok(output) }
     });
 
-    futures::pin_mut!(incoming);
     futures::pin_mut!(outgoing);
 
-    futures::executor::block_on(cold_clear_2::run(incoming, outgoing, config));
+    futures::executor::block_on(cold_clear_2::run(incoming, outgoing, config, replay));
 }
@@ -1,7 +1,5 @@
 use std::convert::TryInto;
 use std::hash::BuildHasher;
-use std::hash::Hash;
-use std::hash::Hasher;
 
 use nohash::IntMap;
 use parking_lot::MappedRwLockReadGuard;
@@ -12,16 +10,23 @@ use parking_lot::RwLockWriteGuard;
 
 use crate::data::GameState;
 
-pub struct StateMap<V, S = ahash::RandomState> {
+/// A concurrent map keyed by `GameState` hashes, split into `SHARDS` independently locked shards.
+///
+/// More shards means less lock contention between worker threads, but every shard costs memory
+/// even while empty, and each layer of the DAG has its own map. The `low-memory` feature lowers
+/// the default shard count for small searches and constrained targets.
+pub struct StateMap<V, S = ahash::RandomState, const SHARDS: usize = DEFAULT_SHARDS> {
     hasher: S,
     buckets: Box<[RwLock<IntMap<u64, V>>; SHARDS]>,
 }
 
-const SHARD_INDEX_BITS: usize = 12;
 const SHARD_INDEX_SHIFT: usize = 32;
-const SHARDS: usize = 1 << SHARD_INDEX_BITS;
+#[cfg(not(feature = "low-memory"))]
+pub const DEFAULT_SHARDS: usize = 1 << 12;
+#[cfg(feature = "low-memory")]
+pub const DEFAULT_SHARDS: usize = 1 << 6;
 
-impl<V, S: Default> Default for StateMap<V, S> {
+impl<V, S: Default, const SHARDS: usize> Default for StateMap<V, S, SHARDS> {
     fn default() -> Self {
         StateMap {
             hasher: Default::default(),
@@ -34,26 +39,24 @@ impl<V, S: Default> Default for StateMap<V, S> {
     }
 }
 
-impl<V, S: BuildHasher> StateMap<V, S> {
+impl<V, S: BuildHasher, const SHARDS: usize> StateMap<V, S, SHARDS> {
     pub fn index(&self, k: &GameState) -> u64 {
-        let mut hasher = self.hasher.build_hasher();
-        k.hash(&mut hasher);
-        hasher.finish()
+        self.hasher.hash_one(k)
     }
 
     fn bucket(&self, k: u64) -> &RwLock<IntMap<u64, V>> {
         &self.buckets[(k >> SHARD_INDEX_SHIFT) as usize % SHARDS]
     }
 
-    pub fn get_raw(&self, k: u64) -> Option<MappedRwLockReadGuard<V>> {
+    pub fn get_raw(&self, k: u64) -> Option<MappedRwLockReadGuard<'_, V>> {
         RwLockReadGuard::try_map(self.bucket(k).read(), |shard| shard.get(&k)).ok()
     }
 
-    pub fn get(&self, k: &GameState) -> Option<MappedRwLockReadGuard<V>> {
+    pub fn get(&self, k: &GameState) -> Option<MappedRwLockReadGuard<'_, V>> {
         self.get_raw(self.index(k))
     }
 
-    pub fn get_raw_mut(&self, k: u64) -> Option<MappedRwLockWriteGuard<V>> {
+    pub fn get_raw_mut(&self, k: u64) -> Option<MappedRwLockWriteGuard<'_, V>> {
         RwLockWriteGuard::try_map(self.bucket(k).write(), |shard| shard.get_mut(&k)).ok()
     }
 
@@ -61,7 +64,7 @@ impl<V, S: BuildHasher> StateMap<V, S> {
         &self,
         k: u64,
         f: impl FnOnce() -> V,
-    ) -> MappedRwLockWriteGuard<V> {
+    ) -> MappedRwLockWriteGuard<'_, V> {
         RwLockWriteGuard::map(self.bucket(k).write(), |shard| {
             shard.entry(k).or_insert_with(f)
         })
@@ -71,10 +74,40 @@ impl<V, S: BuildHasher> StateMap<V, S> {
         &self,
         k: &GameState,
         f: impl FnOnce() -> V,
-    ) -> MappedRwLockWriteGuard<V> {
+    ) -> MappedRwLockWriteGuard<'_, V> {
         self.get_raw_or_insert_with(self.index(k), f)
     }
-    pub fn map_values<T>(self, f: impl Fn(V) -> T) -> StateMap<T, S> {
+
+    /// A rough estimate of the memory used while holding `entries` entries: the entries plus the
+    /// shards themselves. Spare capacity and anything the values point to aren't counted.
+    pub fn approx_bytes(&self, entries: usize) -> usize {
+        let entry = std::mem::size_of::<(u64, V)>();
+        let shard = std::mem::size_of::<RwLock<IntMap<u64, V>>>();
+        entries * entry + SHARDS * shard
+    }
+
+    pub fn remove_raw(&self, k: u64) -> Option<V> {
+        self.bucket(k).write().remove(&k)
+    }
+
+    /// Calls `f` on every entry. Shards are locked one at a time, so entries inserted or removed
+    /// meanwhile may or may not be seen.
+    pub fn for_each(&self, mut f: impl FnMut(u64, &V)) {
+        for shard in self.buckets.iter() {
+            for (&k, v) in shard.read().iter() {
+                f(k, v);
+            }
+        }
+    }
+
+    /// Gives memory no longer needed after removing entries back to the allocator.
+    pub fn shrink_to_fit(&self) {
+        for shard in self.buckets.iter() {
+            shard.write().shrink_to_fit();
+        }
+    }
+
+    pub fn map_entries<T>(self, mut f: impl FnMut(u64, V) -> T) -> StateMap<T, S, SHARDS> {
         StateMap {
             hasher: self.hasher,
             buckets: self
@@ -85,7 +118,7 @@ impl<V, S: BuildHasher> StateMap<V, S> {
                         shard
                             .into_inner()
                             .into_iter()
-                            .map(|(k, v)| (k, f(v)))
+                            .map(|(k, v)| (k, f(k, v)))
                             .collect(),
                     )
                 })
@@ -2,18 +2,130 @@ use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 
 use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
 
 use crate::data::*;
 
+macro_rules! kick_table_lut {
+    ($v:ident => $e:expr) => {
+        lutify!(($e) for $v in [KickTable::Srs, KickTable::SrsPlus, KickTable::Ars])
+    };
+}
+
+/// The row pieces spawn in. Pieces which can't spawn there are moved up one row.
+pub const SPAWN_Y: i8 = 19;
+
+/// Buffers used by `find_moves_with`, kept around to avoid reallocating them on every call.
+#[derive(Default)]
+pub struct Scratch {
+    queue: BinaryHeap<Intermediate>,
+    values: AHashMap<Placement, u32>,
+    underground_locks: AHashMap<Placement, u32>,
+}
+
+/// Rules of the game which change which placements can be reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MoveRules {
+    /// The most cells a piece may be soft dropped. With a limit of 0, only placements reachable
+    /// by moving and rotating above the stack and then hard dropping remain.
+    pub max_soft_drop: u32,
+    /// Whether pieces can be rotated 180 degrees with a single input.
+    pub rotate_180: bool,
+    pub kick_table: KickTable,
+    /// Whether pieces other than T which rotate into a spot they can't move out of count as
+    /// spins, as in TETR.IO. T pieces always use the 3-corner rule.
+    pub all_spin: bool,
+}
+
+impl Default for MoveRules {
+    fn default() -> Self {
+        MoveRules {
+            max_soft_drop: u32::MAX,
+            rotate_180: false,
+            kick_table: KickTable::Srs,
+            all_spin: false,
+        }
+    }
+}
+
+/// The wall kicks tried when rotating. 180 degree rotations use TETR.IO's kicks regardless.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KickTable {
+    #[default]
+    Srs,
+    /// SRS with TETR.IO's symmetric I piece kicks.
+    SrsPlus,
+    /// Arika's kicks: in place, then one cell right, then one cell left, and never for the I
+    /// piece. Pieces still use SRS rotation states and the center column rule isn't applied.
+    Ars,
+}
+
 pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
-    puffin::profile_function!();
-    let mut queue = BinaryHeap::new();
-    let mut values = AHashMap::new();
-    let mut underground_locks = AHashMap::new();
     let mut locks = Vec::with_capacity(64);
+    find_moves_with(board, piece, &mut Scratch::default(), &mut locks);
+    locks
+}
+
+/// Like `find_moves`, but always searches from the spawn position instead of taking the shortcut
+/// used on low boards. Slower, but useful for checking that the shortcut finds the same moves.
+pub fn find_moves_without_fast_mode(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
+    let mut locks = Vec::with_capacity(64);
+    let rules = MoveRules::default();
+    find_moves_impl(
+        board,
+        piece,
+        &rules,
+        &mut Scratch::default(),
+        &mut locks,
+        false,
+    );
+    locks
+}
+
+/// Like `find_moves`, but reuses the given buffers. The moves are written to `locks`, replacing
+/// its previous contents.
+pub fn find_moves_with(
+    board: &Board,
+    piece: Piece,
+    scratch: &mut Scratch,
+    locks: &mut Vec<(Placement, u32)>,
+) {
+    find_moves_impl(board, piece, &MoveRules::default(), scratch, locks, true)
+}
+
+/// Like `find_moves_with`, but under the given rules instead of the defaults.
+pub fn find_moves_with_rules(
+    board: &Board,
+    piece: Piece,
+    rules: &MoveRules,
+    scratch: &mut Scratch,
+    locks: &mut Vec<(Placement, u32)>,
+) {
+    find_moves_impl(board, piece, rules, scratch, locks, true)
+}
+
+fn find_moves_impl(
+    board: &Board,
+    piece: Piece,
+    rules: &MoveRules,
+    scratch: &mut Scratch,
+    locks: &mut Vec<(Placement, u32)>,
+    allow_fast_mode: bool,
+) {
+    profile_function!();
+    let Scratch {
+        queue,
+        values,
+        underground_locks,
+    } = scratch;
+    queue.clear();
+    values.clear();
+    underground_locks.clear();
+    locks.clear();
     let collision_map = CollisionMaps::new(board, piece);
 
-    let fast_mode = board.cols.iter().all(|&c| c.leading_zeros() > 64 - 16);
+    let fast_mode = allow_fast_mode && board.cols.iter().all(|&c| c.leading_zeros() > 64 - 16);
     if fast_mode {
         for &rotation in &[
             Rotation::North,
@@ -26,7 +138,7 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
                     piece,
                     rotation,
                     x,
-                    y: 19,
+                    y: SPAWN_Y,
                 };
                 if collision_map.obstructed(location) {
                     continue;
@@ -39,7 +151,7 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
                 };
 
                 let mut update_position =
-                    update_position(&mut queue, &mut values, fast_mode, board);
+                    update_position(queue, values, fast_mode, rules.max_soft_drop, board);
 
                 if let Some(mv) = shift(location, &collision_map, -1) {
                     update_position(mv, distance as u32);
@@ -47,12 +159,17 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
                 if let Some(mv) = shift(location, &collision_map, 1) {
                     update_position(mv, distance as u32);
                 }
-                if let Some(mv) = rotate_cw(location, &collision_map, board) {
+                if let Some(mv) = rotate_cw(location, &collision_map, board, rules) {
                     update_position(mv, distance as u32);
                 }
-                if let Some(mv) = rotate_ccw(location, &collision_map, board) {
+                if let Some(mv) = rotate_ccw(location, &collision_map, board, rules) {
                     update_position(mv, distance as u32);
                 }
+                if rules.rotate_180 {
+                    if let Some(mv) = rotate_180(location, &collision_map, board, rules) {
+                        update_position(mv, distance as u32);
+                    }
+                }
 
                 if location.canonical_form() == location {
                     locks.push((mv, 0));
@@ -60,21 +177,9 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
             }
         }
     } else {
-        let mut spawned = PieceLocation {
-            piece,
-            rotation: Rotation::North,
-            x: 4,
-            y: 19,
-        };
-        if collision_map.obstructed(spawned) {
-            spawned.y += 1;
-            if collision_map.obstructed(spawned) {
-                return vec![];
-            }
-        }
-        let spawned = Placement {
-            location: spawned,
-            spin: Spin::None,
+        let spawned = match spawn(&collision_map, piece) {
+            Some(spawned) => spawned,
+            None => return,
         };
         queue.push(Intermediate {
             soft_drops: 0,
@@ -109,7 +214,8 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
             .or_insert(expand.soft_drops);
         *sds = expand.soft_drops.min(*sds);
 
-        let mut update_position = update_position(&mut queue, &mut values, fast_mode, board);
+        let mut update_position =
+            update_position(queue, values, fast_mode, rules.max_soft_drop, board);
 
         update_position(dropped, expand.soft_drops + drop_dist as u32);
 
@@ -119,26 +225,187 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
         if let Some(mv) = shift(expand.mv.location, &collision_map, 1) {
             update_position(mv, expand.soft_drops);
         }
-        if let Some(mv) = rotate_cw(expand.mv.location, &collision_map, board) {
+        if let Some(mv) = rotate_cw(expand.mv.location, &collision_map, board, rules) {
             update_position(mv, expand.soft_drops);
         }
-        if let Some(mv) = rotate_ccw(expand.mv.location, &collision_map, board) {
+        if let Some(mv) = rotate_ccw(expand.mv.location, &collision_map, board, rules) {
             update_position(mv, expand.soft_drops);
         }
+        if rules.rotate_180 {
+            if let Some(mv) = rotate_180(expand.mv.location, &collision_map, board, rules) {
+                update_position(mv, expand.soft_drops);
+            }
+        }
+    }
+
+    locks.extend(underground_locks.drain());
+}
+
+/// Like `find_moves_with_rules`, but only keeps placements after which a perfect clear is still
+/// possible using at most `pieces_left` more pieces.
+pub fn find_perfect_clear_moves(
+    board: &Board,
+    piece: Piece,
+    pieces_left: u32,
+    rules: &MoveRules,
+    scratch: &mut Scratch,
+    locks: &mut Vec<(Placement, u32)>,
+) {
+    profile_function!();
+    find_moves_impl(board, piece, rules, scratch, locks, true);
+    locks.retain(|(mv, _)| {
+        let mut board = *board;
+        board.place(mv.location);
+        board.remove_lines(board.line_clears());
+        board.perfect_clear_possible(pieces_left)
+    });
+}
+
+/// A single input used to move a piece. Soft drops go all the way to the ground.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Input {
+    Left,
+    Right,
+    Cw,
+    Ccw,
+    Rotate180,
+    SoftDrop,
+}
+
+/// The inputs which move a piece from spawn to a placement. The final hard drop is implied.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct MovePath {
+    pub inputs: Vec<Input>,
+    /// How many cells the piece is soft dropped along the way.
+    pub soft_drop: u32,
+}
+
+/// Like `find_moves`, but also gives the inputs to reach each placement. Paths use as little soft
+/// drop as possible and, among those, as few inputs as possible. Always searches from spawn, so
+/// this is slower than `find_moves`.
+pub fn find_moves_with_paths(
+    board: &Board,
+    piece: Piece,
+    rules: &MoveRules,
+) -> Vec<(Placement, MovePath)> {
+    profile_function!();
+    let collision_map = CollisionMaps::new(board, piece);
+    let spawned = match spawn(&collision_map, piece) {
+        Some(spawned) => spawned,
+        None => return vec![],
+    };
+
+    // Dijkstra over (soft drops, inputs), remembering how each position was best reached
+    let mut queue = BinaryHeap::new();
+    let mut parents = AHashMap::new();
+    let mut locks = AHashMap::new();
+    queue.push(PathIntermediate {
+        cost: (0, 0),
+        mv: spawned,
+    });
+    parents.insert(spawned, ((0, 0), None));
+    while let Some(PathIntermediate { cost, mv }) = queue.pop() {
+        if parents[&mv].0 != cost {
+            continue;
+        }
+        let (soft_drops, presses) = cost;
+
+        let drop_dist = mv.location.drop_distance(board);
+        let dropped = Placement {
+            location: PieceLocation {
+                y: mv.location.y - drop_dist,
+                ..mv.location
+            },
+            spin: if drop_dist == 0 { mv.spin } else { Spin::None },
+        };
+        let lock = Placement {
+            location: dropped.location.canonical_form(),
+            ..dropped
+        };
+        match locks.get(&lock) {
+            Some(&(best, _)) if best <= cost => {}
+            _ => {
+                locks.insert(lock, (cost, mv));
+            }
+        }
+
+        let soft_drop = (
+            Input::SoftDrop,
+            (drop_dist != 0).then_some(dropped),
+            drop_dist as u32,
+        );
+        let neighbours = movements(mv.location, &collision_map, board, rules)
+            .into_iter()
+            .map(|(input, next)| (input, next, 0));
+        for (input, next, dropped_cells) in neighbours.chain([soft_drop]) {
+            let next = match next {
+                Some(next) => next,
+                None => continue,
+            };
+            let next_cost = (soft_drops + dropped_cells, presses + 1);
+            if next_cost.0 > rules.max_soft_drop {
+                continue;
+            }
+            if parents.get(&next).is_none_or(|&(c, _)| next_cost < c) {
+                parents.insert(next, (next_cost, Some((mv, input))));
+                queue.push(PathIntermediate {
+                    cost: next_cost,
+                    mv: next,
+                });
+            }
+        }
     }
 
-    locks.extend(underground_locks.into_iter());
     locks
+        .into_iter()
+        .map(|(lock, ((soft_drop, _), from))| {
+            let mut inputs = vec![];
+            let mut current = from;
+            while let Some((parent, input)) = parents[&current].1 {
+                inputs.push(input);
+                current = parent;
+            }
+            inputs.reverse();
+            (lock, MovePath { inputs, soft_drop })
+        })
+        .collect()
+}
+
+/// The path `find_moves_with_paths` gives to `target`, if it can be reached within the soft drop
+/// limit.
+pub fn path_to(board: &Board, target: &Placement, rules: &MoveRules) -> Option<MovePath> {
+    let target = Placement {
+        location: target.location.canonical_form(),
+        ..*target
+    };
+    find_moves_with_paths(board, target.location.piece, rules)
+        .into_iter()
+        .find(|(lock, _)| *lock == target)
+        .map(|(_, path)| path)
+}
+
+/// Whether `placement` can be reached from spawn and locked in place on `board`, including its
+/// spin status, within the soft drop limit.
+pub fn is_legal(board: &Board, placement: &Placement, rules: &MoveRules) -> bool {
+    path_to(board, placement, rules).is_some()
 }
 
 fn update_position<'a>(
     queue: &'a mut BinaryHeap<Intermediate>,
     values: &'a mut AHashMap<Placement, u32>,
     fast_mode: bool,
+    max_soft_drop: u32,
     board: &'a Board,
 ) -> impl FnMut(Placement, u32) + 'a {
     move |target: Placement, soft_drops: u32| {
-        if fast_mode && target.location.above_stack(board) {
+        // the drops from above already reach these, but not with a spin, which needs the piece to
+        // come from under the stack
+        if fast_mode && target.spin == Spin::None && target.location.above_stack(board) {
+            return;
+        }
+        // soft drops only accumulate, so nothing reachable from here is within the limit
+        if soft_drops > max_soft_drop {
             return;
         }
         let prev_sds = values.entry(target).or_insert(40);
@@ -152,6 +419,51 @@ fn update_position<'a>(
     }
 }
 
+/// Where pieces spawn, moved up a row if that's obstructed, or `None` if the piece can't spawn.
+fn spawn(collision_map: &CollisionMaps, piece: Piece) -> Option<Placement> {
+    let mut spawned = PieceLocation {
+        piece,
+        rotation: Rotation::North,
+        x: 4,
+        y: SPAWN_Y,
+    };
+    if collision_map.obstructed(spawned) {
+        spawned.y += 1;
+        if collision_map.obstructed(spawned) {
+            return None;
+        }
+    }
+    Some(Placement {
+        location: spawned,
+        spin: Spin::None,
+    })
+}
+
+/// Where each input other than soft drop moves a piece, if anywhere.
+fn movements(
+    location: PieceLocation,
+    collision_map: &CollisionMaps,
+    board: &Board,
+    rules: &MoveRules,
+) -> [(Input, Option<Placement>); 5] {
+    [
+        (Input::Left, shift(location, collision_map, -1)),
+        (Input::Right, shift(location, collision_map, 1)),
+        (Input::Cw, rotate_cw(location, collision_map, board, rules)),
+        (
+            Input::Ccw,
+            rotate_ccw(location, collision_map, board, rules),
+        ),
+        (
+            Input::Rotate180,
+            rules
+                .rotate_180
+                .then(|| rotate_180(location, collision_map, board, rules))
+                .flatten(),
+        ),
+    ]
+}
+
 fn shift(mut location: PieceLocation, collision_map: &CollisionMaps, dx: i8) -> Option<Placement> {
     location.x += dx;
     if collision_map.obstructed(location) {
@@ -163,16 +475,22 @@ fn shift(mut location: PieceLocation, collision_map: &CollisionMaps, dx: i8) ->
     })
 }
 
+/// Kicks indexed by kick table, piece, and the rotation the piece starts in.
+type KickLut = [[[[(i8, i8); 5]; 4]; 7]; 3];
+
 fn rotate_cw(
     from: PieceLocation,
     collision_map: &CollisionMaps,
     board: &Board,
+    rules: &MoveRules,
 ) -> Option<Placement> {
+    let table = rules.kick_table;
     if from.piece == Piece::O {
         return None;
     }
-    const KICKS: [[[(i8, i8); 5]; 4]; 7] =
-        piece_lut!(piece => rotation_lut!(rotation => kicks(piece, rotation, rotation.cw())));
+    const KICKS: KickLut = kick_table_lut!(table => piece_lut!(piece =>
+        rotation_lut!(rotation => kicks(table, piece, rotation, rotation.cw()))
+    ));
     let unkicked = PieceLocation {
         rotation: from.rotation.cw(),
         ..from
@@ -181,9 +499,11 @@ fn rotate_cw(
         unkicked,
         collision_map,
         board,
-        KICKS[from.piece as usize][from.rotation as usize]
+        KICKS[table as usize][from.piece as usize][from.rotation as usize]
             .iter()
             .copied(),
+        Some(4),
+        rules.all_spin,
     )
 }
 
@@ -191,16 +511,46 @@ fn rotate_ccw(
     from: PieceLocation,
     collision_map: &CollisionMaps,
     board: &Board,
+    rules: &MoveRules,
 ) -> Option<Placement> {
+    let table = rules.kick_table;
     if from.piece == Piece::O {
         return None;
     }
-    const KICKS: [[[(i8, i8); 5]; 4]; 7] =
-        piece_lut!(piece => rotation_lut!(rotation => kicks(piece, rotation, rotation.ccw())));
+    const KICKS: KickLut = kick_table_lut!(table => piece_lut!(piece =>
+        rotation_lut!(rotation => kicks(table, piece, rotation, rotation.ccw()))
+    ));
     let unkicked = PieceLocation {
         rotation: from.rotation.ccw(),
         ..from
     };
+    rotate(
+        unkicked,
+        collision_map,
+        board,
+        KICKS[table as usize][from.piece as usize][from.rotation as usize]
+            .iter()
+            .copied(),
+        Some(4),
+        rules.all_spin,
+    )
+}
+
+fn rotate_180(
+    from: PieceLocation,
+    collision_map: &CollisionMaps,
+    board: &Board,
+    rules: &MoveRules,
+) -> Option<Placement> {
+    if from.piece == Piece::O {
+        return None;
+    }
+    const KICKS: [[[(i8, i8); 6]; 4]; 7] =
+        piece_lut!(piece => rotation_lut!(rotation => kicks_180(piece, rotation)));
+    let unkicked = PieceLocation {
+        rotation: from.rotation.flip(),
+        ..from
+    };
     rotate(
         unkicked,
         collision_map,
@@ -208,6 +558,8 @@ fn rotate_ccw(
         KICKS[from.piece as usize][from.rotation as usize]
             .iter()
             .copied(),
+        None,
+        rules.all_spin,
     )
 }
 
@@ -234,23 +586,82 @@ const fn offsets(piece: Piece, rotation: Rotation) -> [(i8, i8); 5] {
     }
 }
 
-const fn kicks(piece: Piece, from: Rotation, to: Rotation) -> [(i8, i8); 5] {
-    let mut kicks = [(0, 0); 5];
-    let from = offsets(piece, from);
-    let to = offsets(piece, to);
+const fn kicks(table: KickTable, piece: Piece, from: Rotation, to: Rotation) -> [(i8, i8); 5] {
+    let from_offsets = offsets(piece, from);
+    let to_offsets = offsets(piece, to);
+    let mut kicks = match (table, piece) {
+        (KickTable::SrsPlus, Piece::I) => srs_plus_i_kicks(from, to),
+        (KickTable::Ars, Piece::I) => [(0, 0); 5],
+        // repeating the last kick changes nothing, since it will fail again
+        (KickTable::Ars, _) => [(0, 0), (1, 0), (-1, 0), (-1, 0), (-1, 0)],
+        _ => {
+            let mut kicks = [(0, 0); 5];
+            let mut i = 0;
+            while i < kicks.len() {
+                kicks[i] = (
+                    from_offsets[i].0 - to_offsets[i].0,
+                    from_offsets[i].1 - to_offsets[i].1,
+                );
+                i += 1;
+            }
+            return kicks;
+        }
+    };
+    // these tables are relative to SRS's true rotation, which the first offset gives
+    let mut i = 0;
+    while i < kicks.len() {
+        kicks[i].0 += from_offsets[0].0 - to_offsets[0].0;
+        kicks[i].1 += from_offsets[0].1 - to_offsets[0].1;
+        i += 1;
+    }
+    kicks
+}
+
+/// TETR.IO's I piece kicks, which are symmetric unlike those of SRS.
+const fn srs_plus_i_kicks(from: Rotation, to: Rotation) -> [(i8, i8); 5] {
+    use Rotation::*;
+    match (from, to) {
+        (North, East) => [(0, 0), (1, 0), (-2, 0), (-2, -1), (1, 2)],
+        (East, North) => [(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)],
+        (East, South) => [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        (South, East) => [(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)],
+        (South, West) => [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        (West, South) => [(0, 0), (1, 0), (-2, 0), (1, 2), (-2, -1)],
+        (West, North) => [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+        (North, West) => [(0, 0), (-1, 0), (2, 0), (2, -1), (-1, 2)],
+        _ => [(0, 0); 5],
+    }
+}
+
+/// The kicks TETR.IO uses for 180 degree rotations, on top of the offset which keeps the piece
+/// rotating about its true center as the first SRS kick does.
+const fn kicks_180(piece: Piece, from: Rotation) -> [(i8, i8); 6] {
+    let mut kicks = match from {
+        Rotation::North => [(0, 0), (0, 1), (1, 1), (-1, 1), (1, 0), (-1, 0)],
+        Rotation::East => [(0, 0), (1, 0), (1, 2), (1, 1), (0, 2), (0, 1)],
+        Rotation::South => [(0, 0), (0, -1), (-1, -1), (1, -1), (-1, 0), (1, 0)],
+        Rotation::West => [(0, 0), (-1, 0), (-1, 2), (-1, 1), (0, 2), (0, 1)],
+    };
+    let from_offset = offsets(piece, from)[0];
+    let to_offset = offsets(piece, from.flip())[0];
     let mut i = 0;
     while i < kicks.len() {
-        kicks[i] = (from[i].0 - to[i].0, from[i].1 - to[i].1);
+        kicks[i].0 += from_offset.0 - to_offset.0;
+        kicks[i].1 += from_offset.1 - to_offset.1;
         i += 1;
     }
     kicks
 }
 
+/// Tries each kick in turn. A T-spin using the kick at index `full_spin_kick` is always a full
+/// spin, like the TST kick in SRS.
 fn rotate(
     unkicked: PieceLocation,
     collision_map: &CollisionMaps,
     board: &Board,
     kicks: impl Iterator<Item = (i8, i8)>,
+    full_spin_kick: Option<usize>,
+    all_spin: bool,
 ) -> Option<Placement> {
     for (i, (dx, dy)) in kicks.enumerate() {
         let target = PieceLocation {
@@ -264,7 +675,10 @@ fn rotate(
 
         let spin;
         if target.piece != Piece::T {
-            spin = Spin::None;
+            spin = match all_spin && collision_map.immobile(target) {
+                true => Spin::Full,
+                false => Spin::None,
+            };
         } else {
             let corners = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
                 .iter()
@@ -278,7 +692,7 @@ fn rotate(
 
             if corners < 3 {
                 spin = Spin::None;
-            } else if mini_corners == 2 || i == 4 {
+            } else if mini_corners == 2 || Some(i) == full_spin_kick {
                 spin = Spin::Full;
             } else {
                 spin = Spin::Mini;
@@ -318,6 +732,32 @@ impl PartialOrd for Intermediate {
     }
 }
 
+#[derive(Clone, Copy, Debug, Eq)]
+struct PathIntermediate {
+    /// Soft drops, then inputs.
+    cost: (u32, u32),
+    mv: Placement,
+}
+
+impl PartialEq for PathIntermediate {
+    fn eq(&self, other: &PathIntermediate) -> bool {
+        self.cost == other.cost
+    }
+}
+
+impl Ord for PathIntermediate {
+    fn cmp(&self, other: &PathIntermediate) -> Ordering {
+        // reversed so the heap gives the cheapest position first
+        other.cost.cmp(&self.cost)
+    }
+}
+
+impl PartialOrd for PathIntermediate {
+    fn partial_cmp(&self, other: &PathIntermediate) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 struct CollisionMaps {
     boards: [[u64; 10]; 4],
 }
@@ -353,4 +793,358 @@ impl CollisionMaps {
                 .unwrap_or(true);
         v
     }
+
+    fn immobile(&self, piece: PieceLocation) -> bool {
+        [(-1, 0), (1, 0), (0, -1), (0, 1)].iter().all(|&(dx, dy)| {
+            self.obstructed(PieceLocation {
+                x: piece.x + dx,
+                y: piece.y + dy,
+                ..piece
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use enumset::EnumSet;
+
+    use super::*;
+
+    fn placement(piece: Piece, rotation: Rotation, x: i8, y: i8, spin: Spin) -> Placement {
+        Placement {
+            location: PieceLocation {
+                piece,
+                rotation,
+                x,
+                y,
+            },
+            spin,
+        }
+    }
+
+    /// A T-spin double slot under an overhang at column 3.
+    fn tsd_board() -> Board {
+        Board::from_ascii("...#......\n#...######\n##.#######")
+    }
+
+    #[test]
+    fn found_moves_are_legal() {
+        let rules = MoveRules::default();
+        let board = tsd_board();
+        for piece in EnumSet::<Piece>::all() {
+            for (mv, _) in find_moves(&board, piece) {
+                assert!(is_legal(&board, &mv, &rules), "{:?}", mv);
+            }
+        }
+    }
+
+    #[test]
+    fn spin_into_covered_slot_is_legal() {
+        let rules = MoveRules::default();
+        let board = tsd_board();
+        let tsd = placement(Piece::T, Rotation::South, 2, 1, Spin::Full);
+        assert!(is_legal(&board, &tsd, &rules));
+        // the slot is covered, so the T can't get there without spinning
+        let dropped = Placement {
+            spin: Spin::None,
+            ..tsd
+        };
+        assert!(!is_legal(&board, &dropped, &rules));
+    }
+
+    #[test]
+    fn floating_and_overlapping_placements_are_illegal() {
+        let rules = MoveRules::default();
+        let board = tsd_board();
+        let floating = placement(Piece::T, Rotation::North, 6, 5, Spin::None);
+        assert!(!is_legal(&board, &floating, &rules));
+        let resting = placement(Piece::T, Rotation::North, 6, 2, Spin::None);
+        assert!(is_legal(&board, &resting, &rules));
+        let overlapping = placement(Piece::T, Rotation::North, 6, 1, Spin::None);
+        assert!(!is_legal(&board, &overlapping, &rules));
+    }
+
+    #[test]
+    fn soft_drop_limit_excludes_deeper_tucks() {
+        // the I soft drops to the floor and slides left under the overhang
+        let board = Board::from_ascii("###.......\n..........");
+        let tuck = placement(Piece::I, Rotation::North, 1, 0, Spin::None);
+        let moves_under = |max_soft_drop| {
+            let rules = MoveRules {
+                max_soft_drop,
+                ..MoveRules::default()
+            };
+            let mut moves = vec![];
+            find_moves_with_rules(
+                &board,
+                Piece::I,
+                &rules,
+                &mut Scratch::default(),
+                &mut moves,
+            );
+            moves
+        };
+        let needed = moves_under(u32::MAX)
+            .into_iter()
+            .find(|&(mv, _)| mv == tuck)
+            .map(|(_, soft_drop)| soft_drop)
+            .unwrap();
+        assert!(needed > 1);
+
+        let reaches = |max_soft_drop| moves_under(max_soft_drop).iter().any(|&(mv, _)| mv == tuck);
+        assert!(reaches(needed));
+        assert!(!reaches(needed - 1));
+        assert!(!reaches(1));
+        // with no soft drop at all, only placements reached by hard dropping remain
+        assert!(moves_under(0).len() < moves_under(u32::MAX).len());
+        assert!(moves_under(0).iter().all(|&(_, soft_drop)| soft_drop == 0));
+    }
+
+    #[test]
+    fn tucks_past_the_soft_drop_limit_are_illegal() {
+        let board = Board::from_ascii("###.......\n..........");
+        let tuck = placement(Piece::I, Rotation::North, 1, 0, Spin::None);
+        let no_soft_drop = MoveRules {
+            max_soft_drop: 0,
+            ..MoveRules::default()
+        };
+        assert!(is_legal(&board, &tuck, &MoveRules::default()));
+        assert!(!is_legal(&board, &tuck, &no_soft_drop));
+        // hard dropping right of the overhang still works
+        let open = placement(Piece::I, Rotation::North, 5, 0, Spin::None);
+        assert!(is_legal(&board, &open, &no_soft_drop));
+    }
+
+    #[test]
+    fn perfect_clear_moves_keep_perfect_clear_possible() {
+        let rules = MoveRules::default();
+        let mut scratch = Scratch::default();
+        let mut moves = vec![];
+        let board = Board::from_ascii("#########.\n#########.\n#########.\n#########.");
+
+        // only the I standing in the well clears the board
+        find_perfect_clear_moves(&board, Piece::I, 0, &rules, &mut scratch, &mut moves);
+        assert_eq!(moves.len(), 1);
+        let mut cleared = board;
+        cleared.place(moves[0].0.location);
+        assert_eq!(cleared.line_clears().count_ones(), 4);
+
+        find_perfect_clear_moves(&board, Piece::O, 0, &rules, &mut scratch, &mut moves);
+        assert!(moves.is_empty());
+
+        // with pieces to spare, only placements which stack too high or leave gaps that can't be
+        // filled are dropped
+        let flat = Board::from_ascii("####......");
+        find_perfect_clear_moves(&flat, Piece::O, 3, &rules, &mut scratch, &mut moves);
+        let all = find_moves(&flat, Piece::O);
+        assert!(!moves.is_empty() && moves.len() < all.len());
+        for (mv, _) in &moves {
+            let mut board = flat;
+            board.place(mv.location);
+            assert!(board.perfect_clear_possible(3));
+        }
+    }
+
+    #[test]
+    fn fast_mode_finds_the_same_placements() {
+        let boards = [
+            Board::default(),
+            tsd_board(),
+            Board::from_ascii("#.........\n##....#...\n###..##..#\n####.###.#"),
+            Board::from_ascii(&"#.#.#.#.#.\n".repeat(8)),
+        ];
+        for board in &boards {
+            for piece in EnumSet::<Piece>::all() {
+                let placements = |moves: Vec<(Placement, u32)>| -> HashSet<Placement> {
+                    moves.into_iter().map(|(mv, _)| mv).collect()
+                };
+                assert_eq!(
+                    placements(find_moves(board, piece)),
+                    placements(find_moves_without_fast_mode(board, piece)),
+                    "{:?} on\n{}",
+                    piece,
+                    board.diff_string(&Board::default()),
+                );
+            }
+        }
+    }
+
+    /// A board with rows up to 8 filled, except for `target` and the `open` cells.
+    fn boxed_in(target: PieceLocation, open: &[(i8, i8)]) -> Board {
+        let mut board = Board::default();
+        for x in 0..10 {
+            for y in 0..8 {
+                if !target.cells().contains(&(x, y)) && !open.contains(&(x, y)) {
+                    board.fill(x as usize, y as usize, '#');
+                }
+            }
+        }
+        board
+    }
+
+    #[test]
+    fn all_spin_counts_immobile_rotations() {
+        let spin_into = |target: PieceLocation, board: &Board, all_spin| {
+            let rules = MoveRules {
+                all_spin,
+                ..MoveRules::default()
+            };
+            let from = PieceLocation {
+                rotation: target.rotation.ccw(),
+                ..target
+            };
+            let collision_map = CollisionMaps::new(board, target.piece);
+            let mv = rotate_cw(from, &collision_map, board, &rules).unwrap();
+            assert_eq!(mv.location, target);
+            mv.spin
+        };
+
+        // rotating into a slot the piece fits exactly leaves it unable to move
+        for piece in [Piece::S, Piece::Z, Piece::L, Piece::J] {
+            let target = PieceLocation {
+                piece,
+                rotation: Rotation::East,
+                x: 4,
+                y: 3,
+            };
+            let board = boxed_in(target, &[]);
+            assert_eq!(spin_into(target, &board, true), Spin::Full, "{:?}", piece);
+            assert_eq!(spin_into(target, &board, false), Spin::None, "{:?}", piece);
+        }
+
+        // T keeps the 3-corner rule: boxed in with its corners open, it still isn't a spin
+        let t = PieceLocation {
+            piece: Piece::T,
+            rotation: Rotation::East,
+            x: 4,
+            y: 3,
+        };
+        let cornered = boxed_in(t, &[]);
+        let corners = [(3, 2), (5, 2), (3, 4), (5, 4)];
+        let open_corners = boxed_in(t, &corners);
+        for all_spin in [false, true] {
+            assert_eq!(spin_into(t, &cornered, all_spin), Spin::Full);
+            assert_eq!(spin_into(t, &open_corners, all_spin), Spin::None);
+        }
+    }
+
+    #[test]
+    fn kick_tables_kick_the_i_piece_differently() {
+        // a cell in the way of the I standing up where it was lying
+        let mut board = Board::default();
+        board.fill(5, 9, '#');
+        let collision_map = CollisionMaps::new(&board, Piece::I);
+        let lying = PieceLocation {
+            piece: Piece::I,
+            rotation: Rotation::North,
+            x: 4,
+            y: 10,
+        };
+        let standing_at = |kick_table| {
+            let rules = MoveRules {
+                kick_table,
+                ..MoveRules::default()
+            };
+            rotate_cw(lying, &collision_map, &board, &rules).map(|mv| mv.location.x)
+        };
+        // unobstructed, the I would stand in column 5; SRS tries two cells left first, SRS+ one
+        // cell right, and ARS never kicks the I
+        assert_eq!(standing_at(KickTable::Srs), Some(3));
+        assert_eq!(standing_at(KickTable::SrsPlus), Some(6));
+        assert_eq!(standing_at(KickTable::Ars), None);
+    }
+
+    #[test]
+    fn ars_kicks_right_then_left() {
+        let board = Board::default();
+        let collision_map = CollisionMaps::new(&board, Piece::T);
+        let rules = MoveRules {
+            kick_table: KickTable::Ars,
+            ..MoveRules::default()
+        };
+        let t = |rotation, x| PieceLocation {
+            piece: Piece::T,
+            rotation,
+            x,
+            y: 5,
+        };
+        // a T pointing away from a wall has to move over to lie flat
+        let left_wall = rotate_cw(t(Rotation::East, 0), &collision_map, &board, &rules);
+        assert_eq!(left_wall.unwrap().location, t(Rotation::South, 1));
+        let right_wall = rotate_ccw(t(Rotation::West, 9), &collision_map, &board, &rules);
+        assert_eq!(right_wall.unwrap().location, t(Rotation::South, 8));
+    }
+
+    /// Where `inputs` followed by a hard drop take a piece from spawn.
+    fn replay(board: &Board, piece: Piece, inputs: &[Input], rules: &MoveRules) -> Placement {
+        let collision_map = CollisionMaps::new(board, piece);
+        let mut mv = spawn(&collision_map, piece).unwrap();
+        for &input in inputs {
+            let drop_dist = mv.location.drop_distance(board);
+            let dropped = Placement {
+                location: PieceLocation {
+                    y: mv.location.y - drop_dist,
+                    ..mv.location
+                },
+                spin: Spin::None,
+            };
+            mv = match input {
+                Input::SoftDrop => Some(dropped),
+                _ => movements(mv.location, &collision_map, board, rules)
+                    .into_iter()
+                    .find(|&(i, _)| i == input)
+                    .and_then(|(_, next)| next),
+            }
+            .unwrap_or_else(|| panic!("{:?} isn't possible from {:?}", input, mv));
+        }
+        let drop_dist = mv.location.drop_distance(board);
+        Placement {
+            location: PieceLocation {
+                y: mv.location.y - drop_dist,
+                ..mv.location
+            }
+            .canonical_form(),
+            spin: if drop_dist == 0 { mv.spin } else { Spin::None },
+        }
+    }
+
+    #[test]
+    fn paths_lead_to_their_placements() {
+        let rules = MoveRules::default();
+        for board in [Board::default(), tsd_board()] {
+            for piece in EnumSet::<Piece>::all() {
+                let paths = find_moves_with_paths(&board, piece, &rules);
+                let moves: HashMap<_, _> = find_moves(&board, piece).into_iter().collect();
+                assert_eq!(paths.len(), moves.len());
+                for (mv, path) in paths {
+                    assert_eq!(replay(&board, piece, &path.inputs, &rules), mv);
+                    assert_eq!(Some(&path.soft_drop), moves.get(&mv), "{:?}", mv);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn paths_stay_within_the_soft_drop_limit() {
+        // the I has to soft drop to the floor and slide left to get under the overhang
+        let board = Board::from_ascii("###.......\n..........");
+        let tuck = placement(Piece::I, Rotation::North, 1, 0, Spin::None);
+        let path = path_to(&board, &tuck, &MoveRules::default()).unwrap();
+        assert_eq!(
+            replay(&board, Piece::I, &path.inputs, &MoveRules::default()),
+            tuck
+        );
+        let needed = path.soft_drop;
+        assert!(needed > 0);
+
+        let limited = |max_soft_drop| MoveRules {
+            max_soft_drop,
+            ..MoveRules::default()
+        };
+        assert!(path_to(&board, &tuck, &limited(needed)).is_some());
+        assert_eq!(path_to(&board, &tuck, &limited(needed - 1)), None);
+    }
 }
@@ -0,0 +1,86 @@
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::data::{GameState, Piece, Placement};
+use crate::tbp::{Move, MoveInfo};
+
+/// Records every suggestion the bot makes and every move played as JSON lines, so a game can be
+/// reconstructed and analyzed afterwards.
+pub struct ReplayLog {
+    out: Box<dyn Write>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Entry<'a> {
+    Suggestion {
+        state: State<'a>,
+        moves: &'a [Move],
+        move_info: &'a MoveInfo,
+    },
+    Play {
+        #[serde(rename = "move")]
+        mv: Placement,
+    },
+    Garbage {
+        column: u8,
+        lines: u8,
+    },
+}
+
+#[derive(Serialize)]
+struct State<'a> {
+    board: [u64; 10],
+    queue: &'a [Piece],
+    reserve: Piece,
+    bag: Vec<Piece>,
+    b2b: u16,
+    combo: u8,
+}
+
+impl ReplayLog {
+    pub fn new(out: impl Write + 'static) -> Self {
+        ReplayLog { out: Box::new(out) }
+    }
+
+    pub fn suggestion(
+        &mut self,
+        state: &GameState,
+        queue: &[Piece],
+        moves: &[Move],
+        move_info: &MoveInfo,
+    ) {
+        self.record(&Entry::Suggestion {
+            state: State {
+                board: state.board.cols,
+                queue,
+                reserve: state.reserve,
+                bag: state.bag.iter().collect(),
+                b2b: state.b2b,
+                combo: state.combo,
+            },
+            moves,
+            move_info,
+        });
+    }
+
+    pub fn play(&mut self, mv: Placement) {
+        self.record(&Entry::Play { mv });
+    }
+
+    pub fn garbage(&mut self, column: u8, lines: u8) {
+        self.record(&Entry::Garbage { column, lines });
+    }
+
+    fn record(&mut self, entry: &Entry) {
+        let result = serde_json::to_writer(&mut self.out, entry)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(self.out))
+            .and_then(|_| self.out.flush());
+        if let Err(e) = result {
+            eprintln!("warning: failed to write replay log: {}", e);
+        }
+    }
+}
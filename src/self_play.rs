@@ -0,0 +1,163 @@
+//! Plays games against a simulated opponent on the current thread, for comparing configurations
+//! without a frontend.
+
+use std::sync::Arc;
+
+use enumset::EnumSet;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::bot::{Bot, BotOptions, Statistics};
+use crate::data::{Board, GameState, GarbagePattern, Piece};
+use crate::BotConfig;
+
+/// How many pieces the bot is shown ahead of the current one.
+const PREVIEWS: usize = 5;
+
+/// Garbage sent to the bot at a steady rate.
+#[derive(Clone, Copy, Debug)]
+pub struct Garbage {
+    pub pattern: GarbagePattern,
+    /// Lines of garbage sent each time.
+    pub lines: u32,
+    /// Pieces between batches of garbage.
+    pub interval: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Pieces to play before stopping.
+    pub pieces: u32,
+    /// Nodes to search before each move.
+    pub nodes: u64,
+    /// Seeds both the pieces and the garbage holes.
+    pub seed: u64,
+    pub garbage: Option<Garbage>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Outcome {
+    pub pieces: u32,
+    pub lines: u32,
+    /// Lines of attack sent after cancelling incoming garbage.
+    pub attack: u32,
+    /// Lines of garbage that made it onto the board.
+    pub garbage: u32,
+    /// How many pieces it took to clear 40 lines, if that happened.
+    pub sprint_pieces: Option<u32>,
+    /// Whether the game ended early because the bot had no move or garbage pushed cells out of
+    /// the top of the board.
+    pub topped_out: bool,
+}
+
+/// Plays a game with pieces from a 7-bag. Attack first cancels incoming garbage; whatever is left
+/// rises onto the board after the next placement that doesn't clear lines.
+pub fn play(config: Arc<BotConfig>, options: &Options) -> Result<Outcome, String> {
+    if let Some(garbage) = &options.garbage {
+        garbage.pattern.validate()?;
+    }
+
+    let mut rng = SmallRng::seed_from_u64(options.seed);
+    let mut bag = EnumSet::all();
+    let mut draw = |rng: &mut SmallRng| {
+        let piece = bag.iter().nth(rng.gen_range(0..bag.len())).unwrap();
+        bag.remove(piece);
+        if bag.is_empty() {
+            bag = EnumSet::all();
+        }
+        piece
+    };
+
+    let first = draw(&mut rng);
+    let root = GameState::new(Board::default(), first, EnumSet::all() - first, 0, 0);
+    let queue: Vec<Piece> = (0..PREVIEWS).map(|_| draw(&mut rng)).collect();
+    let bot_options = BotOptions {
+        speculate: true,
+        config,
+    };
+    let mut bot = Bot::new(bot_options, root, &queue);
+
+    let mut outcome = Outcome::default();
+    let mut incoming = 0;
+    while outcome.pieces < options.pieces {
+        let mut stats = Statistics::default();
+        while stats.nodes < options.nodes {
+            let step = bot.do_work(&mut rng);
+            if step.expansions == 0 {
+                break;
+            }
+            stats.accumulate(step);
+            if bot.over_node_cap() {
+                bot.evict();
+            }
+        }
+
+        let info = match bot.suggest().first() {
+            Some(&mv) => bot.advance(mv).unwrap(),
+            None => {
+                outcome.topped_out = true;
+                break;
+            }
+        };
+        bot.new_piece(draw(&mut rng));
+        outcome.pieces += 1;
+        outcome.lines += info.lines_cleared;
+        if outcome.lines >= 40 && outcome.sprint_pieces.is_none() {
+            outcome.sprint_pieces = Some(outcome.pieces);
+        }
+
+        outcome.attack += info.cancel_garbage(&mut incoming);
+        if let Some(garbage) = &options.garbage {
+            if outcome.pieces % garbage.interval.max(1) == 0 {
+                incoming += garbage.lines;
+            }
+            if info.lines_cleared == 0 && incoming > 0 {
+                outcome.garbage += incoming;
+                if bot.insert_garbage(incoming, garbage.pattern, &mut rng) {
+                    outcome.topped_out = true;
+                    break;
+                }
+                incoming = 0;
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(garbage: Option<Garbage>) -> Options {
+        Options {
+            pieces: 30,
+            nodes: 500,
+            seed: 0,
+            garbage,
+        }
+    }
+
+    #[test]
+    fn garbage_rises_onto_board() {
+        let garbage = Garbage {
+            pattern: GarbagePattern::Messy { change_chance: 0.5 },
+            lines: 1,
+            interval: 3,
+        };
+        let config = Arc::new(BotConfig::default());
+        let outcome = play(config, &options(Some(garbage))).unwrap();
+        assert_eq!(outcome.pieces, 30);
+        assert!(outcome.garbage > 0);
+    }
+
+    #[test]
+    fn rejects_invalid_pattern() {
+        let garbage = Garbage {
+            pattern: GarbagePattern::Messy { change_chance: 2.0 },
+            lines: 1,
+            interval: 3,
+        };
+        let config = Arc::new(BotConfig::default());
+        assert!(play(config, &options(Some(garbage))).is_err());
+    }
+}
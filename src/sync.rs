@@ -1,10 +1,13 @@
-use std::time::Instant;
+use std::panic::AssertUnwindSafe;
+use std::time::{Duration, Instant};
 
 use parking_lot::{Condvar, Mutex, RwLock};
+use rand::rngs::SmallRng;
+use rand::{thread_rng, RngCore, SeedableRng};
 
-use crate::bot::{Bot, Statistics};
-use crate::data::{Piece, Placement};
-use crate::tbp::MoveInfo;
+use crate::bot::{AdvanceError, Bot, Statistics};
+use crate::data::{GameState, Piece, Placement, PlacementInfo};
+use crate::tbp::{GameStats, MoveInfo};
 
 pub struct BotSyncronizer {
     state: Mutex<State>,
@@ -21,6 +24,12 @@ impl BotSyncronizer {
                 node_limit: u64::MAX,
                 start: Instant::now(),
                 nodes_since_start: 0,
+                first_advance: None,
+                pieces: 0,
+                attack: 0,
+                last_suggestion: None,
+                game: 0,
+                shutdown: false,
             }),
             blocker: Condvar::new(),
             bot: RwLock::new(None),
@@ -32,43 +41,182 @@ impl BotSyncronizer {
         state.stats = Default::default();
         state.nodes_since_start = 0;
         state.start = Instant::now();
+        state.last_advance = state.start;
+        state.first_advance = None;
+        state.pieces = 0;
+        state.attack = 0;
+        state.last_suggestion = None;
+        state.game += 1;
+        state.node_limit = initial_state.config().nodes_per_move();
         *self.bot.write() = Some(initial_state);
         self.blocker.notify_all();
     }
 
+    /// Changes how many nodes are searched per move in the current game, resuming search if the
+    /// new limit is higher.
+    pub fn set_node_limit(&self, limit: u64) {
+        let mut state = self.state.lock();
+        state.node_limit = limit;
+        self.blocker.notify_all();
+    }
+
     pub fn stop(&self) {
         *self.bot.write() = None;
     }
 
-    pub fn suggest(&self) -> Option<(Vec<Placement>, MoveInfo)> {
+    /// Stops the current game and makes every `work_loop` return.
+    pub fn shutdown(&self) {
+        let mut state = self.state.lock();
+        state.shutdown = true;
+        *self.bot.write() = None;
+        self.blocker.notify_all();
+    }
+
+    /// Returns the current best move without doing any search. This only reads the root's
+    /// children, which backpropagation keeps sorted, and the node counters for the statistics.
+    /// At worst it waits for a worker to finish expanding the root node, or for a move being
+    /// played to finish.
+    ///
+    /// Also gives whether the moves differ from those returned by the previous call since the
+    /// root last changed, for frontends that only want to hear about changes.
+    pub fn suggest(&self) -> Option<(Vec<Placement>, MoveInfo, bool)> {
+        // Locks are taken in the same order as everywhere else (state, then bot) and both are
+        // held throughout, so the suggestion and statistics always describe the same root.
+        let mut state = self.state.lock();
         let bot = self.bot.read();
         bot.as_ref().map(|bot| {
-            let state = self.state.lock();
             let suggestion = bot.suggest();
+            let (tree_nodes, tree_bytes) = bot.tree_size();
             let info = MoveInfo {
                 nodes: state.stats.nodes,
-                nps: state.stats.nodes as f64 / state.last_advance.elapsed().as_secs_f64(),
+                nps: per_second(state.stats.nodes as f64, state.last_advance.elapsed()),
+                depth: bot.depth(),
                 extra: format!(
-                    "{:.1}% of selections expanded, overall speed: {:.1} Mnps",
-                    state.stats.expansions as f64 / state.stats.selections as f64 * 100.0,
-                    state.nodes_since_start as f64 / state.start.elapsed().as_secs_f64() / 1_000_000.0
-                )
+                    "{:.1}% of selections expanded, overall speed: {:.1} Mnps, \
+                     tree: {} positions (~{:.0} MB)",
+                    state.stats.expansions as f64 / state.stats.selections.max(1) as f64 * 100.0,
+                    per_second(state.nodes_since_start as f64, state.start.elapsed()) / 1_000_000.0,
+                    tree_nodes,
+                    tree_bytes as f64 / 1_000_000.0,
+                ),
+                confidence: bot.confidence(),
+                progress: state.progress(),
             };
-            (suggestion, info)
+            let changed = state.last_suggestion.as_ref() != Some(&suggestion);
+            state.last_suggestion = Some(suggestion.clone());
+            (suggestion, info, changed)
         })
     }
 
-    pub fn advance(&self, mv: Placement) {
+    /// Like `suggest`, but first waits as `wait_to_suggest` does.
+    pub fn suggest_after(&self, think: Duration) -> Option<(Vec<Placement>, MoveInfo, bool)> {
+        self.wait_to_suggest(think);
+        self.suggest()
+    }
+
+    /// Blocks until search has been running on the current root for `think`, until the node
+    /// limit has been reached, or until the game ends. Whether the tree has been searched
+    /// completely isn't tracked, so that case waits out the full time.
+    pub fn wait_to_suggest(&self, think: Duration) {
+        let mut state = self.state.lock();
+        while self.bot.read().is_some() && state.stats.nodes <= state.node_limit {
+            // the root can change while we wait, which restarts the clock
+            let deadline = state.last_advance + think;
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            // parking_lot uses its own Instant type on wasm, so wait for a duration instead
+            self.blocker.wait_for(&mut state, deadline - now);
+        }
+    }
+
+    /// The principal variation and the moves available from the root, if a game is in progress.
+    #[allow(clippy::type_complexity)]
+    pub fn analysis(&self) -> Option<(Vec<(PlacementInfo, f64)>, Vec<(Placement, f64, u32)>)> {
+        let bot = self.bot.read();
+        bot.as_ref()
+            .map(|bot| (bot.principal_variation(), bot.root_moves()))
+    }
+
+    /// Plays a move. A move which overlaps the board ends the game; one played before the piece
+    /// after it is known is rejected, leaving the game as it was.
+    pub fn advance(&self, mv: Placement) -> Result<(), String> {
+        let mut state = self.state.lock();
+        let mut bot = self.bot.write();
+        let result = match &mut *bot {
+            Some(b) => match b.advance(mv) {
+                Ok(info) => {
+                    state.stats = Default::default();
+                    state.last_advance = Instant::now();
+                    state.last_suggestion = None;
+                    let now = state.last_advance;
+                    state.first_advance.get_or_insert(now);
+                    state.pieces += 1;
+                    state.attack += info.attack;
+                    Ok(())
+                }
+                Err(e @ AdvanceError::NoNextPiece) => {
+                    eprintln!("warning: {}, ignoring the move", e);
+                    Err(format!("{}, send new_piece before play", e))
+                }
+                Err(e @ AdvanceError::Overlaps(..)) => {
+                    eprintln!("warning: {}, stopping", e);
+                    *bot = None;
+                    Err(format!("{}, stopping", e))
+                }
+            },
+            None => Err("no game is in progress".to_owned()),
+        };
+        self.blocker.notify_all();
+        result
+    }
+
+    pub fn add_garbage(&self, column: u8, lines: u8) {
         let mut state = self.state.lock();
         state.stats = Default::default();
         state.last_advance = Instant::now();
+        state.last_suggestion = None;
         let mut bot = self.bot.write();
-        if let Some(bot) = &mut *bot {
-            bot.advance(mv);
+        if let Some(b) = &mut *bot {
+            if b.add_garbage(column, lines) {
+                eprintln!("warning: garbage pushed the stack out of the top of the board");
+            }
         }
         self.blocker.notify_all();
     }
 
+    /// Undoes the most recent move if the bot keeps history. Returns whether a move was undone.
+    pub fn rewind(&self) -> bool {
+        let mut state = self.state.lock();
+        let mut bot = self.bot.write();
+        let rewound = bot.as_mut().is_some_and(|bot| bot.rewind());
+        if rewound {
+            state.stats = Default::default();
+            state.last_advance = Instant::now();
+            state.last_suggestion = None;
+        }
+        self.blocker.notify_all();
+        rewound
+    }
+
+    /// The current root state and known queue, if a game is in progress.
+    pub fn state(&self) -> Option<(GameState, Vec<Piece>)> {
+        let bot = self.bot.read();
+        bot.as_ref().map(|bot| bot.state())
+    }
+
+    pub fn game_stats(&self) -> GameStats {
+        let state = self.state.lock();
+        let elapsed = state.first_advance.map_or(Duration::ZERO, |t| t.elapsed());
+        GameStats {
+            pieces: state.pieces,
+            attack: state.attack,
+            pps: per_second(state.pieces as f64, elapsed),
+            apm: per_second(state.attack as f64, elapsed) * 60.0,
+        }
+    }
+
     pub fn new_piece(&self, piece: Piece) {
         let mut bot = self.bot.write();
         if let Some(bot) = &mut *bot {
@@ -77,9 +225,16 @@ impl BotSyncronizer {
         self.blocker.notify_all();
     }
 
-    pub fn work_loop(&self) {
+    /// Runs search on the current bot until `shutdown` is called. `worker` distinguishes the
+    /// search threads so each derives a different random seed from a configured one.
+    pub fn work_loop(&self, worker: u64) {
+        let mut rng: Box<dyn RngCore> = Box::new(thread_rng());
+        let mut game = 0;
         let mut state = self.state.lock();
         loop {
+            if state.shutdown {
+                return;
+            }
             if state.stats.nodes > state.node_limit {
                 self.blocker.wait(&mut state);
                 continue;
@@ -94,22 +249,226 @@ impl BotSyncronizer {
                 }
             };
 
+            if game != state.game {
+                // Reseed at the start of every game so a seeded game doesn't depend on what was
+                // searched in the previous one.
+                game = state.game;
+                rng = match bot.config().seed {
+                    Some(seed) => Box::new(SmallRng::seed_from_u64(seed.wrapping_add(worker))),
+                    None => Box::new(thread_rng()),
+                };
+            }
+
             drop(state);
-            let new_stats = bot.do_work();
+            // The read guard must be held until the work is done; anything that restructures the
+            // tree (advance, new_piece) takes the write lock and so waits for us to finish.
+            // A panic releases the node it was expanding as the selection unwinds, so the worker
+            // survives and the node can be selected again.
+            let new_stats = std::panic::catch_unwind(AssertUnwindSafe(|| bot.do_work(&mut *rng)))
+                .unwrap_or_else(|_| {
+                    eprintln!("warning: search panicked while expanding a node");
+                    Statistics::default()
+                });
+            let over_node_cap = bot.over_node_cap();
             drop(bot_guard);
 
             state = self.state.lock();
+            if over_node_cap {
+                if let Some(bot) = &mut *self.bot.write() {
+                    bot.evict();
+                }
+            }
             state.stats.accumulate(new_stats);
             state.nodes_since_start += new_stats.nodes;
+            if state.stats.nodes > state.node_limit {
+                // wake anyone waiting in suggest_after
+                self.blocker.notify_all();
+            }
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 struct State {
     stats: Statistics,
     last_advance: Instant,
     node_limit: u64,
     start: Instant,
     nodes_since_start: u64,
+    first_advance: Option<Instant>,
+    pieces: u32,
+    attack: u32,
+    /// The moves last returned by `suggest` for the current root.
+    last_suggestion: Option<Vec<Placement>>,
+    /// Counts calls to `start`, so workers know when to reseed.
+    game: u64,
+    shutdown: bool,
+}
+
+/// `count` divided by `elapsed` in seconds. Right after a game starts or a move is played the
+/// elapsed time can be too small to give a meaningful rate, or zero, which would give infinity
+/// or NaN; JSON can't represent those, so this reports 0 instead.
+fn per_second(count: f64, elapsed: Duration) -> f64 {
+    const MIN_ELAPSED: Duration = Duration::from_millis(1);
+    match elapsed >= MIN_ELAPSED {
+        true => count / elapsed.as_secs_f64(),
+        false => 0.0,
+    }
+}
+
+impl State {
+    fn progress(&self) -> Option<f64> {
+        (self.node_limit != u64::MAX)
+            .then(|| (self.stats.nodes as f64 / self.node_limit as f64).min(1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use enumset::EnumSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use rand::seq::SliceRandom;
+
+    use super::*;
+    use crate::bot::BotOptions;
+    use crate::data::Board;
+    use crate::movegen::is_legal;
+    use crate::BotConfig;
+
+    #[test]
+    fn rates_are_finite_right_after_start() {
+        use Piece::*;
+        let options = BotOptions {
+            speculate: true,
+            config: Arc::new(BotConfig::default()),
+        };
+        let root = GameState::new(Board::default(), I, EnumSet::all(), 0, 0);
+        let bot = BotSyncronizer::new();
+        bot.start(Bot::new(options, root, &[O, T, L, J]));
+
+        // hardly any time has passed and no worker is searching, so there is no rate yet
+        let (_, info, _) = bot.suggest().unwrap();
+        assert!(info.nps.is_finite());
+        assert_eq!(info.nps, 0.0);
+        let json = serde_json::to_value(&info).unwrap();
+        assert!(json["nps"].is_number());
+
+        let stats = bot.game_stats();
+        assert_eq!((stats.pps, stats.apm), (0.0, 0.0));
+    }
+
+    #[test]
+    fn per_second_guards_tiny_intervals() {
+        assert_eq!(per_second(100.0, Duration::ZERO), 0.0);
+        assert_eq!(per_second(100.0, Duration::from_micros(10)), 0.0);
+        assert_eq!(per_second(100.0, Duration::from_millis(500)), 200.0);
+    }
+
+    #[test]
+    fn new_pieces_while_searching_keep_the_tree_consistent() {
+        use Piece::*;
+        let options = BotOptions {
+            speculate: true,
+            config: Arc::new(BotConfig::default()),
+        };
+        let root = GameState::new(Board::default(), I, EnumSet::all() - I, 0, 0);
+        let bot = Arc::new(BotSyncronizer::new());
+        bot.start(Bot::new(options, root, &[O, T, L, J]));
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let bot = bot.clone();
+                std::thread::spawn(move || bot.work_loop(worker))
+            })
+            .collect();
+
+        // finish the first bag, then keep despeculating layers while the workers search them
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut pieces = vec![S, Z];
+        for i in 0..200 {
+            if pieces.is_empty() {
+                pieces = EnumSet::<Piece>::all().iter().collect();
+                pieces.shuffle(&mut rng);
+            }
+            bot.new_piece(pieces.pop().unwrap());
+            if i % 20 == 0 {
+                // the write lock waits for every worker to finish its expansion
+                let guard = bot.bot.write();
+                guard.as_ref().unwrap().check_invariants();
+            }
+        }
+        bot.bot.write().as_ref().unwrap().check_invariants();
+        assert_eq!(bot.state().unwrap().1.len(), 4 + 200);
+        assert!(bot.suggest().is_some_and(|(moves, _, _)| !moves.is_empty()));
+
+        bot.shutdown();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn suggestions_fit_the_root_while_moves_are_played() {
+        use Piece::*;
+        let config = Arc::new(BotConfig::default());
+        let options = BotOptions {
+            speculate: true,
+            config: config.clone(),
+        };
+        let root = GameState::new(Board::default(), I, EnumSet::all() - I, 0, 0);
+        let bot = Arc::new(BotSyncronizer::new());
+        bot.start(Bot::new(options, root, &[O, T, L, J, S]));
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let bot = bot.clone();
+                std::thread::spawn(move || bot.work_loop(worker))
+            })
+            .collect();
+        // another frontend thread asking for suggestions as fast as it can
+        let done = Arc::new(AtomicBool::new(false));
+        let suggester = {
+            let bot = bot.clone();
+            let done = done.clone();
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    bot.suggest();
+                }
+            })
+        };
+
+        let rules = config.move_rules();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut pieces = vec![Z];
+        for _ in 0..60 {
+            let moves = loop {
+                match bot.suggest() {
+                    Some((moves, _, _)) if !moves.is_empty() => break moves,
+                    Some(_) => std::thread::sleep(Duration::from_millis(1)),
+                    None => panic!("the game ended"),
+                }
+            };
+            // nothing else plays moves, so the root can't have changed since the suggestion
+            let (state, queue) = bot.state().unwrap();
+            let mv = moves[0];
+            assert!([state.reserve, queue[0]].contains(&mv.location.piece));
+            assert!(is_legal(&state.board, &mv, &rules), "{:?}", mv);
+            bot.advance(mv).unwrap();
+
+            if pieces.is_empty() {
+                pieces = EnumSet::<Piece>::all().iter().collect();
+                pieces.shuffle(&mut rng);
+            }
+            bot.new_piece(pieces.pop().unwrap());
+        }
+        bot.bot.write().as_ref().unwrap().check_invariants();
+
+        done.store(true, Ordering::Relaxed);
+        suggester.join().unwrap();
+        bot.shutdown();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
 }
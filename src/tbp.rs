@@ -1,13 +1,22 @@
 use enumset::{EnumSet, EnumSetType};
 use serde::{Deserialize, Serialize};
 
+use crate::bot::BotConfig;
 use crate::data::{Board, Piece, Placement};
+use crate::movegen::{Input, KickTable};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum FrontendMessage {
-    Rules,
+    Rules {
+        /// Optional features the frontend wants enabled, out of those listed in our info.
+        #[serde(default)]
+        features: Vec<String>,
+        /// Extension: the rotation system of the game, overriding the configured one.
+        #[serde(default)]
+        kick_table: Option<KickTable>,
+    },
     Start(Start),
     Play {
         #[serde(rename = "move")]
@@ -16,9 +25,48 @@ pub enum FrontendMessage {
     NewPiece {
         piece: Piece,
     },
-    Suggest,
+    Suggest {
+        /// Extension: search for this many milliseconds since the last move before answering.
+        /// Other messages are still handled while waiting. A later `suggest`, or a `start`,
+        /// `stop` or `play`, replaces the pending answer.
+        #[serde(default)]
+        think_ms: Option<u64>,
+    },
     Stop,
     Quit,
+    GetConfig,
+    GetStats,
+    Analyze,
+    /// Extension: replaces the configured `node_limit`, for this game and those after it.
+    SetNodeLimit {
+        #[serde(default)]
+        limit: Option<u64>,
+    },
+    /// Extension: answered immediately with `pong`, whatever the bot is doing. For supervisors
+    /// checking that the bot is still responsive.
+    Ping,
+    /// Extension: undo the last move played. Only has an effect with `keep_history` enabled.
+    Rewind,
+    /// Extension: `lines` rows of garbage with the hole in `column` were pushed into the bottom
+    /// of the board. Search starts over from the new board. A `column` off the board is answered
+    /// with `error` and ignored.
+    Garbage {
+        column: u8,
+        lines: u8,
+    },
+    /// Replaces the weights used by games started after this message.
+    SetWeights {
+        #[serde(default)]
+        freestyle_weights: Option<serde_json::Value>,
+        #[serde(default)]
+        sprint_weights: Option<serde_json::Value>,
+        #[serde(default)]
+        downstack_weights: Option<serde_json::Value>,
+    },
+    /// A line which couldn't be parsed as a message, with the reason. Never deserialized; see
+    /// `parse_message`.
+    #[serde(skip_deserializing)]
+    Invalid(String),
     #[serde(other)]
     Unknown,
 }
@@ -31,13 +79,30 @@ pub enum BotMessage {
         name: &'static str,
         version: &'static str,
         author: &'static str,
-        features: &'static [&'static str],
+        features: Vec<&'static str>,
     },
     Ready,
     Suggestion {
-        moves: Vec<Placement>,
+        moves: Vec<Move>,
         move_info: MoveInfo,
-    }
+    },
+    Config {
+        config: Box<BotConfig>,
+    },
+    Stats(GameStats),
+    /// Sent instead of a suggestion identical to the last one when the frontend enabled the
+    /// `suggest_changes_only` feature.
+    Unchanged,
+    Pong,
+    Analysis {
+        pv: Vec<PvStep>,
+        moves: Vec<MoveStats>,
+    },
+    /// Extension: a message from the frontend couldn't be handled, such as a malformed message,
+    /// a message type we don't know, or a suggestion requested with no game in progress.
+    Error {
+        message: String,
+    },
 }
 
 #[derive(Deserialize)]
@@ -47,11 +112,15 @@ pub struct Start {
     pub hold: Option<Piece>,
     pub combo: u32,
     pub back_to_back: bool,
+    /// Extension: the number of consecutive hard clears so far, for frontends which track the
+    /// length of the back-to-back chain. Only `back_to_back` is used if this is absent.
+    #[serde(default)]
+    pub b2b_chain: Option<u16>,
     #[serde(default)]
     pub randomizer: Randomizer,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum Randomizer {
@@ -59,34 +128,118 @@ pub enum Randomizer {
         #[serde(deserialize_with = "collect_enumset")]
         bag_state: EnumSet<Piece>,
     },
+    /// Extension: every piece is drawn uniformly and independently of the ones before it.
+    Memoryless,
+    /// Extension: the NES randomizer, which rerolls once if it draws the previous piece again,
+    /// making repeats less likely.
+    Classic,
     #[serde(other)]
+    #[default]
     Unknown,
 }
 
-impl Default for Randomizer {
-    fn default() -> Self {
-        Self::Unknown
-    }
+/// One move of the principal variation and what it does.
+#[derive(Serialize)]
+pub struct PvStep {
+    #[serde(flatten)]
+    pub placement: Placement,
+    /// The evaluation of the position after this move.
+    pub eval: f64,
+    pub lines_cleared: u32,
+    pub back_to_back: bool,
+    /// The number of consecutive hard clears after this move.
+    pub b2b_chain: u16,
+    pub perfect_clear: bool,
+    pub hold: bool,
+}
+
+/// A move available from the current position and how much it has been searched.
+#[derive(Serialize)]
+pub struct MoveStats {
+    #[serde(flatten)]
+    pub placement: Placement,
+    pub eval: f64,
+    pub visits: u32,
+}
+
+#[derive(Serialize)]
+pub struct Move {
+    #[serde(flatten)]
+    pub placement: Placement,
+    /// The cells the piece occupies once placed, if the frontend asked for them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cells: Option<[(i8, i8); 4]>,
+    /// The inputs which reach this placement from spawn, for the first suggested move if the
+    /// frontend enabled the `move_path` feature.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<Vec<Input>>,
+    /// Extension: whether playing this move means swapping the current piece with hold.
+    pub hold: bool,
 }
 
 #[derive(Serialize)]
 pub struct MoveInfo {
     pub nodes: u64,
     pub nps: f64,
+    /// Extension: how many moves deep positions have been expanded, counting moves whose piece
+    /// is only speculated.
+    pub depth: u32,
     pub extra: String,
+    /// How much better the suggested move evaluates than the average available move. Small
+    /// values mean every option looks about equally good (or bad).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    /// Fraction of the search budget used so far, if the search is bounded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<f64>,
 }
 
-impl From<Vec<[Option<char>; 10]>> for Board {
-    fn from(v: Vec<[Option<char>; 10]>) -> Self {
-        let mut cols = [0; 10];
-        for x in 0..10 {
-            for y in 0..40 {
-                if v[y][x].is_some() {
-                    cols[x] |= 1 << y;
+#[derive(Serialize)]
+pub struct GameStats {
+    pub pieces: u32,
+    pub attack: u32,
+    pub pps: f64,
+    pub apm: f64,
+}
+
+// Frontends don't always send exactly 40 rows of 10 cells, so missing rows and cells are treated
+// as empty and anything outside the 10x40 board is ignored.
+impl From<Vec<Vec<Option<char>>>> for Board {
+    fn from(v: Vec<Vec<Option<char>>>) -> Self {
+        let mut board = Board::default();
+        for (y, row) in v.iter().take(40).enumerate() {
+            for (x, &cell) in row.iter().take(10).enumerate() {
+                if let Some(c) = cell {
+                    board.fill(x, y, c);
                 }
             }
         }
-        Board { cols }
+        board
+    }
+}
+
+// We don't know which piece filled a cell, so filled cells are written as garbage. With the
+// `garbage-plane` feature, cells which didn't come from garbage are written as `X` instead so that
+// they're still distinguishable after reading the board back in.
+impl From<Board> for Vec<[Option<char>; 10]> {
+    fn from(board: Board) -> Self {
+        (0..40)
+            .map(|y| {
+                let mut row = [None; 10];
+                for (x, cell) in row.iter_mut().enumerate() {
+                    if !board.occupied((x as i8, y as i8)) {
+                        continue;
+                    }
+                    #[cfg(feature = "garbage-plane")]
+                    if board.garbage[x] & 1 << y == 0 {
+                        *cell = Some('X');
+                        continue;
+                    }
+                    *cell = Some('G');
+                }
+                row
+            })
+            .collect()
     }
 }
 
@@ -97,3 +250,74 @@ where
 {
     Ok(Vec::<T>::deserialize(de)?.into_iter().collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// A board as JSON with `rows` rows of `width` cells, where the bottom row and the cells of
+    /// the rightmost column are filled.
+    fn board_json(rows: usize, width: usize) -> String {
+        let rows: Vec<Vec<Option<char>>> = (0..rows)
+            .map(|y| {
+                (0..width)
+                    .map(|x| (y == 0 || x == width - 1).then_some('G'))
+                    .collect()
+            })
+            .collect();
+        serde_json::to_string(&rows).unwrap()
+    }
+
+    #[test]
+    fn short_board_is_padded() {
+        let board: Board = serde_json::from_str(&board_json(20, 10)).unwrap();
+        assert_eq!(board.cols[0], 1);
+        assert_eq!(board.cols[9], (1 << 20) - 1);
+    }
+
+    #[test]
+    fn tall_board_is_clipped() {
+        let board: Board = serde_json::from_str(&board_json(44, 10)).unwrap();
+        assert_eq!(board.cols[0], 1);
+        assert_eq!(board.cols[9], (1 << 40) - 1);
+    }
+
+    #[test]
+    fn ragged_rows_are_padded_and_clipped() {
+        let narrow: Board = serde_json::from_str(&board_json(40, 8)).unwrap();
+        assert_eq!(narrow.cols[7], (1 << 40) - 1);
+        assert_eq!(narrow.cols[8], 0);
+        let wide: Board = serde_json::from_str(&board_json(40, 12)).unwrap();
+        assert_eq!(wide.cols[9], 1);
+        assert!(serde_json::from_str::<Board>("[]")
+            .unwrap()
+            .cols
+            .iter()
+            .all(|&c| c == 0));
+    }
+
+    /// Boards with any cells filled in the 40 rows TBP sends.
+    fn any_board() -> impl Strategy<Value = Board> {
+        let cols = || prop::array::uniform10(0..1u64 << 40);
+        #[cfg(not(feature = "garbage-plane"))]
+        return cols().prop_map(|cols| Board { cols });
+        // garbage cells are a subset of the filled ones
+        #[cfg(feature = "garbage-plane")]
+        return (cols(), cols()).prop_map(|(cols, mut garbage)| {
+            for (g, c) in garbage.iter_mut().zip(&cols) {
+                *g &= c;
+            }
+            Board { cols, garbage }
+        });
+    }
+
+    proptest! {
+        #[test]
+        fn board_round_trips_through_json(board in any_board()) {
+            let json = serde_json::to_string(&board).unwrap();
+            prop_assert_eq!(serde_json::from_str::<Board>(&json).unwrap(), board);
+        }
+    }
+}
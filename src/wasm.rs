@@ -0,0 +1,167 @@
+//! A bot for the browser, enabled by the `wasm` feature. There are no threads or clocks on
+//! `wasm32-unknown-unknown`, so instead of background workers searching for a while, JavaScript
+//! calls `step` to search a fixed number of nodes at a time (from a timer, an animation frame, or a
+//! loop in a web worker) and polls `suggest` for the best move found so far.
+//!
+//! Arguments are the JSON bodies of the corresponding TBP messages, without the `type` field.
+//! Suggestions come back as whole TBP `suggestion` messages.
+
+use std::sync::Arc;
+
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+use wasm_bindgen::prelude::*;
+
+use crate::bot::{AdvanceError, Bot};
+use crate::data::{Piece, Placement};
+use crate::tbp::{self, BotMessage, MoveInfo};
+use crate::{add_first_piece, create_bot, read_config, start_hold_empty, tbp_moves, BotConfig};
+
+#[wasm_bindgen]
+pub struct WasmBot {
+    config: Arc<BotConfig>,
+    bot: Option<Bot>,
+    waiting_on_first_piece: Option<tbp::Start>,
+    hold_empty: bool,
+    /// Nodes searched since the root last changed.
+    nodes: u64,
+    rng: SmallRng,
+}
+
+#[wasm_bindgen]
+impl WasmBot {
+    /// Creates a bot with the given JSON configuration, or the default one.
+    #[wasm_bindgen(constructor)]
+    pub fn new(config_json: Option<String>) -> Result<WasmBot, JsError> {
+        let config = match config_json {
+            Some(json) => read_config(json.as_bytes(), false).map_err(|e| JsError::new(&e))?,
+            None => Arc::new(BotConfig::default()),
+        };
+        let rng = match config.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        Ok(WasmBot {
+            config,
+            bot: None,
+            waiting_on_first_piece: None,
+            hold_empty: false,
+            nodes: 0,
+            rng,
+        })
+    }
+
+    /// Starts a new game from a TBP `start` message.
+    pub fn start(&mut self, start_json: &str) -> Result<(), JsError> {
+        let start: tbp::Start = serde_json::from_str(start_json)?;
+        self.begin(start);
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.bot = None;
+        self.waiting_on_first_piece = None;
+    }
+
+    /// Adds a piece, such as `"T"`, to the end of the queue.
+    pub fn new_piece(&mut self, piece: &str) -> Result<(), JsError> {
+        let piece: Piece = serde_json::from_value(piece.into())?;
+        if let Some(mut start) = self.waiting_on_first_piece.take() {
+            add_first_piece(&mut start, piece, &self.config);
+            self.begin(start);
+        } else if let Some(bot) = &mut self.bot {
+            bot.new_piece(piece);
+        }
+        Ok(())
+    }
+
+    /// Plays the move of a TBP `play` message, such as
+    /// `{"location":{"type":"T","orientation":"north","x":4,"y":0},"spin":"none"}`.
+    pub fn play(&mut self, move_json: &str) -> Result<(), JsError> {
+        let mv: Placement = serde_json::from_str(move_json)?;
+        let bot = match &mut self.bot {
+            Some(bot) => bot,
+            None => return Ok(()),
+        };
+        let held = bot.state().0.reserve != mv.location.piece;
+        match bot.advance(mv) {
+            Ok(_) => self.nodes = 0,
+            Err(e @ AdvanceError::NoNextPiece) => {
+                return Err(JsError::new(&format!("{}, call new_piece first", e)));
+            }
+            Err(e @ AdvanceError::Overlaps(..)) => {
+                self.bot = None;
+                return Err(JsError::new(&e.to_string()));
+            }
+        }
+        if held {
+            self.hold_empty = false;
+        }
+        Ok(())
+    }
+
+    /// Searches up to `nodes` more nodes and returns how many were actually searched. Returns 0
+    /// once there is nothing left to do: no game is running, the node limit for this move has
+    /// been reached, or the tree can't grow any further.
+    pub fn step(&mut self, nodes: u32) -> u32 {
+        let bot = match &mut self.bot {
+            Some(bot) => bot,
+            None => return 0,
+        };
+        let limit = self.config.nodes_per_move();
+        let mut searched = 0;
+        while searched < nodes as u64 && self.nodes < limit {
+            let stats = bot.do_work(&mut self.rng);
+            if stats.expansions == 0 {
+                break;
+            }
+            searched += stats.nodes;
+            self.nodes += stats.nodes;
+            if bot.over_node_cap() {
+                bot.evict();
+            }
+        }
+        searched.min(u32::MAX as u64) as u32
+    }
+
+    /// The suggestion for the current position as a TBP `suggestion` message, or nothing if no
+    /// game is running. Speeds are reported as 0 since there is no clock.
+    pub fn suggest(&self) -> Option<String> {
+        let bot = self.bot.as_ref()?;
+        let state = bot.state();
+        let moves = tbp_moves(&self.config, bot.suggest(), Some(&state), self.hold_empty);
+        let (tree_nodes, tree_bytes) = bot.tree_size();
+        let limit = self.config.nodes_per_move();
+        let move_info = MoveInfo {
+            nodes: self.nodes,
+            nps: 0.0,
+            depth: bot.depth(),
+            extra: format!(
+                "tree: {} positions (~{:.0} MB)",
+                tree_nodes,
+                tree_bytes as f64 / 1_000_000.0,
+            ),
+            confidence: bot.confidence(),
+            progress: (limit != u64::MAX).then(|| (self.nodes as f64 / limit as f64).min(1.0)),
+        };
+        serde_json::to_string(&BotMessage::Suggestion { moves, move_info }).ok()
+    }
+}
+
+impl WasmBot {
+    fn begin(&mut self, start: tbp::Start) {
+        let empty = start_hold_empty(&start, &self.config);
+        self.nodes = 0;
+        match create_bot(start, self.config.clone()) {
+            Ok(bot) => {
+                self.bot = Some(bot);
+                self.waiting_on_first_piece = None;
+                self.hold_empty = empty;
+            }
+            Err(start) => {
+                self.bot = None;
+                self.waiting_on_first_piece = Some(*start);
+            }
+        }
+    }
+}